@@ -0,0 +1,10 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use qserver::Dissection;
+
+fuzz_target!(|datagram: &[u8]| {
+    if let Some(dissection) = Dissection::new(datagram){
+        let _ = dissection.is_protocol();
+        let _ = dissection.to_string();
+    }
+});
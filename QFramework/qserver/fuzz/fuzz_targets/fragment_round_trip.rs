@@ -0,0 +1,45 @@
+#![no_main]
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use qserver::{fragment_message, MessageExchangeHeader, Reassembly, ReassemblyError};
+
+#[derive(Arbitrary, Debug)]
+struct Input{
+    exchange_id: u64,
+    nak: bool,
+    message: Vec<u8>,
+    /// The fragments in the order they arrive, taken modulo the fragment count
+    /// Repeats are duplicates and fragments never named are lost
+    arrivals: Vec<u16>,
+    /// Datagrams of garbage that arrive before the real fragments
+    junk: Vec<Vec<u8>>,
+}
+
+fuzz_target!(|input: Input| {
+    let datagrams = fragment_message(input.exchange_id, input.nak, &input.message);
+    let Some(first) = datagrams.first() else {
+        assert!(input.message.is_empty());
+        return;
+    };
+    let header: MessageExchangeHeader = bincode::deserialize(first).unwrap();
+    let mut reassembly = Reassembly::new(&header).unwrap();
+    for datagram in input.junk.iter(){
+        let _ = reassembly.insert(datagram);
+    }
+    let mut arrived = vec![false; datagrams.len()];
+    for index in input.arrivals.iter().map(|i| *i as usize % datagrams.len()){
+        reassembly.insert(&datagrams[index]).unwrap();
+        arrived[index] = true;
+    }
+    // Junk can fill a slot the real fragment never overwrote, so only a clean run is compared
+    if !input.junk.is_empty(){
+        return;
+    }
+    match reassembly.assemble(){
+        Ok(message) => assert_eq!(message, input.message),
+        Err(e) => {
+            assert_eq!(e, ReassemblyError::Incomplete);
+            assert!(arrived.contains(&false));
+        },
+    }
+});
@@ -0,0 +1,19 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use qserver::{MessageExchangeHeader, Reassembly};
+
+// Raw datagrams as they could come off the socket for a single exchange
+// The first one that carries a header opens the exchange like the receive side does
+fuzz_target!(|datagrams: Vec<Vec<u8>>| {
+    let mut datagrams = datagrams.iter();
+    let Some(mut reassembly) = datagrams.by_ref().find_map(|d| {
+        let header: MessageExchangeHeader = bincode::deserialize(d).ok()?;
+        Reassembly::new(&header).ok()
+    }) else {return};
+    for datagram in datagrams{
+        let _ = reassembly.insert(datagram);
+        let _ = reassembly.missing();
+    }
+    let assembled = reassembly.assemble();
+    assert_eq!(assembled.is_ok(), reassembly.is_complete());
+});
@@ -0,0 +1,307 @@
+use std::{fmt, net::{IpAddr, SocketAddr}, str::FromStr, collections::HashMap, time::{Duration, Instant}};
+
+use crate::LocalServer;
+
+/// Buckets that have been idle this long are forgotten when the table is full
+const BUCKET_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A range of addresses such as 10.0.0.0/8 or fe80::/10
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr{
+    addr: IpAddr,
+    prefix: u8,
+}
+
+#[derive(Debug)]
+pub struct CidrParseError(String);
+
+/// The abuse protection settings of a server
+/// Every datagram is checked against these before an exchange is spawned for it
+#[derive(Clone, Debug)]
+pub struct RateLimits{
+    /// How many datagrams per second a single source address may send on average
+    pub datagrams_per_second: f64,
+    /// How many datagrams a single source address may send at once
+    pub burst: f64,
+    /// The most source addresses we keep a token bucket for
+    pub max_sources: usize,
+    /// The most servers we keep a keep alive running for
+    pub max_peers: usize,
+    /// If not empty, only sources in one of these ranges are accepted
+    pub allow: Vec<Cidr>,
+    /// Sources in any of these ranges are always dropped
+    pub deny: Vec<Cidr>,
+}
+
+struct TokenBucket{
+    tokens: f64,
+    last: Instant,
+}
+
+/// The live admission state of a server
+pub(crate) struct Admission{
+    limits: RateLimits,
+    buckets: HashMap<IpAddr, TokenBucket>,
+}
+
+impl Cidr{
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Cidr, CidrParseError> {
+        let max = match addr{
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix > max{
+            return Err(CidrParseError(format!("prefix /{} is too long for {}", prefix, addr)));
+        }
+        Ok(Cidr{ addr, prefix })
+    }
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip){
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(range) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(range) & mask == u128::from(ip) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr{
+    type Err = CidrParseError;
+
+    /// A bare address is a range of just that address
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/'){
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.trim().parse().map_err(|_| CidrParseError(format!("{} is not an ip address", addr)))?;
+        let prefix = match prefix{
+            Some(p) => p.trim().parse().map_err(|_| CidrParseError(format!("{} is not a prefix length", p)))?,
+            None => if addr.is_ipv4() {32} else {128},
+        };
+        Cidr::new(addr, prefix)
+    }
+}
+
+impl fmt::Display for Cidr{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl fmt::Display for CidrParseError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid cidr range: {}", self.0)
+    }
+}
+
+impl Default for RateLimits{
+    fn default() -> Self {
+        RateLimits{
+            datagrams_per_second: 5000.0,
+            burst: 1000.0,
+            max_sources: 4096,
+            max_peers: 1024,
+            allow: vec![],
+            deny: vec![] }
+    }
+}
+
+impl RateLimits{
+    /// Is the source allowed by the allow and deny lists
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|range| range.contains(ip)){
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|range| range.contains(ip))
+    }
+}
+
+impl Admission{
+    pub(crate) fn new(limits: RateLimits) -> Admission {
+        Admission{ limits, buckets: HashMap::new() }
+    }
+    /// Takes a token from the source's bucket
+    /// Returns false if the datagram should be dropped
+    fn admit(&mut self, ip: IpAddr) -> bool {
+        self.admit_at(ip, Instant::now())
+    }
+    fn admit_at(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if !self.limits.permits(ip){
+            return false;
+        }
+        if !self.buckets.contains_key(&ip) && self.buckets.len() >= self.limits.max_sources{
+            // The table is full so we forget anyone who has gone quiet
+            self.buckets.retain(|_, bucket| now.duration_since(bucket.last) < BUCKET_IDLE_TIMEOUT);
+            if self.buckets.len() >= self.limits.max_sources{
+                return false;
+            }
+        }
+        let limits = &self.limits;
+        let bucket = self.buckets.entry(ip).or_insert(TokenBucket{ tokens: limits.burst, last: now });
+        let elapsed = now.duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limits.datagrams_per_second).min(limits.burst);
+        bucket.last = now;
+        if bucket.tokens < 1.0{
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+/// Abuse protection functionality
+impl LocalServer{
+    /// Replaces the abuse protection settings, existing buckets are kept
+    pub fn set_rate_limits(&self, limits: RateLimits){
+        self.admission.lock().unwrap().limits = limits;
+    }
+    pub fn rate_limits(&self) -> RateLimits {
+        self.admission.lock().unwrap().limits.clone()
+    }
+    /// Should a datagram from `source` be processed
+    /// Our own loopback traffic is always admitted
+    pub(crate) fn admit(&self, source: SocketAddr) -> bool {
+        if source == self.local_address(){
+            return true;
+        }
+        self.admission.lock().unwrap().admit(source.ip())
+    }
+    pub(crate) fn max_peers(&self) -> usize {
+        self.admission.lock().unwrap().limits.max_peers
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn limits(datagrams_per_second: f64, burst: f64) -> RateLimits {
+        RateLimits{ datagrams_per_second, burst, ..RateLimits::default() }
+    }
+
+    #[test]
+    fn cidr_parses_ranges_and_bare_addresses(){
+        assert_eq!("10.0.0.0/8".parse::<Cidr>().unwrap(), Cidr::new(ip("10.0.0.0"), 8).unwrap());
+        assert_eq!(" 192.168.1.7 ".parse::<Cidr>().unwrap(), Cidr::new(ip("192.168.1.7"), 32).unwrap());
+        assert_eq!("fe80::1".parse::<Cidr>().unwrap(), Cidr::new(ip("fe80::1"), 128).unwrap());
+        assert_eq!("fe80::/10".parse::<Cidr>().unwrap().to_string(), "fe80::/10");
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/-1".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn cidr_contains(){
+        let range: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(range.contains(ip("10.1.0.0")));
+        assert!(range.contains(ip("10.1.255.255")));
+        assert!(!range.contains(ip("10.2.0.0")));
+        assert!(!range.contains(ip("::ffff:10.1.0.1")));
+
+        let v6: Cidr = "fe80::/10".parse().unwrap();
+        assert!(v6.contains(ip("febf::1")));
+        assert!(!v6.contains(ip("fec0::1")));
+        assert!(!v6.contains(ip("10.1.0.1")));
+    }
+
+    #[test]
+    fn cidr_zero_prefix_contains_every_address_of_its_family(){
+        let all_v4: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all_v4.contains(ip("0.0.0.0")));
+        assert!(all_v4.contains(ip("255.255.255.255")));
+        assert!(!all_v4.contains(ip("::1")));
+
+        let all_v6: Cidr = "::/0".parse().unwrap();
+        assert!(all_v6.contains(ip("::")));
+        assert!(all_v6.contains(ip("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff")));
+        assert!(!all_v6.contains(ip("127.0.0.1")));
+    }
+
+    #[test]
+    fn cidr_full_prefix_contains_one_address(){
+        let one: Cidr = "192.168.1.7/32".parse().unwrap();
+        assert!(one.contains(ip("192.168.1.7")));
+        assert!(!one.contains(ip("192.168.1.6")));
+        assert!(!one.contains(ip("192.168.1.8")));
+
+        let one_v6: Cidr = "fe80::7/128".parse().unwrap();
+        assert!(one_v6.contains(ip("fe80::7")));
+        assert!(!one_v6.contains(ip("fe80::6")));
+    }
+
+    #[test]
+    fn deny_wins_over_allow(){
+        let limits = RateLimits{
+            allow: vec!["10.0.0.0/8".parse().unwrap()],
+            deny: vec!["10.0.0.13/32".parse().unwrap()],
+            ..RateLimits::default()
+        };
+        assert!(limits.permits(ip("10.0.0.12")));
+        assert!(!limits.permits(ip("10.0.0.13")));
+        assert!(!limits.permits(ip("11.0.0.1")));
+        assert!(RateLimits::default().permits(ip("11.0.0.1")));
+    }
+
+    #[test]
+    fn bucket_allows_a_burst_then_drops(){
+        let mut admission = Admission::new(limits(10.0, 5.0));
+        let now = Instant::now();
+        let source = ip("10.0.0.1");
+        for _ in 0..5{
+            assert!(admission.admit_at(source, now));
+        }
+        assert!(!admission.admit_at(source, now));
+        // Every source has a bucket of its own
+        assert!(admission.admit_at(ip("10.0.0.2"), now));
+    }
+
+    #[test]
+    fn bucket_refills_at_the_configured_rate(){
+        let mut admission = Admission::new(limits(10.0, 5.0));
+        let start = Instant::now();
+        let source = ip("10.0.0.1");
+        for _ in 0..5{
+            assert!(admission.admit_at(source, start));
+        }
+        assert!(!admission.admit_at(source, start));
+        // A tenth of a second buys exactly one datagram at 10 per second
+        let later = start + Duration::from_millis(100);
+        assert!(admission.admit_at(source, later));
+        assert!(!admission.admit_at(source, later));
+    }
+
+    #[test]
+    fn bucket_never_refills_past_the_burst(){
+        let mut admission = Admission::new(limits(10.0, 5.0));
+        let start = Instant::now();
+        let source = ip("10.0.0.1");
+        assert!(admission.admit_at(source, start));
+        let much_later = start + Duration::from_secs(60);
+        for _ in 0..5{
+            assert!(admission.admit_at(source, much_later));
+        }
+        assert!(!admission.admit_at(source, much_later));
+    }
+
+    #[test]
+    fn full_table_forgets_only_idle_sources(){
+        let mut admission = Admission::new(RateLimits{ max_sources: 2, ..RateLimits::default() });
+        let start = Instant::now();
+        assert!(admission.admit_at(ip("10.0.0.1"), start));
+        assert!(admission.admit_at(ip("10.0.0.2"), start));
+        assert!(!admission.admit_at(ip("10.0.0.3"), start + Duration::from_secs(1)));
+        assert!(admission.admit_at(ip("10.0.0.3"), start + BUCKET_IDLE_TIMEOUT));
+    }
+}
@@ -0,0 +1,90 @@
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{SystemTime, UNIX_EPOCH};
+use qserver::{CaptureReader, Dissection, Direction, channel_name};
+
+use clap::{Parser, Subcommand};
+
+/// Decodes qserver datagrams into their exchange and station headers
+#[derive(Parser)]
+struct Args {
+    #[command(subcommand)]
+    source: Source,
+
+    /// Do not highlight special channels
+    #[arg(long)]
+    no_color: bool,
+}
+
+#[derive(Subcommand)]
+enum Source {
+    /// Dissect every datagram of a capture file
+    Read {
+        file: String,
+    },
+    /// Bind a port and dissect whatever arrives without ever responding
+    Listen {
+        port: u16,
+        #[arg(short, long, default_value_t = String::from("0.0.0.0"))]
+        bind: String,
+    },
+}
+
+const HIGHLIGHT: &str = "\x1b[1;33m";
+const RESET: &str = "\x1b[0m";
+
+fn main(){
+    let arg = Args::parse();
+    match arg.source{
+        Source::Read { file } => {
+            let reader = match CaptureReader::open(&file){
+                Ok(r) => r,
+                Err(e) => {eprintln!("Could not open capture {}: {}", file, e); std::process::exit(1);}
+            };
+            for record in reader{
+                let record = match record{
+                    Ok(r) => r,
+                    Err(e) => {eprintln!("Capture {} is corrupt: {}", file, e); std::process::exit(1);}
+                };
+                let arrow = match record.direction{
+                    Direction::Inbound => "<-",
+                    Direction::Outbound => "->",
+                };
+                let line = format!("{} {} {} {} {}", record.timestamp, record.local, arrow, record.peer, describe(&record.data));
+                print_line(line, &record.data, arg.no_color);
+            }
+        },
+        Source::Listen { port, bind } => {
+            let addr = SocketAddr::new(bind.parse().expect("Invalid bind address"), port);
+            let socket = UdpSocket::bind(addr).expect("Could not bind listen address");
+            println!("Passively listening on {}", socket.local_addr().unwrap());
+            let mut data = [0; 65536];
+            loop{
+                let Ok((len, source)) = socket.recv_from(&mut data) else {continue};
+                let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|t| t.as_micros()).unwrap_or(0);
+                let line = format!("{} <- {} {}", timestamp, source, describe(&data[..len]));
+                print_line(line, &data[..len], arg.no_color);
+            }
+        },
+    }
+}
+
+fn describe(datagram: &[u8]) -> String {
+    match Dissection::new(datagram){
+        Some(d) => d.to_string(),
+        None => format!("undecodable {}B datagram", datagram.len()),
+    }
+}
+
+/// Datagrams on special channels are highlighted so server traffic stands out from user traffic
+fn print_line(line: String, datagram: &[u8], no_color: bool){
+    let special = Dissection::new(datagram)
+        .and_then(|d| d.station)
+        .and_then(|s| channel_name(s.channel))
+        .is_some();
+    if special && !no_color{
+        println!("{}{}{}", HIGHLIGHT, line, RESET);
+    }
+    else{
+        println!("{}", line);
+    }
+}
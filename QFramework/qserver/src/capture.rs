@@ -0,0 +1,183 @@
+use std::{sync::Arc, net::SocketAddr, fs::File, path::Path, time::{SystemTime, UNIX_EPOCH}};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use serde::{Serialize, Deserialize};
+
+use crate::{LocalServer, MAX_MESSAGE_LENGTH, message_exchange::{MessageOp, MessageExchangeHeader}};
+use crate::{qlog, logging::LogLevel};
+
+/// Every capture file starts with these bytes followed by the format version
+const CAPTURE_MAGIC: &[u8; 4] = b"QCAP";
+const CAPTURE_VERSION: u16 = 1;
+
+/// Which way a captured datagram was travelling
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction{
+    Inbound,
+    Outbound,
+}
+
+/// A single datagram as seen by the socket of a LocalServer
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CaptureRecord{
+    /// Microseconds since the unix epoch
+    pub timestamp: u64,
+    pub direction: Direction,
+    /// The address of the server that captured the datagram
+    pub local: SocketAddr,
+    /// The other end of the datagram
+    pub peer: SocketAddr,
+    /// The decoded exchange header, None if the datagram did not have a valid one
+    pub header: Option<MessageExchangeHeader>,
+    /// The raw datagram, exchange header included
+    pub data: Vec<u8>,
+}
+
+/// Writes capture records to a file
+/// The format is the magic and version followed by length prefixed bincode records
+pub(crate) struct CaptureWriter{
+    file: BufWriter<File>,
+}
+
+/// Reads the records of a capture file in the order they were captured
+pub struct CaptureReader{
+    file: BufReader<File>,
+    /// Set once a record failed to read, iteration stops there
+    failed: bool,
+}
+
+/// What a replay fed into the server
+pub struct ReplayReport{
+    /// The number of inbound datagrams pushed through the receive path
+    pub replayed: usize,
+    /// The number of outbound datagrams that were skipped
+    pub skipped: usize,
+}
+
+impl CaptureWriter{
+    pub(crate) fn create<P: AsRef<Path>>(path: P) -> io::Result<CaptureWriter> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(CAPTURE_MAGIC)?;
+        file.write_all(&CAPTURE_VERSION.to_le_bytes())?;
+        file.flush()?;
+        Ok(CaptureWriter{ file })
+    }
+    pub(crate) fn write(&mut self, record: &CaptureRecord) -> io::Result<()> {
+        let bytes = bincode::serialize(record).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.file.write_all(&(bytes.len() as u32).to_le_bytes())?;
+        self.file.write_all(&bytes)?;
+        // We flush every record since captures are most useful when something crashed
+        self.file.flush()
+    }
+}
+
+impl CaptureReader{
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<CaptureReader> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut magic = [0; 4];
+        file.read_exact(&mut magic)?;
+        if &magic != CAPTURE_MAGIC{
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a qserver capture file"));
+        }
+        let mut version = [0; 2];
+        file.read_exact(&mut version)?;
+        let version = u16::from_le_bytes(version);
+        if version != CAPTURE_VERSION{
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unsupported capture version {}", version)));
+        }
+        Ok(CaptureReader{ file, failed: false })
+    }
+    /// Reads the next record, Ok(None) is the end of the capture
+    pub fn next_record(&mut self) -> io::Result<Option<CaptureRecord>> {
+        let mut len = [0; 4];
+        match self.file.read_exact(&mut len){
+            Ok(_) => {},
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let mut bytes = vec![0; u32::from_le_bytes(len) as usize];
+        self.file.read_exact(&mut bytes)?;
+        let record = bincode::deserialize(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Some(record))
+    }
+}
+
+/// Yields records until the end of the capture. A corrupt capture yields its error once and then ends
+impl Iterator for CaptureReader{
+    type Item = io::Result<CaptureRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed{
+            return None;
+        }
+        let record = self.next_record().transpose();
+        self.failed = matches!(record, Some(Err(_)));
+        record
+    }
+}
+
+impl CaptureRecord{
+    pub(crate) fn new(direction: Direction, local: SocketAddr, peer: SocketAddr, data: &[u8]) -> CaptureRecord {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|t| t.as_micros() as u64).unwrap_or(0);
+        CaptureRecord{
+            timestamp,
+            direction,
+            local,
+            peer,
+            header: bincode::deserialize(data).ok(),
+            data: data.to_vec() }
+    }
+}
+
+/// Capture functionality
+impl LocalServer{
+    /// Starts recording every datagram this server sends and receives to `path`
+    /// Any capture already running is replaced
+    pub fn start_capture<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let writer = CaptureWriter::create(path)?;
+        *self.capture.lock().unwrap() = Some(writer);
+        Ok(())
+    }
+    pub fn stop_capture(&self){
+        *self.capture.lock().unwrap() = None;
+    }
+    /// Called by the socket functions for every datagram
+    pub(crate) fn capture_datagram(&self, direction: Direction, peer: SocketAddr, data: &[u8]){
+        let mut capture = self.capture.lock().unwrap();
+        if let Some(writer) = capture.as_mut(){
+            let record = CaptureRecord::new(direction, self.local_address(), peer, data);
+            if let Err(e) = writer.write(&record){
+                qlog!(LogLevel::Warn, "Stopping capture on {} after write failure: {}", self.local_address(), e);
+                *capture = None;
+            }
+        }
+    }
+    /// Feeds the inbound datagrams of a capture back into `server` through the receive path
+    /// If `realtime` is set the original gaps between datagrams are kept, otherwise the
+    /// datagrams are replayed as fast as possible in their captured order
+    pub async fn replay_capture<P: AsRef<Path>>(server: Arc<LocalServer>, path: P, realtime: bool) -> io::Result<ReplayReport> {
+        let mut reader = CaptureReader::open(path)?;
+        let mut report = ReplayReport{ replayed: 0, skipped: 0 };
+        let mut last_timestamp = None;
+        while let Some(record) = reader.next_record()?{
+            if record.direction != Direction::Inbound || record.data.len() > MAX_MESSAGE_LENGTH{
+                report.skipped += 1;
+                continue;
+            }
+            if realtime{
+                if let Some(last) = last_timestamp{
+                    crate::async_timer(record.timestamp.saturating_sub(last) / 1000).await;
+                }
+                last_timestamp = Some(record.timestamp);
+            }
+            let mut data = [0; MAX_MESSAGE_LENGTH];
+            data[..record.data.len()].copy_from_slice(&record.data);
+            let op = MessageOp::Receive((record.data.len(), record.peer, data));
+            // Exchanges can wait on later fragments, so each one gets its own task just like in the udp intake
+            tokio::spawn(Self::exchange(server.clone(), op));
+            // Yielding lets the exchange route its packet before the next one is pushed
+            tokio::task::yield_now().await;
+            report.replayed += 1;
+        }
+        Ok(report)
+    }
+}
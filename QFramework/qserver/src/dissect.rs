@@ -0,0 +1,74 @@
+use std::fmt;
+
+use crate::{message_exchange::FRAGMENT_HEADER_SPACE, MessageExchangeHeader, StationHeader, NO_MESSAGE_CHANNEL, PING_CHANNEL, SERVER_CHANNEL, NO_DELIVER_CHANNEL};
+
+/// A datagram decoded into the headers it carries
+pub struct Dissection{
+    pub exchange: MessageExchangeHeader,
+    /// Only the first fragment of a message carries the station header
+    pub station: Option<StationHeader>,
+    /// The number of bytes behind the exchange header
+    pub payload: usize,
+}
+
+impl Dissection{
+    /// Decodes a raw datagram, None if it does not start with a valid exchange header
+    pub fn new(datagram: &[u8]) -> Option<Dissection> {
+        let exchange: MessageExchangeHeader = bincode::deserialize(datagram).ok()?;
+        // The exchange header always takes up its in memory size on the wire
+        let data = datagram.get(FRAGMENT_HEADER_SPACE..).unwrap_or(&[]);
+        let data = &data[..data.len().min(exchange.fragment_data as usize)];
+        // Protocol headers (retransmit requests and message completes) carry no data
+        let station = match exchange.fragment_index == 0 && !exchange.message_complete && !data.is_empty(){
+            true => bincode::deserialize(data).ok(),
+            false => None,
+        };
+        Some(Dissection{ exchange, station, payload: data.len() })
+    }
+    /// Is this a retransmit request or a message complete rather than message data
+    pub fn is_protocol(&self) -> bool {
+        self.exchange.message_complete || self.exchange.fragment_data == 0
+    }
+}
+
+/// The name of a special channel, None for user channels
+pub fn channel_name(channel: u32) -> Option<&'static str> {
+    match channel{
+        NO_MESSAGE_CHANNEL => Some("NO_MESSAGE_CHANNEL"),
+        PING_CHANNEL => Some("PING_CHANNEL"),
+        SERVER_CHANNEL => Some("SERVER_CHANNEL"),
+        NO_DELIVER_CHANNEL => Some("NO_DELIVER_CHANNEL"),
+        _ => None,
+    }
+}
+
+impl fmt::Display for Dissection{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let exchange = &self.exchange;
+        write!(f, "exchange {:016x} fragment {}/{} data {}B", exchange.exchange_id, exchange.fragment_index as u64 + 1, exchange.fragment_count, exchange.fragment_data)?;
+        if exchange.nak{
+            write!(f, " nak")?;
+        }
+        if exchange.message_complete{
+            write!(f, " complete")?;
+        }
+        else if self.is_protocol(){
+            write!(f, " retransmit-request")?;
+        }
+        if let Some(station) = &self.station{
+            // Remember, for pings the to id is the channel of the pinging station
+            match station.channel{
+                PING_CHANNEL => write!(f, " | station {:016x} pinging channel {}", station.from_id, station.to_id)?,
+                _ => write!(f, " | station {:016x} -> {:016x}", station.from_id, station.to_id)?,
+            }
+            match channel_name(station.channel){
+                Some(name) => write!(f, " on {}", name)?,
+                None => write!(f, " on channel {}", station.channel)?,
+            }
+            if let Some(trace) = station.trace{
+                write!(f, " {}", trace)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,352 @@
+use std::{sync::{Arc, Mutex, RwLock}, time::Instant};
+use serde::{Serialize, Deserialize};
+use tokio::time::{Duration, timeout};
+
+use crate::{Station, StationOperable, station::StationId};
+use crate::{qlog, logging::LogLevel};
+
+/// How often a leader tells its followers it is still alive
+const HEARTBEAT_INTERVAL: u64 = 250;
+/// How long followers wait for a heartbeat before they start an election
+const LEADER_TIMEOUT: u64 = 1000;
+/// How long a candidate waits for higher stations to answer, or for the winner to announce itself
+const ELECTION_TIMEOUT: u64 = 500;
+/// How often the election task checks its timers when nothing arrives
+const ELECTION_TICK: u64 = 50;
+
+/// The messages of the bully algorithm
+/// The station with the highest id that is alive always wins
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ElectionMessage{
+    /// Sent to every higher station when a station wants to lead
+    Election,
+    /// A higher station telling a candidate it will take over
+    Alive,
+    /// The winner announcing itself with the term it leads
+    Coordinator{term: u64},
+    Heartbeat{term: u64},
+    /// Tells a leader that its term is older than one already seen, so it has to move past it
+    Stale{term: u64},
+}
+
+/// Published every time the cluster settles on a new leader
+#[derive(Clone, Copy, Debug)]
+pub struct LeadershipEvent{
+    pub term: u64,
+    pub leader: StationId,
+    /// Are we the new leader
+    pub is_self: bool,
+}
+
+/// Proof of leadership for a term
+/// Terms only ever increase, so anything accepting writes from a leader can reject
+/// writes carrying a token older than the newest one it has seen
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct FencingToken{
+    pub term: u64,
+    pub leader: StationId,
+}
+
+struct ElectionState{
+    term: u64,
+    leader: Option<StationId>,
+}
+
+/// A running leader election
+/// The election runs in its own task for as long as the handle is alive
+pub struct LeaderElection{
+    id: StationId,
+    state: Arc<RwLock<ElectionState>>,
+    subscribers: Arc<Mutex<Vec<flume::Sender<LeadershipEvent>>>>,
+    // Dropping this stops the election task
+    _stop: flume::Sender<()>,
+}
+
+/// Where a station is in the bully algorithm
+enum Phase{
+    Follower{last_heard: Instant},
+    Leader{last_heartbeat: Instant},
+    /// Waiting for higher stations to answer
+    Candidate{started: Instant},
+    /// A higher station answered and we are waiting for it to win
+    Deferred{started: Instant},
+}
+
+impl LeaderElection{
+    /// Starts taking part in the election of every station on `station`'s channel
+    pub fn start(station: Station<ElectionMessage>) -> LeaderElection {
+        let id = station.id();
+        let state = Arc::new(RwLock::new(ElectionState{ term: 0, leader: None }));
+        let subscribers = Arc::new(Mutex::new(vec![]));
+        let stop = flume::bounded(1);
+        let runtime = station.server.get_runtime();
+        runtime.spawn(Self::run(station, state.clone(), subscribers.clone(), stop.1));
+        LeaderElection{ id, state, subscribers, _stop: stop.0 }
+    }
+    pub fn leader(&self) -> Option<StationId> {
+        self.state.read().unwrap().leader
+    }
+    pub fn is_leader(&self) -> bool {
+        self.leader() == Some(self.id)
+    }
+    /// The token to attach to writes, None unless we currently lead
+    pub fn fencing_token(&self) -> Option<FencingToken> {
+        let state = self.state.read().unwrap();
+        match state.leader{
+            Some(leader) if leader == self.id => Some(FencingToken{ term: state.term, leader }),
+            _ => None,
+        }
+    }
+    /// Every leadership change from now on will be sent on the returned channel
+    pub fn subscribe(&self) -> flume::Receiver<LeadershipEvent> {
+        let (tx, rx) = flume::unbounded();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    async fn run(mut station: Station<ElectionMessage>, state: Arc<RwLock<ElectionState>>, subscribers: Arc<Mutex<Vec<flume::Sender<LeadershipEvent>>>>, stop: flume::Receiver<()>){
+        let id = station.id();
+        // We start as a follower so we have time to discover the other stations before electing
+        let mut phase = Phase::Follower{ last_heard: Instant::now() };
+        while !stop.is_disconnected(){
+            if let Ok(Some((_, from, message))) = timeout(Duration::from_millis(ELECTION_TICK), station.listen()).await{
+                match message{
+                    ElectionMessage::Election => {
+                        // A lower station wants to lead, we bully it and make sure someone wins
+                        if from < id{
+                            let _ = station.send(from, false, &ElectionMessage::Alive).await;
+                            if let Phase::Follower{..} = phase{
+                                phase = Self::campaign(&mut station).await;
+                            }
+                        }
+                    },
+                    ElectionMessage::Alive => {
+                        if let Phase::Candidate{..} = phase{
+                            phase = Phase::Deferred{ started: Instant::now() };
+                        }
+                    },
+                    ElectionMessage::Coordinator{ term } | ElectionMessage::Heartbeat{ term } => {
+                        if from < id{
+                            // We outrank whoever announced itself, but we still have to move past its term
+                            let leading = matches!(phase, Phase::Leader{..});
+                            if let Some(term) = Self::outranked(&state, &subscribers, id, term, leading){
+                                Self::broadcast(&mut station, &ElectionMessage::Coordinator{ term }).await;
+                            }
+                            match phase{
+                                Phase::Leader{..} | Phase::Candidate{..} => {},
+                                _ => phase = Self::campaign(&mut station).await,
+                            }
+                        }
+                        else{
+                            let known = state.read().unwrap().term;
+                            if term < known{
+                                // A restarted leader would hand out fencing tokens that go backwards
+                                let _ = station.send(from, false, &ElectionMessage::Stale{ term: known }).await;
+                            }
+                            Self::accept(&state, &subscribers, id, from, term);
+                            phase = Phase::Follower{ last_heard: Instant::now() };
+                        }
+                    },
+                    ElectionMessage::Stale{ term } => {
+                        if let Phase::Leader{..} = phase{
+                            let term = term.max(state.read().unwrap().term) + 1;
+                            Self::accept(&state, &subscribers, id, id, term);
+                            Self::broadcast(&mut station, &ElectionMessage::Coordinator{ term }).await;
+                        }
+                    },
+                }
+            }
+
+            // Then we check our timers
+            match phase{
+                Phase::Follower{ last_heard } if last_heard.elapsed() > Duration::from_millis(LEADER_TIMEOUT) => {
+                    qlog!(LogLevel::Info, "Station {} lost its leader, starting an election", id);
+                    phase = Self::campaign(&mut station).await;
+                },
+                Phase::Candidate{ started } if started.elapsed() > Duration::from_millis(ELECTION_TIMEOUT) => {
+                    // Nobody above us answered so we lead
+                    let term = state.read().unwrap().term + 1;
+                    Self::accept(&state, &subscribers, id, id, term);
+                    Self::broadcast(&mut station, &ElectionMessage::Coordinator{ term }).await;
+                    phase = Phase::Leader{ last_heartbeat: Instant::now() };
+                },
+                Phase::Deferred{ started } if started.elapsed() > Duration::from_millis(ELECTION_TIMEOUT) => {
+                    // Whoever answered died before winning
+                    phase = Self::campaign(&mut station).await;
+                },
+                Phase::Leader{ last_heartbeat } if last_heartbeat.elapsed() > Duration::from_millis(HEARTBEAT_INTERVAL) => {
+                    let term = state.read().unwrap().term;
+                    Self::broadcast(&mut station, &ElectionMessage::Heartbeat{ term }).await;
+                    phase = Phase::Leader{ last_heartbeat: Instant::now() };
+                },
+                _ => {},
+            }
+        }
+    }
+    /// Challenges every higher station
+    async fn campaign(station: &mut Station<ElectionMessage>) -> Phase {
+        let id = station.id();
+        for peer in station.known_stations().into_iter().filter(|p| *p > id){
+            let _ = station.send(peer, false, &ElectionMessage::Election).await;
+        }
+        Phase::Candidate{ started: Instant::now() }
+    }
+    async fn broadcast(station: &mut Station<ElectionMessage>, message: &ElectionMessage){
+        for peer in station.known_stations(){
+            let _ = station.send(peer, false, message).await;
+        }
+    }
+    /// Folds in the term announced by a station we outrank
+    /// If we lead, returns the term to announce ourselves with, past the announced one if need be,
+    /// otherwise the term we win our next election with will be past it
+    fn outranked(state: &RwLock<ElectionState>, subscribers: &Mutex<Vec<flume::Sender<LeadershipEvent>>>, id: StationId, term: u64, leading: bool) -> Option<u64> {
+        let known = {
+            let mut state = state.write().unwrap();
+            if !leading{
+                state.term = state.term.max(term);
+                return None;
+            }
+            state.term
+        };
+        if term < known{
+            return Some(known);
+        }
+        Self::accept(state, subscribers, id, id, term + 1);
+        Some(term + 1)
+    }
+    /// Records a leader and publishes the change if it is a new one
+    fn accept(state: &RwLock<ElectionState>, subscribers: &Mutex<Vec<flume::Sender<LeadershipEvent>>>, id: StationId, leader: StationId, term: u64){
+        let term = {
+            let mut state = state.write().unwrap();
+            // Terms never go backwards, even if we hear an old leader late
+            let term = state.term.max(term);
+            if state.leader == Some(leader) && state.term == term{
+                return;
+            }
+            state.term = term;
+            state.leader = Some(leader);
+            term
+        };
+        qlog!(LogLevel::Info, "Station {} recognised station {} as leader for term {}", id, leader, term);
+        let event = LeadershipEvent{ term, leader, is_self: leader == id };
+        subscribers.lock().unwrap().retain(|s| s.send(event).is_ok());
+    }
+}
+
+impl StationOperable for ElectionMessage{
+    fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        bincode::deserialize(bytes).unwrap()
+    }
+
+    /// Any station can send us anything, so what does not decode is dropped rather than unwrapped
+    fn try_from_bytes(bytes: &[u8]) -> Option<Self> {
+        bincode::deserialize(bytes).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const ID: StationId = 50;
+    const LOWER: StationId = 10;
+    const HIGHER: StationId = 90;
+
+    /// An election that is never started, so its state only changes as the tests drive it
+    fn election() -> LeaderElection {
+        LeaderElection{
+            id: ID,
+            state: Arc::new(RwLock::new(ElectionState{ term: 0, leader: None })),
+            subscribers: Arc::new(Mutex::new(vec![])),
+            _stop: flume::bounded(1).0,
+        }
+    }
+
+    fn lead(election: &LeaderElection, term: u64){
+        LeaderElection::accept(&election.state, &election.subscribers, ID, ID, term);
+    }
+
+    /// What the task does when a candidate hears nothing back
+    fn win(election: &LeaderElection) -> u64 {
+        let term = election.state.read().unwrap().term + 1;
+        lead(election, term);
+        term
+    }
+
+    fn outranked(election: &LeaderElection, term: u64, leading: bool) -> Option<u64> {
+        LeaderElection::outranked(&election.state, &election.subscribers, ID, term, leading)
+    }
+
+    #[test]
+    fn leader_moves_past_a_newer_term_from_a_lower_station(){
+        let election = election();
+        lead(&election, 5);
+        let before = election.fencing_token().unwrap();
+        // A lower station led term 9 while it could not hear us
+        assert_eq!(outranked(&election, 9, true), Some(10));
+        let after = election.fencing_token().unwrap();
+        assert!(after > before);
+        assert!(after > FencingToken{ term: 9, leader: LOWER });
+    }
+
+    #[test]
+    fn leader_keeps_its_term_against_an_older_one(){
+        let election = election();
+        lead(&election, 5);
+        assert_eq!(outranked(&election, 3, true), Some(5));
+        assert_eq!(outranked(&election, 5, true), Some(6));
+        assert_eq!(election.fencing_token().unwrap().term, 6);
+    }
+
+    #[test]
+    fn follower_wins_past_the_term_of_a_lower_station(){
+        let election = election();
+        LeaderElection::accept(&election.state, &election.subscribers, ID, HIGHER, 3);
+        assert_eq!(outranked(&election, 9, false), None);
+        // Nothing is handed out before we win
+        assert_eq!(election.fencing_token(), None);
+        assert_eq!(win(&election), 10);
+        assert!(election.fencing_token().unwrap() > FencingToken{ term: 9, leader: LOWER });
+    }
+
+    #[test]
+    fn fencing_tokens_never_decrease(){
+        let election = election();
+        let events = election.subscribe();
+        let mut tokens = vec![];
+        let mut record = |election: &LeaderElection| tokens.extend(election.fencing_token());
+
+        win(&election);
+        record(&election);
+        outranked(&election, 4, true);
+        record(&election);
+        // Someone above us takes over with an old term, then dies
+        LeaderElection::accept(&election.state, &election.subscribers, ID, HIGHER, 2);
+        outranked(&election, 7, false);
+        win(&election);
+        record(&election);
+        outranked(&election, 1, true);
+        record(&election);
+        outranked(&election, 12, true);
+        record(&election);
+
+        assert!(tokens.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", tokens);
+        let terms: Vec<u64> = events.try_iter().map(|event| event.term).collect();
+        assert!(terms.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", terms);
+        assert_eq!(tokens.last().unwrap().term, 13);
+    }
+
+    #[test]
+    fn junk_is_dropped_not_a_panic(){
+        assert!(ElectionMessage::try_from_bytes(&[]).is_none());
+        assert!(ElectionMessage::try_from_bytes(&[0xff; 16]).is_none());
+        // A coordinator cut short, its term is missing
+        let truncated = ElectionMessage::Coordinator{ term: 7 }.to_bytes();
+        assert!(ElectionMessage::try_from_bytes(&truncated[..truncated.len() - 1]).is_none());
+        assert!(matches!(ElectionMessage::try_from_bytes(&truncated), Some(ElectionMessage::Coordinator{ term: 7 })));
+    }
+}
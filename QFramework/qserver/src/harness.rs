@@ -0,0 +1,242 @@
+use std::{fmt, net::SocketAddr, sync::Arc, thread::sleep, time::{Duration, Instant}};
+use tokio::runtime::Runtime;
+
+use crate::{LocalServer, Tuning, TuningError};
+
+/// How often the harness looks at the peer tables while it waits
+const HARNESS_POLL: u64 = 50;
+/// How long a join waits for the handshake to complete
+const HARNESS_JOIN_TIMEOUT: u64 = 5000;
+
+/// Where the servers of a TestCluster run
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeRuntime{
+    /// Every server shares one runtime, like many servers in one process
+    Shared,
+    /// Every server gets a runtime of its own, like separate processes talking over loopback
+    PerNode,
+}
+
+/// A set of servers on loopback for cluster tests
+/// Nodes keep their index for the life of the cluster, killed nodes included.
+/// Every call blocks, so the harness has to be driven from outside of any runtime
+pub struct TestCluster{
+    mode: NodeRuntime,
+    /// The runtime of every node in shared mode
+    runtime: Option<Arc<Runtime>>,
+    nodes: Vec<(Arc<LocalServer>, bool)>,
+    tuning: Option<Tuning>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HarnessError{
+    /// The node has no handshake with the server it joined
+    JoinTimeout{node: usize, target: SocketAddr},
+    /// The peer tables did not converge. Holds every (node, peer) pair still missing
+    NotConverged(Vec<(usize, usize)>),
+    /// Killed nodes are still in the tables of the nodes in the list
+    NotRemoved{node: usize, holders: Vec<usize>},
+    NoSuchNode(usize),
+}
+
+impl TestCluster{
+    pub fn new(mode: NodeRuntime) -> TestCluster {
+        let runtime = match mode{
+            NodeRuntime::Shared => Some(Arc::new(tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap())),
+            NodeRuntime::PerNode => None,
+        };
+        TestCluster{ mode, runtime, nodes: vec![], tuning: None }
+    }
+    /// Starts `nodes` servers of which the last `private` are not discoverable, and joins
+    /// every one of them to the first
+    pub fn start(mode: NodeRuntime, nodes: usize, private: usize) -> Result<TestCluster, HarnessError> {
+        let mut cluster = Self::new(mode);
+        let private = private.min(nodes.saturating_sub(1));
+        for index in 0..nodes{
+            cluster.add_node(index < nodes - private);
+        }
+        for index in 1..nodes{
+            cluster.join(index, 0)?;
+        }
+        Ok(cluster)
+    }
+    /// Uses `tuning` on every node, started or yet to start
+    /// Tests of keep alive expiry want a much shorter interval than the default
+    pub fn set_tuning(&mut self, tuning: Tuning) -> Result<(), TuningError>{
+        tuning.check()?;
+        for (server, _) in self.nodes.iter(){
+            server.set_tuning(tuning)?;
+        }
+        self.tuning = Some(tuning);
+        Ok(())
+    }
+    /// Starts a server on an ephemeral loopback port without joining anyone
+    pub fn add_node(&mut self, discoverable: bool) -> usize {
+        let bind = SocketAddr::from(([127, 0, 0, 1], 0));
+        let server = LocalServer::new(Some(bind), discoverable, self.runtime.clone(), None);
+        if let Some(tuning) = self.tuning{
+            // Checked when it was set
+            let _ = server.set_tuning(tuning);
+        }
+        self.nodes.push((server, true));
+        self.nodes.len() - 1
+    }
+    /// Joins `node` to the cluster `target` is part of through `join_server` and waits
+    /// for the handshake, since a server only hands out the peers it already has
+    pub fn join(&self, node: usize, target: usize) -> Result<(), HarnessError> {
+        let server = self.server(node).ok_or(HarnessError::NoSuchNode(node))?;
+        let target = self.server(target).ok_or(HarnessError::NoSuchNode(target))?.local_address();
+        let runtime = server.get_runtime();
+        runtime.block_on(LocalServer::join_server(server.clone(), target));
+        let deadline = Instant::now() + Duration::from_millis(HARNESS_JOIN_TIMEOUT);
+        while runtime.block_on(server.peer_info(target)).is_none(){
+            if Instant::now() > deadline{
+                return Err(HarnessError::JoinTimeout{ node, target });
+            }
+            sleep(Duration::from_millis(HARNESS_POLL));
+        }
+        Ok(())
+    }
+    /// Stops a node without telling its peers, they have to notice through keep alives
+    pub fn kill(&mut self, node: usize) -> Result<(), HarnessError> {
+        let (server, alive) = self.nodes.get_mut(node).ok_or(HarnessError::NoSuchNode(node))?;
+        server.shutdown();
+        *alive = false;
+        Ok(())
+    }
+    /// Makes a node leave the cluster gracefully
+    pub fn leave(&mut self, node: usize) -> Result<(), HarnessError> {
+        let (server, alive) = self.nodes.get_mut(node).ok_or(HarnessError::NoSuchNode(node))?;
+        if *alive{
+            server.get_runtime().block_on(LocalServer::leave(server.clone()));
+        }
+        *alive = false;
+        Ok(())
+    }
+    pub fn server(&self, node: usize) -> Option<Arc<LocalServer>> {
+        self.nodes.get(node).map(|n| n.0.clone())
+    }
+    pub fn address(&self, node: usize) -> Option<SocketAddr> {
+        self.nodes.get(node).map(|n| n.0.local_address())
+    }
+    pub fn is_alive(&self, node: usize) -> bool {
+        self.nodes.get(node).map(|n| n.1).unwrap_or(false)
+    }
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+    pub fn mode(&self) -> NodeRuntime {
+        self.mode
+    }
+    /// The indices of the nodes in the peer table of `node`
+    pub fn peers(&self, node: usize) -> Vec<usize> {
+        let Some(server) = self.server(node) else {return vec![]};
+        let known = server.get_runtime().block_on(server.peer_addresses());
+        self.indices(&known)
+    }
+    /// Every (node, peer) pair where a live node lacks a live peer it should have
+    /// Discoverable servers are told about everyone, private servers only about discoverable ones
+    pub fn missing_peers(&self) -> Vec<(usize, usize)> {
+        let mut missing = vec![];
+        for (node, (server, alive)) in self.nodes.iter().enumerate(){
+            if !alive{
+                continue;
+            }
+            let peers = self.peers(node);
+            for (other, (other_server, other_alive)) in self.nodes.iter().enumerate(){
+                let expected = server.is_discoverable() || other_server.is_discoverable();
+                if other != node && *other_alive && expected && !peers.contains(&other){
+                    missing.push((node, other));
+                }
+            }
+        }
+        missing
+    }
+    /// Waits until every live node has every peer it should
+    pub fn wait_converged(&self, timeout: Duration) -> Result<(), HarnessError> {
+        let deadline = Instant::now() + timeout;
+        loop{
+            let missing = self.missing_peers();
+            if missing.is_empty(){
+                return Ok(());
+            }
+            if Instant::now() > deadline{
+                return Err(HarnessError::NotConverged(missing));
+            }
+            sleep(Duration::from_millis(HARNESS_POLL));
+        }
+    }
+    /// The live nodes that still have `node` in their peer or keep alive tables
+    pub fn holders(&self, node: usize) -> Vec<usize> {
+        let Some(address) = self.address(node) else {return vec![]};
+        let mut holders = vec![];
+        for (index, (server, alive)) in self.nodes.iter().enumerate(){
+            if !alive || index == node{
+                continue;
+            }
+            let runtime = server.get_runtime();
+            let held = runtime.block_on(server.peer_addresses()).contains(&address)
+                || runtime.block_on(server.known_servers()).contains(&address);
+            if held{
+                holders.push(index);
+            }
+        }
+        holders
+    }
+    /// Waits until no live node has `node` in its peer or keep alive tables anymore
+    pub fn wait_removed(&self, node: usize, timeout: Duration) -> Result<(), HarnessError> {
+        if node >= self.nodes.len(){
+            return Err(HarnessError::NoSuchNode(node));
+        }
+        let deadline = Instant::now() + timeout;
+        loop{
+            let holders = self.holders(node);
+            if holders.is_empty(){
+                return Ok(());
+            }
+            if Instant::now() > deadline{
+                return Err(HarnessError::NotRemoved{ node, holders });
+            }
+            sleep(Duration::from_millis(HARNESS_POLL));
+        }
+    }
+    /// Stops every node that is still running
+    pub fn stop(&mut self){
+        for (server, alive) in self.nodes.iter_mut(){
+            if *alive{
+                server.shutdown();
+                *alive = false;
+            }
+        }
+    }
+
+    fn indices(&self, addresses: &[SocketAddr]) -> Vec<usize> {
+        addresses.iter().filter_map(|a| self.nodes.iter().position(|n| n.0.local_address() == *a)).collect()
+    }
+}
+
+impl Drop for TestCluster{
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl fmt::Display for HarnessError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            HarnessError::JoinTimeout{ node, target } => write!(f, "Node {} got no handshake from {}", node, target),
+            HarnessError::NotConverged(missing) => {
+                write!(f, "The cluster did not converge, missing")?;
+                for (node, peer) in missing{
+                    write!(f, " {}->{}", node, peer)?;
+                }
+                Ok(())
+            },
+            HarnessError::NotRemoved{ node, holders } => write!(f, "Node {} is still known to nodes {:?}", node, holders),
+            HarnessError::NoSuchNode(node) => write!(f, "There is no node {}", node),
+        }
+    }
+}
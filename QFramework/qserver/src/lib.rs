@@ -7,27 +7,89 @@ use tokio::{runtime::Runtime, net::UdpSocket, sync::RwLock, time::{Duration,slee
 mod local_server;
 mod station;
 mod message_exchange;
+mod capture;
+mod dissect;
+mod protocol;
+mod admission;
+mod transfer;
+mod replicated;
+mod election;
+mod ownership;
+#[doc(hidden)]
+pub mod logging;
+mod tuning;
+mod harness;
+
+pub use station::{TraceContext, StationSendError};
+pub use message_exchange::{MessageExchangeHeader, ExchangeCounts, Reassembly, ReassemblyError, fragment_message, MAX_FRAGMENT_COUNT};
+pub use station::StationHeader;
+pub use dissect::{Dissection, channel_name};
+pub use protocol::{PROTOCOL_VERSION, MIN_COMPATIBLE_VERSION, Capabilities, Handshake, PeerInfo, HandshakeError};
+pub use admission::{RateLimits, Cidr, CidrParseError};
+pub use transfer::{BulkSender, BulkReceiver, ReceivedObject, TransferMessage, TransferProgress, TransferError, Checksum, DEFAULT_CHUNK_SIZE};
+pub use replicated::{ReplicatedStore, ReplicaMessage, Version, Entry, WatchEvent};
+pub use election::{LeaderElection, ElectionMessage, LeadershipEvent, FencingToken};
+pub use ownership::{ShardOwnership, OwnershipMessage, OwnershipError, ShardRecord, ShardRequest, ShardId, shard_of};
+pub use capture::{CaptureReader, CaptureRecord, Direction, ReplayReport};
+pub use logging::{LogLevel, LogLevelParseError, set_log_level, log_level};
+pub use tuning::{Tuning, TuningError};
+pub use harness::{TestCluster, NodeRuntime, HarnessError};
 
 
 pub(crate) const MAX_MESSAGE_LENGTH: usize = 1024;
 pub(crate) const KEEP_ALIVE_TIMEOUT: u64 = 500;
 pub(crate) const KEEP_ALIVE_BUDGET: usize = 3;
+pub(crate) const DRAIN_TIMEOUT: u64 = 5000;
+pub(crate) const DRAIN_POLL: u64 = 50;
 pub(crate) const NO_MESSAGE_CHANNEL:u32 = u32::MAX;
 pub(crate) const PING_CHANNEL:u32 = u32::MAX - 1;
 pub(crate) const SERVER_CHANNEL:u32 = u32::MAX - 2;
 pub(crate) const NO_DELIVER_CHANNEL:u32 = u32::MAX - 3;
 pub(crate) type SocketPacket = (usize, SocketAddr, [u8; MAX_MESSAGE_LENGTH]);
+/// The datagrams of one priority class waiting for the udp outtake
+pub(crate) type OutboundQueue = (flume::Sender<(SocketAddr, Vec<u8>)>, flume::Receiver<(SocketAddr, Vec<u8>)>);
+
+/// The scheduling class of an outbound exchange
+/// Queued datagrams of a higher class are put on the wire before any datagram of a lower
+/// class, but bulk still gets one datagram in every `LOWER_CLASS_SHARE + 1` past interactive traffic
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Priority{
+    /// Keep alives, pings and server channel communication
+    Control,
+    /// Normal station traffic
+    Interactive,
+    /// Large user payloads that can wait
+    Bulk,
+}
+pub(crate) const PRIORITY_CLASSES: usize = 3;
+/// How many interactive datagrams a waiting bulk datagram lets go first, control is never held back
+pub(crate) const LOWER_CLASS_SHARE: usize = 8;
 
 pub trait StationOperable{
     fn to_bytes(&self) -> Vec<u8>;
     fn from_bytes(bytes: &[u8]) -> Self;
+    /// Like `from_bytes` but None for bytes that do not decode
+    /// Stations receive through this and drop what does not decode, so types that can be sent
+    /// junk by other servers should override it
+    fn try_from_bytes(bytes: &[u8]) -> Option<Self> where Self: Sized {
+        Some(Self::from_bytes(bytes))
+    }
 }
 #[derive(Clone, Serialize, Deserialize)]
 pub enum ServerInternalComm{
-    // When you ping you send a bool that determines discoverability
-    Ping(bool),
+    // When you ping you send your handshake which includes your discoverability
+    Ping(Handshake),
     KeepAlive(bool),
     AddrDownload(Vec<SocketAddr>),
+    // The answer to a compatible ping
+    Welcome(Handshake),
+    // The answer to an incompatible ping
+    Reject(Handshake),
+    // The sender is shutting down and should be dropped straight away
+    Leave,
+    // Never sent, stands in for a message from a server speaking a protocol version we can not parse
+    #[serde(skip)]
+    Incompatible(u16),
 }
 
 /// The main struct of the QServer library
@@ -45,12 +107,24 @@ pub struct LocalServer{
     life: TerminateSignal,
     /// The state of all known servers
     keep_alive_tasks: RwLock<HashMap<SocketAddr, flume::Sender<bool>>>,
+    /// The negotiated protocol state of all servers that completed a handshake
+    peers: RwLock<HashMap<SocketAddr, PeerInfo>>,
+    /// Totals of every exchange run since start
+    exchange_counters: message_exchange::ExchangeCounters,
     /// The state of all live message exchanges
     message_exchanges: RwLock<HashMap<u64, Arc<(flume::Sender<SocketPacket>, flume::Receiver<SocketPacket>)>>>,
     /// The state of all known comm ports
     stations: RwLock<HashMap<station::StationChannel, HashMap<station::StationId, flume::Sender<(SocketAddr, Vec<u8>)>>>>,    
+    /// The outbound datagram queues, one for each priority class
+    outbound: [OutboundQueue; PRIORITY_CLASSES],
+    /// The datagram capture, if one is running
+    capture: std::sync::Mutex<Option<capture::CaptureWriter>>,
+    /// The protocol timers
+    tuning: std::sync::RwLock<Tuning>,
+    /// The abuse protection settings and per source rate state
+    admission: std::sync::Mutex<admission::Admission>,
     /// Server Communication Station ID
-    internal_station_channel: Option<flume::Sender<(SocketAddr, Vec<u8>)>>,
+    internal_station_id: StationId,
 }
 
 /// The CommPort struct represents a channel for users to push data to a live CommGroup for transfer.
@@ -61,13 +135,29 @@ pub struct Station<T: StationOperable> {
     intake: (flume::Sender<(SocketAddr, Vec<u8>)>, flume::Receiver<(SocketAddr, Vec<u8>)>),
     known_stations: HashMap<station::StationId, SocketAddr>,
     message_queue: VecDeque<(SocketAddr,Vec<u8>)>,
+    /// The class all of this station's sends are scheduled with
+    priority: Priority,
     object: Option<T>,
 }
 
 
-#[derive(Clone)]
+/// A snapshot of a LocalServer
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ServerStatus{
+    pub address: SocketAddr,
+    pub discoverable: bool,
+    /// Servers that completed a handshake
+    pub peers: usize,
+    /// Servers we are running a keep alive for
+    pub keep_alives: usize,
+    pub stations: usize,
+    /// Live message exchanges
+    pub exchanges: usize,
+}
+
 struct TerminateSignal {
-    channel: (flume::Sender<bool>, flume::Receiver<bool>),
+    /// Only the parent holds the sender, dropping it notifies every child
+    channel: (Option<tokio::sync::watch::Sender<bool>>, tokio::sync::watch::Receiver<bool>),
 }
 
 pub(crate) async fn async_timer(timeout: u64){
@@ -8,8 +8,9 @@ use tokio::time::{self, timeout};
 use tokio::{net::UdpSocket, runtime::Runtime, sync::{RwLock, RwLockReadGuard, RwLockWriteGuard}, time::sleep};
 
 use crate::station::{StationReturn, StationId, self};
-use crate::{KEEP_ALIVE_TIMEOUT, KEEP_ALIVE_BUDGET, async_timer, StationOperable, Station, SERVER_CHANNEL, ServerInternalComm, NO_DELIVER_CHANNEL};
-use crate::{LocalServer, SocketPacket, TerminateSignal, message_exchange::MessageOp, MAX_MESSAGE_LENGTH, station::StationHeader};
+use crate::{qlog, logging::LogLevel};
+use crate::{async_timer, DRAIN_POLL, StationOperable, Station, SERVER_CHANNEL, ServerInternalComm, NO_DELIVER_CHANNEL, Priority, PRIORITY_CLASSES, LOWER_CLASS_SHARE, OutboundQueue};
+use crate::{LocalServer, SocketPacket, TerminateSignal, message_exchange::MessageOp, MAX_MESSAGE_LENGTH, capture::Direction, Handshake, PeerInfo, HandshakeError, PROTOCOL_VERSION, MIN_COMPATIBLE_VERSION, RateLimits, admission::Admission, ServerStatus, Tuning};
 
 impl LocalServer{
     ///
@@ -35,11 +36,14 @@ impl LocalServer{
         };
         let socket = Self::new_socket(target_socket, target_runtime.clone());
         let life = TerminateSignal::new();
-        let foreign_servers = RwLock::new(HashMap::new());
+        let keep_alive_tasks = RwLock::new(HashMap::new());
+        let peers = RwLock::new(HashMap::new());
         let message_exchanges = RwLock::new(HashMap::new());
         let stations = RwLock::new(HashMap::new());
+        let outbound = [flume::unbounded(), flume::unbounded(), flume::unbounded()];
+        let admission = std::sync::Mutex::new(Admission::new(RateLimits::default()));
         
-        println!(
+        qlog!(LogLevel::Info,
             "Started Cluster Terminal on {}",
             socket.local_addr().unwrap()
         );
@@ -50,15 +54,21 @@ impl LocalServer{
             discoverable,
             socket,
             life,
-            foreign_servers,
+            keep_alive_tasks,
+            peers,
+            exchange_counters: Default::default(),
             message_exchanges,
             stations,
-            internal_station_channel: None,
+            outbound,
+            capture: std::sync::Mutex::new(None),
+            tuning: std::sync::RwLock::new(Tuning::default()),
+            admission,
+            internal_station_id,
             });
         target_runtime.spawn(Self::udp_intake(server.clone()));
+        target_runtime.spawn(Self::udp_outtake(server.clone()));
         let station:Station<ServerInternalComm> = Station::new(server.clone(), SERVER_CHANNEL, Some(internal_station_id));
         target_runtime.spawn(Self::server_comm(server.clone(), join_server, station));
-        server.internal_station_channel = Some(station.get_sender());
         server
     }
     
@@ -66,88 +76,230 @@ impl LocalServer{
         let lifetime = server.life.subscribe();
         loop {
             tokio::select! {
-                _ = lifetime.terminated()=>{println!("Shutting down main udp listener for {}", server.local_address());break;}
+                _ = lifetime.terminated()=>{qlog!(LogLevel::Debug, "Shutting down main udp listener for {}", server.local_address());break;}
                 message = server.recieve()=>{
+                    // Sources that are denied or over their rate get dropped before we spend a task on them
+                    if !server.admit(message.1){
+                        continue;
+                    }
                     // We need to update a keep alive if we get a message
-                    Self::update_foreign_server(server.clone(), message.1);
+                    // New keep alives are only started by a handshake, so unknown sources can't create them
+                    server.refresh_foreign_server(message.1).await;
                     let op = MessageOp::Receive(message);
                     tokio::spawn(Self::exchange(server.clone(), op));
                 }
             }
         }
     }
+    /// Udp outtake is the only task that writes to the socket
+    /// It drains the queues in priority order, see `next_outbound`, so control traffic never
+    /// waits for more than the single datagram currently being written
+    async fn udp_outtake(server: Arc<LocalServer>){
+        let lifetime = server.life.subscribe();
+        let mut passed_over = [0; PRIORITY_CLASSES];
+        loop {
+            // If anything is already waiting we take it without yielding
+            if let Some((tgt, data)) = next_outbound(&server.outbound, &mut passed_over){
+                server.send(tgt, &data).await;
+                continue;
+            }
+            // Otherwise we wait for any class to get something
+            let (control, interactive, bulk) = (&server.outbound[0].1, &server.outbound[1].1, &server.outbound[2].1);
+            let datagram = tokio::select! {
+                biased;
+                _ = lifetime.terminated()=>{qlog!(LogLevel::Debug, "Shutting down main udp sender for {}", server.local_address());break;}
+                datagram = control.recv_async()=>datagram,
+                datagram = interactive.recv_async()=>datagram,
+                datagram = bulk.recv_async()=>datagram,
+            };
+            if let Ok((tgt, data)) = datagram{
+                server.send(tgt, &data).await;
+            }
+        }
+    }
     /// Server comm is the task that handles inter-server communication
     /// It is responsible for cluster discovery and contact
     async fn server_comm(server: Arc<Self>, tgt_cluster: Option<SocketAddr>, mut station: Station<ServerInternalComm>){
         let life = server.life.subscribe();
         
-        // The server map that stores the discoverablility of known servers
-        let server_map:HashMap<SocketAddr, bool> = HashMap::new();
+        // Our first contact with the cluster is the handshake ping
+        if let Some(tgt) = tgt_cluster{
+            Self::connect_to_server(server.clone(), &station, tgt, server.discoverable).await;
+        }
         
         loop{
             tokio::select!{
-                _ = life.terminated()=>{println!("Shutting down server comm for {}", server.local_address()); break;}
+                _ = life.terminated()=>{qlog!(LogLevel::Debug, "Shutting down server comm for {}", server.local_address()); break;}
                 // We just wait for any traffic to the main station and then have the server process it
                 message = station.listen()=>{
                     // We have to do this cause the traffic we got may have just been internal or no message
                     if let Some(message) = message{
-                        Self::process_message(server.clone(), &server_map, &mut station, message).await;
+                        Self::process_message(server.clone(), &mut station, message).await;
                     }
                 }
             }
         }
         
     }
-    async fn process_message(server: Arc<LocalServer>, server_map: &HashMap<SocketAddr, bool>, station: &mut Station<ServerInternalComm>, message: StationReturn<ServerInternalComm>){
+    async fn process_message(server: Arc<LocalServer>, station: &mut Station<ServerInternalComm>, message: StationReturn<ServerInternalComm>){
         let (source, from_id, message) = message;
         match message{
             // A ping is a server making first contact with:
-            ServerInternalComm::Ping(discoverable) => {
-                // First we prepare to send all available public addrs
-                let servers = server.read_servers().await;
+            ServerInternalComm::Ping(handshake) => {
+                // Before anything else we make sure we can understand each other
+                let local = Handshake::local(server.discoverable);
+                if let Err(_) = Self::add_peer(server.clone(), source, &handshake).await{
+                    let _ = station.send(from_id, true, &ServerInternalComm::Reject(local)).await;
+                    return;
+                }
+                let _ = station.send(from_id, true, &ServerInternalComm::Welcome(local)).await;
+                
+                // Then we prepare to send all available public addrs
+                let peers:Vec<(SocketAddr, PeerInfo)> = server.read_peers().await.iter().filter(|p| *p.0 != source).map(|p| (*p.0, *p.1)).collect();
                 // This gets all discoverable addrs and sends them
-                let addrs:Vec<SocketAddr> = servers.iter().filter(|s| s.1.0).map(|s| *s.0).collect();
+                let addrs:Vec<SocketAddr> = peers.iter().filter(|p| p.1.discoverable).map(|p| p.0).collect();
                 let comm = ServerInternalComm::AddrDownload(addrs);
                 let _ = station.send(from_id, true, &comm).await;
                 
                 // Now we send each private addr the new source if its discoverable
-                if discoverable{
-                    let privates = servers.iter().filter(|s| !s.1.0).map(|s| *s.0);
+                if handshake.discoverable{
+                    let privates = peers.iter().filter(|p| !p.1.discoverable).map(|p| p.0);
                     for private in privates{
-                        let comm = bincode::serialize(&ServerInternalComm::AddrDownload(vec![source])).unwrap();
+                        let comm = ServerInternalComm::AddrDownload(vec![source]).to_bytes();
                         let mut header = bincode::serialize(&station::make_header(SERVER_CHANNEL, 0, 0)).unwrap();
                         header.extend_from_slice(&comm);
-                        let op = MessageOp::Send(private, true, header);
+                        let op = MessageOp::Send(private, true, Priority::Control, header);
                         let _ = Self::exchange(server.clone(), op).await;
                     }
                 }
                 
                 // Lastly we establish keep alive 
-                Self::update_foreign_server(server.clone(), discoverable, source).await;
+                Self::update_foreign_server(server.clone(), source).await;
                 
             },
             ServerInternalComm::KeepAlive(discoverable) => {
-                Self::update_foreign_server(server.clone(), discoverable,source).await;
+                if let Some(peer) = server.write_peers().await.get_mut(&source){
+                    peer.discoverable = discoverable;
+                }
+                Self::update_foreign_server(server.clone(), source).await;
+            },
+            ServerInternalComm::Welcome(handshake) => {
+                // The server we pinged accepted us, but we still have to accept it
+                if let Ok(_) = Self::add_peer(server.clone(), source, &handshake).await{
+                    Self::update_foreign_server(server.clone(), source).await;
+                }
+            },
+            ServerInternalComm::Reject(handshake) => {
+                let reason = HandshakeError::IncompatibleVersion{ ours: PROTOCOL_VERSION, theirs: handshake.version };
+                qlog!(LogLevel::Warn, "Server {} was rejected by {}: {}", server.local_address(), source, reason);
+                server.write_peers().await.remove(&source);
+            },
+            ServerInternalComm::Leave => {
+                qlog!(LogLevel::Info, "Server {} was told server {} is leaving", server.local_address(), source);
+                server.write_peers().await.remove(&source);
+                // The keep alive may be holding an update so we hand the stop over in the background
+                if let Some(sender) = server.write_server().await.remove(&source){
+                    tokio::spawn(async move {let _ = sender.send_async(false).await;});
+                }
+            },
+            ServerInternalComm::Incompatible(version) => {
+                // Whatever it sent, we can not talk to it, so it gets the same answer as an incompatible ping
+                let reason = HandshakeError::IncompatibleVersion{ ours: PROTOCOL_VERSION, theirs: version };
+                qlog!(LogLevel::Warn, "Server {} refused a message from {}: {}", server.local_address(), source, reason);
+                server.write_peers().await.remove(&source);
+                let _ = station.send(from_id, true, &ServerInternalComm::Reject(Handshake::local(server.discoverable))).await;
             },
             ServerInternalComm::AddrDownload(addrs) => {
-                for addr in addrs.iter(){
-                    println!("Server {} discovered server {}", server.local_address(), addr);
+                // Every ping is answered with a download, so pinging servers we already
+                // shook hands with would have us and them ping each other forever
+                let peers = server.peer_addresses().await;
+                for addr in addrs.iter().filter(|a| !peers.contains(a) && **a != server.local_address()){
+                    qlog!(LogLevel::Info, "Server {} discovered server {}", server.local_address(), addr);
                     Self::connect_to_server(server.clone(), station, *addr, server.discoverable).await;
                 }
             },
         }
     }
     pub async fn connect_to_server(server: Arc<LocalServer>, station: &Station<ServerInternalComm>, tgt: SocketAddr, discoverable: bool){
-        // Here we just send the initial server ping
-        let ping = bincode::serialize(&ServerInternalComm::Ping(discoverable)).unwrap();
+        // Here we just send the initial server ping which carries our handshake
+        let ping = ServerInternalComm::Ping(Handshake::local(discoverable)).to_bytes();
         let mut header = bincode::serialize(&station::make_header(SERVER_CHANNEL, station.id, 0)).unwrap();
         header.extend_from_slice(&ping);
-        let op = MessageOp::Send(tgt, true, header);
-        if let Err(_) = server.runtime.block_on(Self::exchange(server.clone(), op)){
-            panic!("Failed to connect to tgt server");
+        let op = MessageOp::Send(tgt, true, Priority::Control, header);
+        if let Err(_) = Self::exchange(server.clone(), op).await{
+            qlog!(LogLevel::Warn, "Server {} failed to connect to tgt server {}", server.local_address(), tgt);
         }
         
     }
+    /// Joins the cluster `tgt` is part of by sending it our handshake ping
+    pub async fn join_server(server: Arc<LocalServer>, tgt: SocketAddr){
+        let ping = ServerInternalComm::Ping(Handshake::local(server.discoverable)).to_bytes();
+        let mut header = bincode::serialize(&station::make_header(SERVER_CHANNEL, server.internal_station_id, 0)).unwrap();
+        header.extend_from_slice(&ping);
+        let op = MessageOp::Send(tgt, true, Priority::Control, header);
+        if let Err(_) = Self::exchange(server.clone(), op).await{
+            qlog!(LogLevel::Warn, "Server {} failed to join server {}", server.local_address(), tgt);
+        }
+    }
+    /// A snapshot of the server's state
+    pub async fn status(&self) -> ServerStatus {
+        ServerStatus{
+            address: self.local_address(),
+            discoverable: self.discoverable,
+            peers: self.read_peers().await.len(),
+            keep_alives: self.read_servers().await.len(),
+            stations: self.read_stations().await.values().map(|c| c.len()).sum(),
+            exchanges: self.read_exchanges().await.len() }
+    }
+    /// The ids of every station on this server by channel
+    pub async fn station_table(&self) -> HashMap<u32, Vec<u64>> {
+        self.read_stations().await.iter().map(|(channel, stations)| (*channel, stations.keys().copied().collect())).collect()
+    }
+    /// Tells every peer we are going, drains, then shuts down
+    pub async fn leave(server: Arc<LocalServer>){
+        let peers = server.peer_addresses().await;
+        let leave = ServerInternalComm::Leave.to_bytes();
+        for peer in peers{
+            let mut header = bincode::serialize(&station::make_header(SERVER_CHANNEL, server.internal_station_id, 0)).unwrap();
+            header.extend_from_slice(&leave);
+            let op = MessageOp::Send(peer, true, Priority::Control, header);
+            if Self::exchange(server.clone(), op).await.is_err(){
+                qlog!(LogLevel::Warn, "Server {} could not tell server {} it is leaving", server.local_address(), peer);
+            }
+        }
+        server.drain(server.tuning().drain_timeout).await;
+        server.shutdown();
+    }
+    /// Waits for every exchange live right now to finish and every queued datagram to be written
+    /// Exchanges started meanwhile, such as keep alives, are not waited on
+    /// Returns false if that took longer than `limit` milliseconds
+    pub async fn drain(&self, limit: u64) -> bool {
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(limit);
+        let live: Vec<u64> = self.read_exchanges().await.keys().copied().collect();
+        loop{
+            let queued: usize = self.outbound.iter().map(|queue| queue.1.len()).sum();
+            let exchanges = self.read_exchanges().await;
+            if queued == 0 && !live.iter().any(|id| exchanges.contains_key(id)){
+                return true;
+            }
+            drop(exchanges);
+            if tokio::time::Instant::now() >= deadline{
+                qlog!(LogLevel::Warn, "Server {} gave up draining with {} datagrams queued", self.local_address(), queued);
+                return false;
+            }
+            async_timer(DRAIN_POLL).await;
+        }
+    }
+    /// Stops every task the server started
+    /// Peers will notice through their keep alives running out
+    pub fn shutdown(&self){
+        qlog!(LogLevel::Info, "Shutting down server {}", self.local_address());
+        self.life.terminate();
+    }
+    /// Resolves once the server has been shut down
+    pub async fn stopped(&self){
+        self.life.subscribe().terminated().await;
+    }
     pub fn get_runtime(&self) -> Arc<Runtime> {
         self.runtime.clone()
     }
@@ -163,26 +315,10 @@ impl LocalServer{
     // Upon receiving any message from a particular address we update a keep alive process
     // Keep alives then send a NO_DELIVER message to the source addr which will be ignored by 
     // the source
-    async fn keep_alive(server: Arc<LocalServer>, addr:SocketAddr){
-        println!("Keep alive started for tgt {}", addr);
-        // The first thing we need is an entry into the foreign servers list so we can be found
-        let (tx, rx) = flume::bounded(1);
-        {
-            let mut writer = server.write_server().await;
-            // Has one been added since we get the writer?
-            if let Some(sender) = writer.get(&addr){
-                let _ = sender.try_send(true);
-                // If this is the case then there is some other keep alive task going so we 
-                // can go ahead and cancel this one
-                return;
-            }
-            else{
-                // If not we add one and continue
-                writer.insert(addr, tx);
-            }
-        }
-        
-        let mut keep_alive_budget = KEEP_ALIVE_BUDGET;
+    async fn keep_alive(server: Arc<LocalServer>, addr:SocketAddr, rx: flume::Receiver<bool>){
+        qlog!(LogLevel::Debug, "Keep alive started for tgt {}", addr);
+        let tuning = server.tuning();
+        let mut keep_alive_budget = tuning.keep_alive_budget;
         
         while keep_alive_budget > 0{
             // Each loop we must count down the keep alive budget
@@ -190,29 +326,44 @@ impl LocalServer{
             keep_alive_budget -= 1;
             
             // We need to check for an update
-            if let Ok(_) = rx.try_recv(){
-                keep_alive_budget = KEEP_ALIVE_BUDGET;
-                println!("Keep alive maintained for tgt {}", addr);
+            match rx.try_recv(){
+                Ok(true) => {
+                    keep_alive_budget = tuning.keep_alive_budget;
+                    qlog!(LogLevel::Debug, "Keep alive maintained for tgt {}", addr);
+                },
+                // The server told us it left and its entries are already gone
+                // A new keep alive may have replaced ours since so we leave the table alone
+                Ok(false) => {
+                    qlog!(LogLevel::Debug, "Keep alive stopped for tgt {}", addr);
+                    return;
+                },
+                Err(_) => {},
             }
             
             // Now we send this cycle's keep alive message
             // Keep alive messages are just NO_DELIVERs 
             let keep_alive = bincode::serialize(&station::make_header(NO_DELIVER_CHANNEL,0,0)).unwrap();
-            let op = MessageOp::Send(addr,true,keep_alive);
+            // These are control traffic so a peer under heavy load is not wrongly declared dead
+            let op = MessageOp::Send(addr,true,Priority::Control,keep_alive);
             let _ = Self::exchange(server.clone(), op).await;
-            async_timer(KEEP_ALIVE_TIMEOUT).await;
+            async_timer(tuning.keep_alive_interval).await;
         }
         // If we run out of keep alives we will need to remove the entry from the foreign servers list
         let mut writer = server.write_server().await;
         writer.remove(&addr);
-        println!("Keep alive stopped for tgt {}", addr);
+        // A server we can no longer reach is no longer a peer
+        server.write_peers().await.remove(&addr);
+        qlog!(LogLevel::Debug, "Keep alive stopped for tgt {}", addr);
     }
 }
 
 /// State management functionality
 impl LocalServer{
     pub(crate) async fn read_servers(&self) -> RwLockReadGuard<HashMap<SocketAddr, flume::Sender<bool>>> {
-        self.foreign_servers.read().await
+        self.keep_alive_tasks.read().await
+    }
+    pub(crate) async fn read_peers(&self) -> RwLockReadGuard<HashMap<SocketAddr, PeerInfo>> {
+        self.peers.read().await
     }
     pub(crate) async fn read_exchanges(&self) -> RwLockReadGuard<HashMap<u64, Arc<(flume::Sender<SocketPacket>, flume::Receiver<SocketPacket>)>>> {
         self.message_exchanges.read().await
@@ -221,7 +372,10 @@ impl LocalServer{
         self.stations.read().await
     }
     pub(crate) async fn write_server(&self) -> RwLockWriteGuard<HashMap<SocketAddr, flume::Sender<bool>>> {
-        self.foreign_servers.write().await
+        self.keep_alive_tasks.write().await
+    }
+    pub(crate) async fn write_peers(&self) -> RwLockWriteGuard<HashMap<SocketAddr, PeerInfo>> {
+        self.peers.write().await
     }
     pub(crate) async fn write_exchanges(&self) -> RwLockWriteGuard<HashMap<u64, Arc<(flume::Sender<SocketPacket>, flume::Receiver<SocketPacket>)>>> {
         self.message_exchanges.write().await
@@ -231,12 +385,28 @@ impl LocalServer{
     }
     pub(crate) async fn update_foreign_server(server:Arc<LocalServer>, addr: SocketAddr){
         // First we see if one exisits
-        let reader = server.read_servers().await;
-        if let Some(sender) = reader.get(&addr){
+        let mut writer = server.write_server().await;
+        if let Some(sender) = writer.get(&addr){
             let _ = sender.try_send(true);
+            return;
+        }
+        else if writer.len() >= server.max_peers(){
+            // We are tracking as many servers as we are allowed to
+            qlog!(LogLevel::Warn, "Server {} refused keep alive for tgt {}: peer limit of {} reached", server.local_address(), addr, server.max_peers());
+            return;
         }
         // If not we start one
-        tokio::spawn(Self::keep_alive(server.clone(), addr));
+        // The entry goes in before the task starts so a leave processed straight after
+        // finds it instead of the task adding the leaving server back afterwards
+        let (tx, rx) = flume::bounded(1);
+        writer.insert(addr, tx);
+        tokio::spawn(Self::keep_alive(server.clone(), addr, rx));
+    }
+    /// Resets the keep alive of a known server without ever starting a new one
+    pub(crate) async fn refresh_foreign_server(&self, addr: SocketAddr){
+        if let Some(sender) = self.read_servers().await.get(&addr){
+            let _ = sender.try_send(true);
+        }
     }
 }
 
@@ -256,48 +426,180 @@ impl LocalServer{
         let mut data = [0; MAX_MESSAGE_LENGTH];
         loop {
             if let Ok((len, addr)) = self.socket.recv_from(&mut data).await {
+                self.capture_datagram(Direction::Inbound, addr, &data[..len]);
                 return (len, addr, data);
             }
         }
     }
+    /// * `priority` - The class the datagram is scheduled with
+    /// * `tgt` - The target address
+    /// * `data` - The datagram
+    /// Queues a datagram for the udp outtake task
+    pub(crate) fn queue_send(&self, priority: Priority, tgt: SocketAddr, data: &[u8]) {
+        let _ = self.outbound[priority.class()].0.send((tgt, data.to_vec()));
+    }
     /// * `tgt` - The target address
     /// * `data` - A vector of bytes. Needs to be a vector to help with lifetime issues
     /// Async sends a message to the `tgt`
     pub(crate) async fn send(&self, tgt: SocketAddr, data: &[u8]) {
         // println!("Socket {} sent {} bytes to {}", self.local_address(), data.len(), tgt);
+        self.capture_datagram(Direction::Outbound, tgt, data);
         self.socket.send_to(&data, tgt).await.unwrap();
     }
     pub fn local_address(&self) -> SocketAddr {
         self.socket.local_addr().unwrap()
     }
+    pub fn is_discoverable(&self) -> bool {
+        self.discoverable
+    }
     
 }
 
 impl TerminateSignal {
     /// Creates a new Terminate Signal
     pub fn new() -> TerminateSignal {
+        let (tx, rx) = tokio::sync::watch::channel(false);
         TerminateSignal {
-            channel: flume::bounded(1),
+            channel: (Some(tx), rx),
         }
     }
     /// Creates a new child of the terminate signal that will be notified
     pub fn subscribe(&self) -> TerminateSignal {
         let rx = self.channel.1.clone();
-        let tx = flume::bounded(1).0;
-        TerminateSignal { channel: (tx, rx) }
+        TerminateSignal { channel: (None, rx) }
     }
-    /// What a child can wait on to be notified of parent drop
+    /// What a child can wait on to be notified of parent drop or termination
     pub async fn terminated(&self) {
-        let _ = self.channel.1.recv_async().await;
+        let mut rx = self.channel.1.clone();
+        // A child created after termination returns straight away
+        while !*rx.borrow_and_update(){
+            if rx.changed().await.is_err(){
+                return;
+            }
+        }
+    }
+    /// Notifies every child, even those subscribed later
+    pub fn terminate(&self) {
+        if let Some(tx) = &self.channel.0{
+            tx.send_replace(true);
+        }
+    }
+}
+
+/// Takes the next datagram to write from `queues`, which go highest class first
+/// The highest class with a datagram waiting goes first, except that once an interactive or
+/// bulk datagram has waited through `LOWER_CLASS_SHARE` others it goes ahead of everything but
+/// control, so sustained interactive traffic slows bulk down without stopping it.
+/// `passed_over` counts how many datagrams in a row each class has waited through
+fn next_outbound(queues: &[OutboundQueue; PRIORITY_CLASSES], passed_over: &mut [usize; PRIORITY_CLASSES]) -> Option<(SocketAddr, Vec<u8>)> {
+    let waiting: Vec<bool> = queues.iter().map(|queue| !queue.1.is_empty()).collect();
+    let first = waiting.iter().position(|w| *w)?;
+    let class = match first{
+        0 => 0,
+        _ => (first + 1..PRIORITY_CLASSES).find(|c| waiting[*c] && passed_over[*c] >= LOWER_CLASS_SHARE).unwrap_or(first),
+    };
+    for lower in class + 1..PRIORITY_CLASSES{
+        passed_over[lower] = if waiting[lower] {passed_over[lower] + 1} else {0};
+    }
+    passed_over[class] = 0;
+    // Only the outtake takes from the queues so a waiting datagram is still there
+    queues[class].1.try_recv().ok()
+}
+
+impl Priority{
+    /// The index of this class's outbound queue
+    pub(crate) fn class(&self) -> usize {
+        match self{
+            Priority::Control => 0,
+            Priority::Interactive => 1,
+            Priority::Bulk => 2,
+        }
     }
 }
 
+/// Every message starts with the protocol version of its sender, so it can be read whatever
+/// the layout of the rest
 impl StationOperable for ServerInternalComm{
     fn to_bytes(&self) -> Vec<u8> {
-        bincode::serialize(self).unwrap()
+        let mut bytes = PROTOCOL_VERSION.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&bincode::serialize(self).unwrap());
+        bytes
     }
 
     fn from_bytes(bytes: &[u8]) -> Self {
-        bincode::deserialize(bytes).unwrap()
+        // Version 0 was never spoken, so it marks a message without a readable version
+        Self::try_from_bytes(bytes).unwrap_or(ServerInternalComm::Incompatible(0))
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Option<Self> {
+        let version = u16::from_le_bytes(bytes.get(..2)?.try_into().unwrap());
+        if version < MIN_COMPATIBLE_VERSION || version > PROTOCOL_VERSION{
+            return Some(ServerInternalComm::Incompatible(version));
+        }
+        bincode::deserialize(&bytes[2..]).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn keep_alive_entry_exists_once_the_update_returns(){
+        let server = LocalServer::new(Some(SocketAddr::from(([127, 0, 0, 1], 0))), true, None, None);
+        let rt = server.get_runtime();
+        let peer = SocketAddr::from(([127, 0, 0, 1], 9));
+        rt.block_on(async {
+            LocalServer::update_foreign_server(server.clone(), peer).await;
+            // A leave handled straight after has to find the entry to stop the keep alive
+            let entry = server.write_server().await.remove(&peer);
+            assert!(entry.is_some(), "The keep alive entry was not there yet");
+            let _ = entry.unwrap().send_async(false).await;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert!(!server.known_servers().await.contains(&peer), "The stopped keep alive added the server back");
+        });
+        server.shutdown();
+    }
+
+    fn datagram(class: u8) -> (SocketAddr, Vec<u8>) {
+        (SocketAddr::from(([127, 0, 0, 1], 9)), vec![class])
+    }
+
+    #[test]
+    fn control_overtakes_queued_bulk_fragments(){
+        let queues = [flume::unbounded(), flume::unbounded(), flume::unbounded()];
+        let mut passed_over = [0; PRIORITY_CLASSES];
+        for _ in 0..100{
+            queues[Priority::Bulk.class()].0.send(datagram(2)).unwrap();
+        }
+        assert_eq!(next_outbound(&queues, &mut passed_over).unwrap().1, vec![2]);
+        // A keep alive queued behind a long bulk transfer is the next thing written
+        queues[Priority::Control.class()].0.send(datagram(0)).unwrap();
+        queues[Priority::Interactive.class()].0.send(datagram(1)).unwrap();
+        assert_eq!(next_outbound(&queues, &mut passed_over).unwrap().1, vec![0]);
+        assert_eq!(next_outbound(&queues, &mut passed_over).unwrap().1, vec![1]);
+        assert_eq!(next_outbound(&queues, &mut passed_over).unwrap().1, vec![2]);
+    }
+
+    #[test]
+    fn bulk_keeps_a_share_under_sustained_interactive_traffic(){
+        let queues = [flume::unbounded(), flume::unbounded(), flume::unbounded()];
+        let mut passed_over = [0; PRIORITY_CLASSES];
+        for _ in 0..10{
+            queues[Priority::Bulk.class()].0.send(datagram(2)).unwrap();
+        }
+        let mut written = vec![];
+        for _ in 0..(LOWER_CLASS_SHARE + 1) * 10{
+            // The interactive queue never runs dry
+            queues[Priority::Interactive.class()].0.send(datagram(1)).unwrap();
+            written.push(next_outbound(&queues, &mut passed_over).unwrap().1[0]);
+        }
+        assert_eq!(written.iter().filter(|class| **class == 2).count(), 10);
+        // Control still goes first however long bulk has waited
+        queues[Priority::Bulk.class()].0.send(datagram(2)).unwrap();
+        passed_over[Priority::Bulk.class()] = LOWER_CLASS_SHARE;
+        queues[Priority::Control.class()].0.send(datagram(0)).unwrap();
+        assert_eq!(next_outbound(&queues, &mut passed_over).unwrap().1, vec![0]);
+        assert_eq!(next_outbound(&queues, &mut passed_over).unwrap().1, vec![2]);
     }
 }
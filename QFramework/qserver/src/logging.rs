@@ -0,0 +1,86 @@
+use std::{fmt, str::FromStr, sync::atomic::{AtomicU8, Ordering}};
+use serde::{Serialize, Deserialize};
+
+/// How much the library prints
+/// Every level also prints the levels before it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel{
+    Off,
+    /// Something is broken
+    Error,
+    /// Something went wrong that the cluster will recover from
+    Warn,
+    /// Changes to the cluster such as peers joining and leaving
+    Info,
+    /// Per exchange and keep alive chatter
+    Debug,
+}
+
+/// Cluster changes and problems are printed unless told otherwise, matching qswitch's default
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+/// Sets the log level of every server in the process
+pub fn set_log_level(level: LogLevel){
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+pub fn log_level() -> LogLevel {
+    match LEVEL.load(Ordering::Relaxed){
+        0 => LogLevel::Off,
+        1 => LogLevel::Error,
+        2 => LogLevel::Warn,
+        3 => LogLevel::Info,
+        _ => LogLevel::Debug,
+    }
+}
+#[doc(hidden)]
+pub fn enabled(level: LogLevel) -> bool {
+    level != LogLevel::Off && level <= log_level()
+}
+
+/// Prints like println if `level` is enabled
+#[macro_export]
+macro_rules! qlog {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::logging::enabled($level){
+            println!($($arg)*);
+        }
+    };
+}
+
+#[derive(Debug)]
+pub struct LogLevelParseError(String);
+
+impl FromStr for LogLevel{
+    type Err = LogLevelParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str(){
+            "off" => Ok(LogLevel::Off),
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(LogLevelParseError(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for LogLevel{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self{
+            LogLevel::Off => "off",
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for LogLevelParseError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unknown log level {}, expected off, error, warn, info or debug", self.0)
+    }
+}
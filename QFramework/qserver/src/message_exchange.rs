@@ -1,28 +1,80 @@
-use std::{sync::Arc, mem::size_of, net::SocketAddr};
+use std::{sync::{Arc, atomic::{AtomicU64, Ordering}}, collections::HashMap, mem::size_of, net::SocketAddr};
 use tokio::time::{Duration, timeout};
 use serde::{Serialize, Deserialize};
 use rand::{thread_rng, Rng};
 
-use crate::{LocalServer, SocketPacket, MAX_MESSAGE_LENGTH, Station, station};
+use crate::{LocalServer, SocketPacket, MAX_MESSAGE_LENGTH, station, Priority};
+use crate::{qlog, logging::LogLevel};
 pub(crate) type Fragment = (usize, [u8; MAX_MESSAGE_LENGTH]);
 pub(crate) type Message = Vec<u8>;
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 ///Will be auto pasted onto any and all message fragments sent
-pub(crate) struct MessageExchangeHeader{
+pub struct MessageExchangeHeader{
     /// A randomly generated value that will be used by both the sender and reciever to identify
     /// live message exchanges
-    exchange_id: u64,
+    pub exchange_id: u64,
     /// The total number of fragements this message is comprised of
-    fragment_count: u32,
+    pub fragment_count: u32,
     /// This fragments index in the array of split fragments
-    fragment_index: u32,
+    pub fragment_index: u32,
     /// The number of bytes this fragment contains
-    fragment_data: u32,
+    pub fragment_data: u32,
     /// Is this message reliable
-    nak: bool,
+    pub nak: bool,
     /// This is send by the receiver in case they need a rebroadcast
-    message_complete: bool,
+    pub message_complete: bool,
+}
+/// How many exchanges a server has run since it started
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct ExchangeCounts{
+    /// Exchanges running right now, send and receive side
+    pub live: u64,
+    pub sent: u64,
+    /// Reliable sends the receiver confirmed
+    pub confirmed: u64,
+    /// Reliable sends the receiver never confirmed
+    pub unconfirmed: u64,
+    /// Messages that were fully assembled
+    pub received: u64,
+    /// Messages that were given up on before all of their fragments arrived
+    pub dropped: u64,
+}
+/// The running totals behind ExchangeCounts
+#[derive(Default)]
+pub(crate) struct ExchangeCounters{
+    sent: AtomicU64,
+    confirmed: AtomicU64,
+    unconfirmed: AtomicU64,
+    received: AtomicU64,
+    dropped: AtomicU64,
+}
+/// The receive side's view of a message whose fragments are still arriving
+/// Every value in a fragment header comes off the wire, so anything that does not fit
+/// the message announced by the exchange's first header is turned away instead of trusted
+pub struct Reassembly{
+    exchange_id: u64,
+    fragment_count: u32,
+    /// The data of every fragment that arrived, by index
+    /// Memory is only taken as data shows up, so a header announcing a huge message costs nothing
+    fragments: HashMap<u32, Vec<u8>>,
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReassemblyError{
+    /// The datagram does not start with an exchange header
+    MalformedHeader,
+    /// The fragment belongs to another exchange
+    WrongExchange(u64),
+    /// The message is announced with no fragments or more than we are willing to hold
+    BadFragmentCount(u32),
+    /// The fragment's index is past the end of the message
+    BadIndex(u32),
+    /// The fragment claims more data than it carries
+    BadLength(u32),
+    /// The datagram is a protocol header rather than message data
+    NotAFragment,
+    /// Some fragments have not arrived yet
+    Incomplete,
 }
 pub(crate) enum MessageExchangeError{
     NoConfirmation,
@@ -31,15 +83,21 @@ pub(crate) enum MessageExchangeError{
 
 #[derive(Clone)]
 pub(crate) enum MessageOp{
-    Send(SocketAddr, bool, Message),
+    Send(SocketAddr, bool, Priority, Message),
     Receive(SocketPacket),
 }
 
-const SEND_TIMEOUT_TIME:u64= 100;
-const SEND_TIMEOUT_CYCLES:usize = 10;
-const RECEIVE_TIMEOUT_TIME:u64= 16;
-const RECEIVE_TIMEOUT_CYCLES:usize = 10;
-const MESSAGE_COMPLETE_TIMEOUT:u64= 1000;
+pub(crate) const SEND_TIMEOUT_TIME:u64= 100;
+pub(crate) const SEND_TIMEOUT_CYCLES:usize = 10;
+pub(crate) const RECEIVE_TIMEOUT_TIME:u64= 16;
+pub(crate) const RECEIVE_TIMEOUT_CYCLES:usize = 10;
+pub(crate) const MESSAGE_COMPLETE_TIMEOUT:u64= 1000;
+/// The space in front of every fragment's data that holds its exchange header
+/// The header is bincode encoded, which takes fewer bytes than its in memory size
+pub(crate) const FRAGMENT_HEADER_SPACE: usize = size_of::<MessageExchangeHeader>();
+/// The most fragments a message may have, about 4MB of data
+/// Fragments only take memory as they arrive, and arrive no faster than admission lets their source send
+pub const MAX_FRAGMENT_COUNT: u32 = 1 << 12;
 
 /// The Message exchange functionality using the station analogy
 impl LocalServer{
@@ -73,6 +131,10 @@ impl LocalServer{
     /// Then it will send a message complete header to the send side and wait for awhile to make sure the send side 
     /// does not request for an update, which if it does the task will resend the message complete
     
+    /// Fragments are not written to the socket directly but queued by the priority class of the send
+    /// operation. Receive side protocol headers (retransmit requests and message completes) are always
+    /// queued as control so an exchange can finish even while bulk data is waiting
+    
     /// If reliability is not required then the send side will blast all of the fragments and then exit
     /// the recieve side will listen for new packets, but if a timeout is reached and
     /// it does not have all of the fragments then the message is dropped.
@@ -83,9 +145,10 @@ impl LocalServer{
     pub(crate) async fn exchange(server: Arc<LocalServer>, operation: MessageOp) -> Result<bool, MessageExchangeError>{
         // Operation has to cases: Send, Receive
         match operation{
-            MessageOp::Send(addr, mut nak, message) => {
+            MessageOp::Send(addr, mut nak, priority, message) => {
                 // The first thing we do in send is generate the exchange's id
                 let exchange_id = thread_rng().gen::<u64>();
+                server.exchange_counters.sent.fetch_add(1, Ordering::Relaxed);
                 // println!("Starting send request with id {}", exchange_id);
                 if addr == server.local_address(){
                     // We are sending messages over the loopback, we will dont need to nak
@@ -95,53 +158,64 @@ impl LocalServer{
                 }
                 // Then we must break our message into fragments
                 let fragements = Self::message_to_fragments(exchange_id, nak, &message);
-                // Now we just need to send all of our data
-                for fragment in fragements.iter(){
-                    server.send(addr, &fragment.1[0..fragment.0]).await;
+                // A receiver refuses anything with more fragments than it will reassemble, so dont bother sending it
+                if fragements.len() > MAX_FRAGMENT_COUNT as usize{
+                    qlog!(LogLevel::Warn, "Refusing to send a message of {} bytes in {} fragments, the most is {}", message.len(), fragements.len(), MAX_FRAGMENT_COUNT);
+                    return Err(MessageExchangeError::Failed);
                 }
-                // With all of our data in transit, we enter nak space
-                // If we dont have a requested nak we can exit here
+                // If we dont have a requested nak we can just queue all of our data and exit here
                 if !nak{
+                    for fragment in fragements.iter(){
+                        server.queue_send(priority, addr, &fragment.1[0..fragment.0]);
+                    }
                     return Ok(true); 
                 }
                 // However, if we do have a nak then we must listen for retransmit requests
                 // This firstly involves creating an exchange entry in the servers exchange map
+                // We do this before queueing any data since the queue may not be drained before the receiver responds
                 let channel = Arc::new(flume::unbounded());
                 {
                     // We use a scope here to drop the writer
                     let mut writer = server.write_exchanges().await;
                     if let Some(_) = writer.insert(exchange_id, channel.clone()){
                         // We should only ever create one entry with a given id
-                        println!("We have duplicated an exchange entry when setting up a send operation")
+                        qlog!(LogLevel::Error, "We have duplicated an exchange entry when setting up a send operation")
                     }
                 }
-                println!("Created new active exchange from id {}", exchange_id);
+                qlog!(LogLevel::Debug, "Created new active exchange from id {}", exchange_id);
+                // Now we just need to send all of our data
+                for fragment in fragements.iter(){
+                    server.queue_send(priority, addr, &fragment.1[0..fragment.0]);
+                }
                 // Now we wait for any retransmit requests
-                let mut timeout_budget = SEND_TIMEOUT_CYCLES;
+                let tuning = server.tuning();
+                let mut timeout_budget = tuning.send_timeout_cycles;
                 loop{
-                    if let Ok(packet) = timeout(Duration::from_millis(SEND_TIMEOUT_TIME), channel.1.recv_async()).await{
+                    if let Ok(packet) = timeout(Duration::from_millis(tuning.send_timeout), channel.1.recv_async()).await{
                         if let Ok(packet) = packet{
-                            if Self::retransmit_request(server.clone() ,exchange_id, &fragements, packet).await {
+                            if Self::retransmit_request(server.clone() ,exchange_id, priority, &fragements, packet).await {
                                 // Now that the exchange is complete we can remove it from existence
                                 server.remove_exchange(exchange_id).await;
+                                server.exchange_counters.confirmed.fetch_add(1, Ordering::Relaxed);
                                 return Ok(true);
                             }
                         }
                         continue;
                     }
                     
-                    timeout_budget -= 1;
+                    timeout_budget = timeout_budget.saturating_sub(1);
                     // We timeout enough times we consider the message status as unknown
                     if timeout_budget == 0{
                         // Now that the exchange is complete we can remove it from existence
                         server.remove_exchange(exchange_id).await;
+                        server.exchange_counters.unconfirmed.fetch_add(1, Ordering::Relaxed);
                         return Err(MessageExchangeError::NoConfirmation);
                     }
                     // However, we will attempt to contact the receive side and ask for an update
                     let mut header = MessageExchangeHeader::message_complete(exchange_id, nak);
                     header.fragment_count = fragements.len() as u32;
                     let Ok(header):Result<Vec<u8>, _> = bincode::serialize(&header) else {return Err(MessageExchangeError::Failed)};
-                    server.send(addr, &header).await;
+                    server.queue_send(Priority::Control, addr, &header);
                 }
             },
             MessageOp::Receive(packet) => {
@@ -165,18 +239,27 @@ impl LocalServer{
                 }
                 
                 // Since we have a header, we know the message structure which we can prepare
-                // memory for
-                let mut fragments:Vec<Option<Fragment>> = vec![None; header.fragment_count as usize];
-                let mut remaining_timeouts = RECEIVE_TIMEOUT_CYCLES;
+                // memory for, as long as the header is sane
+                let mut reassembly = match Reassembly::new(&header){
+                    Ok(reassembly) => reassembly,
+                    Err(e) => {
+                        qlog!(LogLevel::Debug, "Refused exchange {} from {}: {}", header.exchange_id, packet.1, e);
+                        server.remove_exchange(header.exchange_id).await;
+                        return Err(MessageExchangeError::Failed);
+                    },
+                };
+                let tuning = server.tuning();
+                let mut remaining_timeouts = tuning.receive_timeout_cycles;
                 
                 
                 // Now, we can begin the receive operation and begin to peice the message together
                 loop{
-                    if let Ok(packet) = timeout(Duration::from_millis(RECEIVE_TIMEOUT_TIME), channel.1.recv_async()).await{
+                    if let Ok(packet) = timeout(Duration::from_millis(tuning.receive_timeout), channel.1.recv_async()).await{
                         if let Ok(packet) = packet{
-                            if Self::receive_fragment(server.clone(), header.exchange_id, packet, &mut fragments, channel.clone()).await{
+                            if Self::receive_fragment(server.clone(), header.exchange_id, packet, &mut reassembly, channel.clone()).await{
                                 // Now that the exchange is complete we can remove it from existence
                                 server.remove_exchange(header.exchange_id).await;
+                                server.exchange_counters.received.fetch_add(1, Ordering::Relaxed);
                                 return Ok(true);
                             }
                         }
@@ -184,24 +267,25 @@ impl LocalServer{
                     }
                     // If we have nak, we need to request retransmits
                     if header.nak{
-                        for request in Self::prepare_retransmits(header.exchange_id, &fragments).iter(){
-                            server.send(packet.1, request.as_slice()).await;
+                        for request in Self::prepare_retransmits(header.exchange_id, &reassembly).iter(){
+                            server.queue_send(Priority::Control, packet.1, request.as_slice());
                         }
                     }
                     
                     // If we timeout too many times then we drop the message
-                    remaining_timeouts -= 1;
-                    println!("Receive timeout budget {}", remaining_timeouts);
-                    if remaining_timeouts <= 0{
+                    remaining_timeouts = remaining_timeouts.saturating_sub(1);
+                    qlog!(LogLevel::Debug, "Receive timeout budget {}", remaining_timeouts);
+                    if remaining_timeouts == 0{
                         // Now that the exchange is complete we can remove it from existence
                         server.remove_exchange(header.exchange_id).await;
+                        server.exchange_counters.dropped.fetch_add(1, Ordering::Relaxed);
                         return Err(MessageExchangeError::Failed);
                     }
                 }
             },
         }
     }
-    async fn receive_fragment(server: Arc<LocalServer>, exchange_id: u64, packet: SocketPacket, fragments: &mut [Option<Fragment>], channel: Arc<(flume::Sender<SocketPacket>, flume::Receiver<SocketPacket>)>) -> bool{
+    async fn receive_fragment(server: Arc<LocalServer>, exchange_id: u64, packet: SocketPacket, reassembly: &mut Reassembly, channel: Arc<(flume::Sender<SocketPacket>, flume::Receiver<SocketPacket>)>) -> bool{
         // The receive case can get two message types: A fragment or an update request
         // A fragment is the send case sending the message data
         // An update request is the send case asking what the current state of the receive case is
@@ -212,31 +296,31 @@ impl LocalServer{
         let Ok(header): Result<MessageExchangeHeader, _> = bincode::deserialize(&packet.2) else { return false};
         // We need to see what type of message this is
         if header.message_complete{
-            println!("Receive side for exchange {} asked for state update", exchange_id);
+            qlog!(LogLevel::Debug, "Receive side for exchange {} asked for state update", exchange_id);
             // Remember, if the send side sends a message_complete then it is asking for a state update
             // So we send any retransmits we have
-            for request in Self::prepare_retransmits(exchange_id, fragments).iter(){
-                server.send(packet.1, request.as_slice()).await;
+            for request in Self::prepare_retransmits(exchange_id, reassembly).iter(){
+                server.queue_send(Priority::Control, packet.1, request.as_slice());
             }
             return false;
         }
         
         // If this is not a state update than this is a new fragement
         // If we get a duplicate fragment then we just overwrite what we already have 
-        let index = header.fragment_index;
         // println!("Receive side for exchange {} got fragment {} of {}", exchange_id, index + 1, header.fragment_count);
-        
-        fragments[index as usize] = Some((header.fragment_data as usize, packet.2));
+        if let Err(e) = reassembly.insert(&packet.2[..packet.0]){
+            qlog!(LogLevel::Debug, "Receive side for exchange {} dropped a fragment from {}: {}", exchange_id, packet.1, e);
+            return false;
+        }
         // Now that we have gotten a new fragment we should check to see if we need to
         // enter the message complete stage of the receive case
         // In this stage we will package the message and send it off
         // as well as notifiy the send case of completion and wait for any update requests it might send
         
-        let requests = Self::prepare_retransmits(exchange_id, fragments);
-        if requests.len() == 0 {
+        if reassembly.is_complete() {
             // We have the complete message
             // This means we can peice the message together
-            if let Ok(message) = Self::fragments_to_message(fragments){
+            if let Ok(message) = reassembly.assemble(){
                 // println!("Exchange {} assembled", exchange_id);
                 // And send it off
                 tokio::spawn(station::route_message(server.clone(), packet.1, message));
@@ -245,10 +329,10 @@ impl LocalServer{
                 // If we have nak of course
                 if header.nak{
                     loop{
-                        match timeout(Duration::from_millis(MESSAGE_COMPLETE_TIMEOUT), channel.1.recv_async()).await{
+                        match timeout(Duration::from_millis(server.tuning().message_complete_timeout), channel.1.recv_async()).await{
                             Ok(_) => {
                                 let Ok(header): Result<Vec<u8>, _> = bincode::serialize(&MessageExchangeHeader::message_complete(exchange_id, true)) else { return true};
-                                server.send(packet.1, &header).await;
+                                server.queue_send(Priority::Control, packet.1, &header);
                             },
                             Err(_) => {
                                 // If we have waited long enough we will assume that the send case has closed
@@ -267,48 +351,32 @@ impl LocalServer{
         }
         return false;
     }
-    fn fragments_to_message(fragments: &[Option<Fragment>]) -> Result<Vec<u8>, MessageExchangeError>{
-        let mut message = Vec::with_capacity(fragments.len() * MAX_MESSAGE_LENGTH);
-        for fragment in fragments{
-            if let Some((len, data)) = fragment{
-                // Note we dont look at the beginning of the data because the exchange header is still in there
-                message.extend_from_slice(&data[size_of::<MessageExchangeHeader>()..len + size_of::<MessageExchangeHeader>()]);
-            }
-            else{
-                // This is a critical flaw in the program design
-                return Err(MessageExchangeError::Failed);
-            }
-        }
-        Ok(message)
-    }
-    
-    fn prepare_retransmits(message_id: u64, fragments: &[Option<Fragment>]) -> Vec<Vec<u8>> {
-            let mut headers = Vec::with_capacity(fragments.len());
-            for (index, fragment) in fragments.iter().enumerate(){
-                if let None = fragment{
-                    let header = MessageExchangeHeader{ 
-                        exchange_id: message_id,
-                        fragment_count: 0,
-                        fragment_index: index as u32,
-                        fragment_data: 0,
-                        nak: true,
-                        message_complete: false };
-                    let header:Vec<u8> = bincode::serialize(&header).unwrap();
-                    headers.push(header);
-                }
+    fn prepare_retransmits(message_id: u64, reassembly: &Reassembly) -> Vec<Vec<u8>> {
+            let missing = reassembly.missing();
+            let mut headers = Vec::with_capacity(missing.len());
+            for index in missing{
+                let header = MessageExchangeHeader{ 
+                    exchange_id: message_id,
+                    fragment_count: 0,
+                    fragment_index: index,
+                    fragment_data: 0,
+                    nak: true,
+                    message_complete: false };
+                let header:Vec<u8> = bincode::serialize(&header).unwrap();
+                headers.push(header);
             }
             headers
         } 
      /// This function works on the send case and will process any message the send case receives
     /// It returns a bool which signifies if the send case can shutdown
-    async fn retransmit_request(server: Arc<LocalServer>, exchange_id: u64, fragments: &Vec<Fragment>, packet: SocketPacket) -> bool{
+    async fn retransmit_request(server: Arc<LocalServer>, exchange_id: u64, priority: Priority, fragments: &Vec<Fragment>, packet: SocketPacket) -> bool{
         // The send case can get either a retransmit request or a message complete
         // message
         // The former specifies what fragment to resend, the latter is technically optional
         // and lets the send side know it can close
         let Ok(header): Result<MessageExchangeHeader, _> = bincode::deserialize(&packet.2) else { return false};
         if header.exchange_id != exchange_id{
-            println!("Message from exchange {} landed in exchange {}", header.exchange_id, exchange_id);
+            qlog!(LogLevel::Warn, "Message from exchange {} landed in exchange {}", header.exchange_id, exchange_id);
             return false;
         }
         // If the message complete flag is on in the send case channel that idicated the receive side has
@@ -319,14 +387,17 @@ impl LocalServer{
         
         // If not, then this is a retransmit request and we must send the requested fragment
         // Not the receive side will send back the index it needs
-        let requested_fragment = &fragments[header.fragment_index as usize];
+        let Some(requested_fragment) = fragments.get(header.fragment_index as usize) else {
+            qlog!(LogLevel::Debug, "Exchange {} got a retransmit request for fragment {} of {}", exchange_id, header.fragment_index, fragments.len());
+            return false;
+        };
         let requested_data = &requested_fragment.1[0..requested_fragment.0];
-        server.send(packet.1, requested_data).await;
+        server.queue_send(priority, packet.1, requested_data);
         
         return false;
     }
-    fn message_to_fragments(exchange_id: u64, nak:bool, message: &Message) -> Vec<Fragment> {
-        let data_size = MAX_MESSAGE_LENGTH - size_of::<MessageExchangeHeader>();
+    fn message_to_fragments(exchange_id: u64, nak:bool, message: &[u8]) -> Vec<Fragment> {
+        let data_size = MAX_MESSAGE_LENGTH - FRAGMENT_HEADER_SPACE;
         let mut fragments:Vec<Fragment> = Vec::with_capacity(message.len()/data_size + 1);
         let chunks = message.chunks(data_size);
         let total_chunks = chunks.len() as u32;
@@ -340,10 +411,10 @@ impl LocalServer{
                 nak,
                 message_complete: false, };
             
-            let mut fragment = (size_of::<MessageExchangeHeader>() + chunk.len(), [0; MAX_MESSAGE_LENGTH]);
-            let header_space = &mut fragment.1[0..size_of::<MessageExchangeHeader>()];
-            let _ = bincode::serialize_into(header_space, &header);
-            let data_space = &mut fragment.1[size_of::<MessageExchangeHeader>()..];
+            let mut fragment = (FRAGMENT_HEADER_SPACE + chunk.len(), [0; MAX_MESSAGE_LENGTH]);
+            let header_space = &mut fragment.1[0..FRAGMENT_HEADER_SPACE];
+            bincode::serialize_into(header_space, &header).expect("The exchange header outgrew its space");
+            let data_space = &mut fragment.1[FRAGMENT_HEADER_SPACE..];
             for (index, byte) in chunk.iter().enumerate(){
                 data_space[index] = *byte;
             }
@@ -359,6 +430,82 @@ impl LocalServer{
 
 
 
+/// Splits a message into the datagrams a send puts on the wire
+/// An empty message has no fragments
+pub fn fragment_message(exchange_id: u64, nak: bool, message: &[u8]) -> Vec<Vec<u8>> {
+    LocalServer::message_to_fragments(exchange_id, nak, message).iter().map(|(len, data)| data[..*len].to_vec()).collect()
+}
+
+impl Reassembly{
+    /// Prepares for the message announced by the first header of an exchange
+    /// The header can be a fragment or a sender asking for an update, both carry the fragment count
+    pub fn new(header: &MessageExchangeHeader) -> Result<Reassembly, ReassemblyError> {
+        if header.fragment_count == 0 || header.fragment_count > MAX_FRAGMENT_COUNT{
+            return Err(ReassemblyError::BadFragmentCount(header.fragment_count));
+        }
+        Ok(Reassembly{ exchange_id: header.exchange_id, fragment_count: header.fragment_count, fragments: HashMap::new() })
+    }
+    /// Takes in a fragment datagram, a duplicate overwrites the copy we already have
+    pub fn insert(&mut self, datagram: &[u8]) -> Result<(), ReassemblyError> {
+        let header: MessageExchangeHeader = bincode::deserialize(datagram).map_err(|_| ReassemblyError::MalformedHeader)?;
+        if header.exchange_id != self.exchange_id{
+            return Err(ReassemblyError::WrongExchange(header.exchange_id));
+        }
+        if header.message_complete{
+            return Err(ReassemblyError::NotAFragment);
+        }
+        if header.fragment_count != self.fragment_count{
+            return Err(ReassemblyError::BadFragmentCount(header.fragment_count));
+        }
+        if header.fragment_index >= self.fragment_count{
+            return Err(ReassemblyError::BadIndex(header.fragment_index));
+        }
+        // The data has to be inside both the datagram and a fragment, whatever trails it is ignored
+        let end = match FRAGMENT_HEADER_SPACE.checked_add(header.fragment_data as usize){
+            Some(end) if end <= datagram.len() && end <= MAX_MESSAGE_LENGTH => end,
+            _ => return Err(ReassemblyError::BadLength(header.fragment_data)),
+        };
+        self.fragments.insert(header.fragment_index, datagram[FRAGMENT_HEADER_SPACE..end].to_vec());
+        Ok(())
+    }
+    /// The indices of the fragments that have not arrived
+    pub fn missing(&self) -> Vec<u32> {
+        (0..self.fragment_count).filter(|i| !self.fragments.contains_key(i)).collect()
+    }
+    pub fn is_complete(&self) -> bool {
+        self.fragments.len() == self.fragment_count as usize
+    }
+    pub fn fragment_count(&self) -> u32 {
+        self.fragment_count
+    }
+    /// Puts the message back together
+    pub fn assemble(&self) -> Result<Vec<u8>, ReassemblyError> {
+        if !self.is_complete(){
+            return Err(ReassemblyError::Incomplete);
+        }
+        // Only what actually arrived is reserved, never what the header claimed
+        let mut message = Vec::with_capacity(self.fragments.values().map(|data| data.len()).sum());
+        for index in 0..self.fragment_count{
+            message.extend_from_slice(&self.fragments[&index]);
+        }
+        Ok(message)
+    }
+}
+
+impl std::fmt::Display for ReassemblyError{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self{
+            ReassemblyError::MalformedHeader => write!(f, "The datagram has no exchange header"),
+            ReassemblyError::WrongExchange(id) => write!(f, "The fragment belongs to exchange {}", id),
+            ReassemblyError::BadFragmentCount(count) => write!(f, "A message of {} fragments is not accepted", count),
+            ReassemblyError::BadIndex(index) => write!(f, "Fragment index {} is out of range", index),
+            ReassemblyError::BadLength(len) => write!(f, "The fragment does not carry the {} bytes it claims", len),
+            ReassemblyError::NotAFragment => write!(f, "The datagram is a protocol header"),
+            ReassemblyError::Incomplete => write!(f, "The message is missing fragments"),
+        }
+    }
+}
+
 impl MessageExchangeHeader{
     fn message_complete(message_id: u64, nak: bool) -> MessageExchangeHeader {
         MessageExchangeHeader{ 
@@ -373,6 +520,15 @@ impl MessageExchangeHeader{
     
  
 impl LocalServer{
+    pub async fn exchange_counts(&self) -> ExchangeCounts {
+        ExchangeCounts{
+            live: self.read_exchanges().await.len() as u64,
+            sent: self.exchange_counters.sent.load(Ordering::Relaxed),
+            confirmed: self.exchange_counters.confirmed.load(Ordering::Relaxed),
+            unconfirmed: self.exchange_counters.unconfirmed.load(Ordering::Relaxed),
+            received: self.exchange_counters.received.load(Ordering::Relaxed),
+            dropped: self.exchange_counters.dropped.load(Ordering::Relaxed) }
+    }
     async fn add_unique_exchange(&self, exchange_id: u64) -> Arc<(flume::Sender<SocketPacket>, flume::Receiver<SocketPacket>)> {
         let mut exchanges = self.write_exchanges().await;
         let channel = Arc::new(flume::unbounded());
@@ -410,7 +566,7 @@ impl LocalServer{
         let message_channel = Arc::new(flume::unbounded());
         
         if let Some(_) = writer.insert(exchange_id, message_channel.clone()){
-            println!("Adding pre-exisiting message_id");
+            qlog!(LogLevel::Debug, "Adding pre-exisiting message_id");
         }
         
         (true, message_channel)
@@ -0,0 +1,321 @@
+use std::{fmt, collections::{HashMap, HashSet, VecDeque}};
+use serde::{Serialize, Deserialize};
+use tokio::time::{Duration, Instant, timeout};
+
+use crate::{Station, StationOperable, ReplicatedStore, ReplicaMessage, station::StationId};
+use crate::{qlog, logging::LogLevel};
+
+/// How long an owner waits for the new owner to accept a handoff
+const HANDOFF_TIMEOUT: u64 = 2000;
+/// How many times a commit or abort is sent before the new owner counts as unreachable
+const HANDOFF_RETRIES: usize = 3;
+/// A request that has been forwarded this many times is dropped, the index is still converging
+const MAX_REQUEST_HOPS: u32 = 8;
+
+pub type ShardId = u64;
+
+/// The shard a sequential piece of data such as a planetary system index falls in
+pub fn shard_of(index: u64, shard_size: u64) -> ShardId {
+    index / shard_size.max(1)
+}
+
+/// The entry of a shard in the cluster index
+/// The epoch increases with every handoff so stale owners can be told apart
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShardRecord{
+    pub owner: StationId,
+    pub epoch: u64,
+}
+
+/// The messages owners exchange
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum OwnershipMessage{
+    /// Phase one of a handoff: the current owner offers the shard and its state
+    Prepare{shard: ShardId, epoch: u64, state: Vec<u8>},
+    /// The new owner holds the state and is ready to take over
+    Prepared{shard: ShardId, epoch: u64},
+    /// Phase two: the index has been updated and the new owner serves the shard from now on
+    Commit{shard: ShardId, epoch: u64},
+    /// The handoff was given up, the new owner drops the state
+    Abort{shard: ShardId, epoch: u64},
+    /// A request for whoever owns the shard
+    Request{shard: ShardId, origin: StationId, hops: u32, payload: Vec<u8>},
+}
+
+/// A request delivered to the owner of its shard
+#[derive(Clone, Debug)]
+pub struct ShardRequest{
+    pub shard: ShardId,
+    /// The ownership station that first sent the request
+    pub origin: StationId,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum OwnershipError{
+    /// Someone else already owns the shard
+    Owned{shard: ShardId, owner: StationId},
+    /// Nobody owns the shard
+    Unowned{shard: ShardId},
+    /// We do not own the shard we tried to hand off
+    NotOwner{shard: ShardId},
+    /// The new owner never accepted the handoff
+    HandoffFailed{shard: ShardId, to: StationId},
+}
+
+/// An outgoing handoff, the shard is frozen until it completes
+struct Handoff{
+    to: StationId,
+    epoch: u64,
+    prepared: bool,
+    /// Requests that arrived while frozen, forwarded to the new owner on commit
+    queued: Vec<ShardRequest>,
+}
+
+/// This node's view of, and part in, shard ownership
+/// Every node keeps the cluster index of which ownership station owns which shard. Requests
+/// are sent to whoever the index names, and forwarded again by any node that no longer owns the
+/// shard, so moving a shard is invisible to the nodes sending requests for it
+pub struct ShardOwnership{
+    index: ReplicatedStore<ShardRecord>,
+    station: Station<OwnershipMessage>,
+    /// Shards handed to us whose index update we have not seen yet
+    adopted: HashMap<ShardId, u64>,
+    /// State offered to us by a prepared handoff
+    incoming: HashMap<ShardId, (u64, Vec<u8>)>,
+    /// State of committed handoffs waiting for the application to take it
+    received: HashMap<ShardId, Vec<u8>>,
+    outgoing: HashMap<ShardId, Handoff>,
+    requests: VecDeque<ShardRequest>,
+}
+
+impl ShardOwnership{
+    /// `index` and `station` should be on channels every node uses for this ownership domain
+    pub fn new(index: Station<ReplicaMessage>, station: Station<OwnershipMessage>) -> ShardOwnership {
+        ShardOwnership{
+            index: ReplicatedStore::new(index),
+            station,
+            adopted: HashMap::new(),
+            incoming: HashMap::new(),
+            received: HashMap::new(),
+            outgoing: HashMap::new(),
+            requests: VecDeque::new() }
+    }
+    pub fn id(&self) -> StationId {
+        self.station.id()
+    }
+    pub async fn record(&mut self, shard: ShardId) -> Option<ShardRecord> {
+        let mut record = self.index.get(&Self::key(shard)).await;
+        // A handoff we got the commit for is newer than an index that has not caught up
+        if let Some(epoch) = self.adopted.get(&shard).copied(){
+            match record{
+                Some(r) if r.epoch >= epoch => {self.adopted.remove(&shard);},
+                _ => record = Some(ShardRecord{ owner: self.id(), epoch }),
+            }
+        }
+        record
+    }
+    pub async fn owner(&mut self, shard: ShardId) -> Option<StationId> {
+        self.record(shard).await.map(|r| r.owner)
+    }
+    /// Every shard this node owns right now
+    pub async fn owned(&mut self) -> Vec<ShardId> {
+        let mut owned: HashSet<ShardId> = HashSet::new();
+        for key in self.index.keys().await{
+            let Some(shard) = Self::shard(&key) else {continue};
+            if self.owner(shard).await == Some(self.id()){
+                owned.insert(shard);
+            }
+        }
+        owned.extend(self.adopted.keys());
+        owned.into_iter().collect()
+    }
+    /// Takes ownership of a shard nobody owns
+    pub async fn claim(&mut self, shard: ShardId) -> Result<ShardRecord, OwnershipError>{
+        if let Some(record) = self.record(shard).await{
+            if record.owner != self.id(){
+                return Err(OwnershipError::Owned{ shard, owner: record.owner });
+            }
+            return Ok(record);
+        }
+        let record = ShardRecord{ owner: self.id(), epoch: 1 };
+        self.index.put(&Self::key(shard), &record).await;
+        Ok(record)
+    }
+    /// Sends a request to whoever owns `shard`, which may be us
+    pub async fn request(&mut self, shard: ShardId, payload: Vec<u8>) -> Result<(), OwnershipError>{
+        let request = ShardRequest{ shard, origin: self.id(), payload };
+        self.route(request, 0).await
+    }
+    /// The next request for one of our shards
+    pub async fn next_request(&mut self) -> Option<ShardRequest> {
+        self.process().await;
+        self.requests.pop_front()
+    }
+    /// Waits until a request for one of our shards arrives
+    pub async fn listen(&mut self) -> ShardRequest {
+        loop{
+            if let Some(request) = self.requests.pop_front(){
+                return request;
+            }
+            // The index has to stay live too, so we never wait long on just our own station
+            if let Ok(Some((_, from, message))) = timeout(Duration::from_millis(50), self.station.listen()).await{
+                self.handle(from, message).await;
+            }
+            self.index.process().await;
+        }
+    }
+    /// The state handed to us with a shard, once
+    pub fn take_state(&mut self, shard: ShardId) -> Option<Vec<u8>> {
+        self.received.remove(&shard)
+    }
+    /// Moves one of our shards to the ownership station `to` with a two phase handoff
+    /// The shard is frozen while the handoff runs, requests for it are queued and follow it to the new owner
+    pub async fn hand_off(&mut self, shard: ShardId, to: StationId, state: Vec<u8>) -> Result<(), OwnershipError>{
+        let Some(record) = self.record(shard).await else {return Err(OwnershipError::Unowned{ shard })};
+        if record.owner != self.id(){
+            return Err(OwnershipError::NotOwner{ shard });
+        }
+        let epoch = record.epoch + 1;
+        self.outgoing.insert(shard, Handoff{ to, epoch, prepared: false, queued: vec![] });
+
+        // Phase one, we offer the shard and wait for the new owner to hold its state
+        let _ = self.station.send(to, true, &OwnershipMessage::Prepare{ shard, epoch, state }).await;
+        let deadline = Instant::now() + Duration::from_millis(HANDOFF_TIMEOUT);
+        while !self.outgoing.get(&shard).map_or(false, |h| h.prepared){
+            let Some(remaining) = deadline.checked_duration_since(Instant::now()) else {break};
+            if let Ok(Some((_, from, message))) = timeout(remaining, self.station.listen()).await{
+                self.handle(from, message).await;
+            }
+        }
+        let handoff = self.outgoing.remove(&shard).unwrap();
+        if !handoff.prepared{
+            let _ = self.station.send(to, true, &OwnershipMessage::Abort{ shard, epoch }).await;
+            self.requests.extend(handoff.queued);
+            return Err(OwnershipError::HandoffFailed{ shard, to });
+        }
+
+        // Phase two, we only give the shard up once the new owner has the commit
+        if !self.send_reliable(to, &OwnershipMessage::Commit{ shard, epoch }).await{
+            // The commit may still have arrived with its ack lost, the abort undoes it
+            qlog!(LogLevel::Warn, "Station {} never confirmed the commit of shard {}, keeping it", to, shard);
+            self.send_reliable(to, &OwnershipMessage::Abort{ shard, epoch }).await;
+            self.requests.extend(handoff.queued);
+            return Err(OwnershipError::HandoffFailed{ shard, to });
+        }
+        self.index.put(&Self::key(shard), &ShardRecord{ owner: to, epoch }).await;
+        self.adopted.remove(&shard);
+        qlog!(LogLevel::Info, "Station {} handed shard {} to {} at epoch {}", self.id(), shard, to, epoch);
+        for request in handoff.queued{
+            let _ = self.route(request, 1).await;
+        }
+        Ok(())
+    }
+    /// Applies everything that has arrived on the index and ownership stations
+    pub async fn process(&mut self){
+        self.index.process().await;
+        for (_, from, message) in self.station.receive_all().await{
+            self.handle(from, message).await;
+        }
+    }
+
+    async fn handle(&mut self, from: StationId, message: OwnershipMessage){
+        match message{
+            OwnershipMessage::Prepare { shard, epoch, state } => {
+                self.incoming.insert(shard, (epoch, state));
+                let _ = self.station.send(from, true, &OwnershipMessage::Prepared{ shard, epoch }).await;
+            },
+            OwnershipMessage::Prepared { shard, epoch } => {
+                if let Some(handoff) = self.outgoing.get_mut(&shard){
+                    if handoff.epoch == epoch && handoff.to == from{
+                        handoff.prepared = true;
+                    }
+                }
+            },
+            OwnershipMessage::Commit { shard, epoch } => {
+                if let Some((prepared, state)) = self.incoming.remove(&shard){
+                    if prepared == epoch{
+                        qlog!(LogLevel::Info, "Station {} took over shard {} at epoch {}", self.id(), shard, epoch);
+                        self.adopted.insert(shard, epoch);
+                        self.received.insert(shard, state);
+                    }
+                }
+            },
+            OwnershipMessage::Abort { shard, epoch } => {
+                if self.incoming.get(&shard).map_or(false, |i| i.0 == epoch){
+                    self.incoming.remove(&shard);
+                }
+                // The old owner gave up after we got the commit, so the shard stays with it
+                if self.adopted.get(&shard) == Some(&epoch){
+                    qlog!(LogLevel::Info, "Station {} gave shard {} back after an aborted commit", self.id(), shard);
+                    self.adopted.remove(&shard);
+                    self.received.remove(&shard);
+                }
+            },
+            OwnershipMessage::Request { shard, origin, hops, payload } => {
+                let _ = self.route(ShardRequest{ shard, origin, payload }, hops).await;
+            },
+        }
+    }
+    /// Sends with a few retries, true once `to` has acked the message
+    async fn send_reliable(&mut self, to: StationId, message: &OwnershipMessage) -> bool {
+        for _ in 0..HANDOFF_RETRIES{
+            if self.station.send(to, true, message).await.is_ok(){
+                return true;
+            }
+        }
+        false
+    }
+    /// Delivers a request locally if we own its shard, otherwise forwards it to the owner
+    async fn route(&mut self, request: ShardRequest, hops: u32) -> Result<(), OwnershipError>{
+        if let Some(handoff) = self.outgoing.get_mut(&request.shard){
+            handoff.queued.push(request);
+            return Ok(());
+        }
+        let shard = request.shard;
+        let Some(owner) = self.owner(shard).await else {return Err(OwnershipError::Unowned{ shard })};
+        if owner == self.id(){
+            self.requests.push_back(request);
+            return Ok(());
+        }
+        if hops >= MAX_REQUEST_HOPS{
+            qlog!(LogLevel::Warn, "Station {} dropped a request for shard {} after {} hops", self.id(), shard, hops);
+            return Err(OwnershipError::Unowned{ shard });
+        }
+        let message = OwnershipMessage::Request{ shard, origin: request.origin, hops: hops + 1, payload: request.payload };
+        let _ = self.station.send(owner, true, &message).await;
+        Ok(())
+    }
+    fn key(shard: ShardId) -> String {
+        format!("shard/{}", shard)
+    }
+    fn shard(key: &str) -> Option<ShardId> {
+        key.strip_prefix("shard/")?.parse().ok()
+    }
+}
+
+impl fmt::Display for OwnershipError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            OwnershipError::Owned { shard, owner } => write!(f, "Shard {} is owned by {}", shard, owner),
+            OwnershipError::Unowned { shard } => write!(f, "Shard {} has no owner", shard),
+            OwnershipError::NotOwner { shard } => write!(f, "We do not own shard {}", shard),
+            OwnershipError::HandoffFailed { shard, to } => write!(f, "Station {} never accepted shard {}", to, shard),
+        }
+    }
+}
+
+impl StationOperable for OwnershipMessage{
+    fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        bincode::deserialize(bytes).unwrap()
+    }
+
+    /// Any station can send us anything, so what does not decode is dropped rather than unwrapped
+    fn try_from_bytes(bytes: &[u8]) -> Option<Self> {
+        bincode::deserialize(bytes).ok()
+    }
+}
@@ -0,0 +1,121 @@
+use std::{fmt, net::SocketAddr, sync::Arc};
+use serde::{Serialize, Deserialize};
+
+use crate::LocalServer;
+use crate::{qlog, logging::LogLevel};
+
+/// The version of the wire protocol this build speaks
+/// Must be increased whenever the layout of MessageExchangeHeader, StationHeader
+/// or ServerInternalComm changes
+/// 3 put the version in front of every ServerInternalComm
+pub const PROTOCOL_VERSION: u16 = 3;
+/// The oldest protocol version this build can still parse
+/// Messages before 3 had no version prefix, so there is no telling what they are
+pub const MIN_COMPATIBLE_VERSION: u16 = 3;
+
+/// A bitmap of optional protocol features
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities{
+    pub const NONE: Capabilities = Capabilities(0);
+    /// Station headers may carry a trace context
+    pub const TRACING: Capabilities = Capabilities(1 << 0);
+
+    /// Everything this build supports
+    pub fn supported() -> Capabilities {
+        Capabilities::TRACING
+    }
+    pub fn contains(&self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+    /// The features both sides support, which are the only ones that may be used between them
+    pub fn negotiate(&self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+/// Sent in the initial Ping, and the Welcome or Reject that answers it
+/// A server that can not parse the handshake still learns the sender's version from the
+/// fixed prefix every ServerInternalComm starts with
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Handshake{
+    pub version: u16,
+    pub capabilities: Capabilities,
+    pub discoverable: bool,
+}
+
+/// What we know about another server after its handshake
+#[derive(Clone, Copy, Debug)]
+pub struct PeerInfo{
+    pub discoverable: bool,
+    pub version: u16,
+    /// The negotiated set, not everything the peer supports
+    pub capabilities: Capabilities,
+}
+
+#[derive(Debug)]
+pub enum HandshakeError{
+    IncompatibleVersion{ours: u16, theirs: u16},
+}
+
+impl Handshake{
+    /// The handshake describing this build
+    pub fn local(discoverable: bool) -> Handshake {
+        Handshake{
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::supported(),
+            discoverable }
+    }
+    /// Can we talk to the server that sent this handshake
+    pub fn check(&self) -> Result<PeerInfo, HandshakeError> {
+        if self.version < MIN_COMPATIBLE_VERSION || self.version > PROTOCOL_VERSION{
+            return Err(HandshakeError::IncompatibleVersion{ ours: PROTOCOL_VERSION, theirs: self.version });
+        }
+        Ok(PeerInfo{
+            discoverable: self.discoverable,
+            version: self.version,
+            capabilities: Capabilities::supported().negotiate(self.capabilities) })
+    }
+}
+
+impl fmt::Display for HandshakeError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            HandshakeError::IncompatibleVersion { ours, theirs } => write!(f,
+                "incompatible protocol version {} (we speak {} and accept {} to {})", theirs, ours, MIN_COMPATIBLE_VERSION, PROTOCOL_VERSION),
+        }
+    }
+}
+
+/// Peer table functionality
+impl LocalServer{
+    /// The negotiated protocol state of a peer, None if it has not completed a handshake
+    pub async fn peer_info(&self, addr: SocketAddr) -> Option<PeerInfo> {
+        self.read_peers().await.get(&addr).copied()
+    }
+    /// Every server that completed a handshake with us
+    pub async fn peer_addresses(&self) -> Vec<SocketAddr> {
+        self.read_peers().await.keys().copied().collect()
+    }
+    /// Every server we are running a keep alive for, handshake or not
+    pub async fn known_servers(&self) -> Vec<SocketAddr> {
+        self.read_servers().await.keys().copied().collect()
+    }
+    /// Records a peer's handshake, returning an error for peers we cannot talk to
+    pub(crate) async fn add_peer(server: Arc<LocalServer>, addr: SocketAddr, handshake: &Handshake) -> Result<PeerInfo, HandshakeError> {
+        let info = match handshake.check(){
+            Ok(info) => info,
+            Err(e) => {
+                qlog!(LogLevel::Warn, "Server {} refused peer {}: {}", server.local_address(), addr, e);
+                server.write_peers().await.remove(&addr);
+                return Err(e);
+            },
+        };
+        if let Some(_) = server.write_peers().await.insert(addr, info){
+            return Ok(info);
+        }
+        qlog!(LogLevel::Info, "Server {} added peer {} speaking version {} with capabilities {:#x}", server.local_address(), addr, info.version, info.capabilities.0);
+        Ok(info)
+    }
+}
@@ -0,0 +1,271 @@
+use std::{collections::{HashMap, HashSet}, marker::PhantomData};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+
+use crate::{Station, StationOperable, station::StationId};
+use crate::{qlog, logging::LogLevel};
+
+/// Orders every write in the cluster
+/// Writes are compared by their lamport counter first and the writing station second,
+/// so every replica picks the same winner without coordinating
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Version{
+    pub counter: u64,
+    pub writer: StationId,
+}
+
+/// A value and the version that wrote it
+/// A None value is a delete, which has to be replicated like any other write
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Entry{
+    pub value: Option<Vec<u8>>,
+    pub version: Version,
+}
+
+/// The messages replicas exchange
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ReplicaMessage{
+    /// A write that should be applied by every replica
+    Update{key: String, entry: Entry},
+    /// The first step of anti-entropy: the versions of everything the sender holds
+    Sync{digest: Vec<(String, Version)>},
+    /// Answers a sync with the entries the syncing replica lacks, and the keys the answering replica lacks
+    Entries{entries: Vec<(String, Entry)>, wanted: Vec<String>},
+}
+
+/// Sent to watchers whenever a key changes, locally or by replication
+#[derive(Clone, Debug)]
+pub struct WatchEvent<V>{
+    pub key: String,
+    /// None when the key was deleted
+    pub value: Option<V>,
+    pub version: Version,
+}
+
+/// A key-value map replicated between every ReplicatedStore on the same station channel
+/// Conflicting writes are resolved last writer wins, and a replica that discovers a new
+/// peer runs anti-entropy with it so joining nodes catch up on everything written before
+pub struct ReplicatedStore<V: Serialize + DeserializeOwned + Clone>{
+    station: Station<ReplicaMessage>,
+    entries: HashMap<String, Entry>,
+    /// The lamport clock of this replica
+    clock: u64,
+    /// The peers we have already run anti-entropy with
+    synced: HashSet<StationId>,
+    watchers: Vec<(String, flume::Sender<WatchEvent<V>>)>,
+    value: PhantomData<V>,
+}
+
+impl Entry{
+    /// Does this entry win against what a replica holds for its key
+    /// Only a strictly newer version does, so applying the same write twice changes nothing
+    pub fn supersedes(&self, current: Option<&Entry>) -> bool {
+        current.map_or(true, |current| self.version > current.version)
+    }
+}
+
+impl<V: Serialize + DeserializeOwned + Clone> ReplicatedStore<V>{
+    pub fn new(station: Station<ReplicaMessage>) -> ReplicatedStore<V> {
+        ReplicatedStore{
+            station,
+            entries: HashMap::new(),
+            clock: 0,
+            synced: HashSet::new(),
+            watchers: vec![],
+            value: PhantomData }
+    }
+    pub fn station(&mut self) -> &mut Station<ReplicaMessage> {
+        &mut self.station
+    }
+    pub async fn get(&mut self, key: &str) -> Option<V> {
+        self.get_versioned(key).await.map(|(value, _)| value)
+    }
+    pub async fn get_versioned(&mut self, key: &str) -> Option<(V, Version)> {
+        self.process().await;
+        let entry = self.entries.get(key)?;
+        let value = bincode::deserialize(entry.value.as_ref()?).ok()?;
+        Some((value, entry.version))
+    }
+    /// Every live key
+    pub async fn keys(&mut self) -> Vec<String> {
+        self.process().await;
+        self.entries.iter().filter(|e| e.1.value.is_some()).map(|e| e.0.clone()).collect()
+    }
+    pub async fn put(&mut self, key: &str, value: &V) -> Version {
+        self.write(key, Some(bincode::serialize(value).unwrap())).await
+    }
+    pub async fn delete(&mut self, key: &str) -> Version {
+        self.write(key, None).await
+    }
+    /// Every change to a key starting with `prefix` will be sent on the returned channel
+    /// An empty prefix watches the whole store
+    pub fn watch(&mut self, prefix: &str) -> flume::Receiver<WatchEvent<V>> {
+        let (tx, rx) = flume::unbounded();
+        self.watchers.push((prefix.to_string(), tx));
+        rx
+    }
+    /// Applies everything that has arrived and syncs with any peer we have not synced with yet
+    pub async fn process(&mut self){
+        for (_, from, message) in self.station.receive_all().await{
+            self.handle(from, message).await;
+        }
+        self.sync_new_peers().await;
+    }
+    /// Waits for the next replica message and applies it
+    pub async fn listen(&mut self){
+        if let Some((_, from, message)) = self.station.listen().await{
+            self.handle(from, message).await;
+        }
+        self.sync_new_peers().await;
+    }
+
+    async fn write(&mut self, key: &str, value: Option<Vec<u8>>) -> Version {
+        // We make sure we have seen every write we can before picking our version
+        self.process().await;
+        self.clock += 1;
+        let version = Version{ counter: self.clock, writer: self.station.id() };
+        let entry = Entry{ value, version };
+        self.apply(key.to_string(), entry.clone());
+        let update = ReplicaMessage::Update{ key: key.to_string(), entry };
+        for peer in self.station.known_stations(){
+            let _ = self.station.send(peer, true, &update).await;
+        }
+        version
+    }
+    async fn handle(&mut self, from: StationId, message: ReplicaMessage){
+        match message{
+            ReplicaMessage::Update { key, entry } => {
+                self.apply(key, entry);
+            },
+            ReplicaMessage::Sync { digest } => {
+                let theirs: HashMap<String, Version> = digest.into_iter().collect();
+                // We send everything they are missing or hold an older version of
+                let entries: Vec<(String, Entry)> = self.entries.iter()
+                    .filter(|(key, entry)| theirs.get(*key).map_or(true, |v| *v < entry.version))
+                    .map(|(key, entry)| (key.clone(), entry.clone()))
+                    .collect();
+                // And ask for everything we are missing or hold an older version of
+                let wanted: Vec<String> = theirs.iter()
+                    .filter(|(key, version)| self.entries.get(*key).map_or(true, |e| e.version < **version))
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                self.synced.insert(from);
+                let _ = self.station.send(from, true, &ReplicaMessage::Entries{ entries, wanted }).await;
+            },
+            ReplicaMessage::Entries { entries, wanted } => {
+                for (key, entry) in entries{
+                    self.apply(key, entry);
+                }
+                if !wanted.is_empty(){
+                    let entries = wanted.into_iter().filter_map(|key| self.entries.get(&key).map(|e| (key, e.clone()))).collect();
+                    let _ = self.station.send(from, true, &ReplicaMessage::Entries{ entries, wanted: vec![] }).await;
+                }
+            },
+        }
+    }
+    /// Keeps the entry if it wins against what we have
+    fn apply(&mut self, key: String, entry: Entry){
+        self.clock = self.clock.max(entry.version.counter);
+        if !entry.supersedes(self.entries.get(&key)){
+            return;
+        }
+        let value = entry.value.as_ref().and_then(|v| bincode::deserialize(v).ok());
+        let version = entry.version;
+        self.entries.insert(key.clone(), entry);
+        // Watchers that hung up are forgotten
+        self.watchers.retain(|(prefix, watcher)| {
+            if !key.starts_with(prefix.as_str()){
+                return true;
+            }
+            watcher.send(WatchEvent{ key: key.clone(), value: value.clone(), version }).is_ok()
+        });
+    }
+    async fn sync_new_peers(&mut self){
+        let new_peers: Vec<StationId> = self.station.known_stations().into_iter().filter(|p| !self.synced.contains(p)).collect();
+        if new_peers.is_empty(){
+            return;
+        }
+        let digest: Vec<(String, Version)> = self.entries.iter().map(|(key, entry)| (key.clone(), entry.version)).collect();
+        for peer in new_peers{
+            qlog!(LogLevel::Debug, "Replica {} running anti-entropy with {}", self.station.id(), peer);
+            self.synced.insert(peer);
+            let _ = self.station.send(peer, true, &ReplicaMessage::Sync{ digest: digest.clone() }).await;
+        }
+    }
+}
+
+impl StationOperable for ReplicaMessage{
+    fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        bincode::deserialize(bytes).unwrap()
+    }
+
+    /// Any station can send us anything, so what does not decode is dropped rather than unwrapped
+    fn try_from_bytes(bytes: &[u8]) -> Option<Self> {
+        bincode::deserialize(bytes).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn entry(value: u8, counter: u64, writer: StationId) -> Entry {
+        Entry{ value: Some(vec![value]), version: Version{ counter, writer } }
+    }
+
+    /// What a replica ends up holding after seeing `writes` in order
+    fn replay<'a>(writes: impl IntoIterator<Item = &'a Entry>) -> Option<Entry> {
+        let mut current: Option<Entry> = None;
+        for write in writes{
+            if write.supersedes(current.as_ref()){
+                current = Some(write.clone());
+            }
+        }
+        current
+    }
+
+    #[test]
+    fn higher_counter_wins_whoever_wrote_it(){
+        let old = entry(1, 1, 9);
+        let new = entry(2, 2, 1);
+        assert!(new.supersedes(Some(&old)));
+        assert!(!old.supersedes(Some(&new)));
+    }
+
+    #[test]
+    fn equal_counters_are_broken_by_the_writer(){
+        let low = entry(1, 5, 3);
+        let high = entry(2, 5, 7);
+        assert!(high.supersedes(Some(&low)));
+        assert!(!low.supersedes(Some(&high)));
+    }
+
+    #[test]
+    fn every_arrival_order_picks_the_same_winner(){
+        let writes = [entry(1, 5, 3), entry(2, 5, 7), entry(3, 5, 5), entry(4, 4, 9)];
+        let orders: [[usize; 4]; 4] = [[0, 1, 2, 3], [3, 2, 1, 0], [1, 0, 3, 2], [2, 3, 0, 1]];
+        for order in orders{
+            let winner = replay(order.iter().map(|i| &writes[*i])).unwrap();
+            assert_eq!(winner.version, Version{ counter: 5, writer: 7 });
+            assert_eq!(winner.value, Some(vec![2]));
+        }
+    }
+
+    #[test]
+    fn the_same_write_twice_is_not_reapplied(){
+        let write = entry(1, 5, 3);
+        assert!(write.supersedes(None));
+        assert!(!write.supersedes(Some(&write.clone())));
+    }
+
+    #[test]
+    fn deletes_take_part_like_any_other_write(){
+        let value = entry(1, 5, 3);
+        let delete = Entry{ value: None, version: Version{ counter: 5, writer: 4 } };
+        assert_eq!(replay([&delete, &value]).unwrap().value, None);
+        assert_eq!(replay([&value, &delete]).unwrap().value, None);
+    }
+}
@@ -1,25 +1,49 @@
-use std::{sync::Arc, net::SocketAddr, mem::size_of, collections::{HashMap, VecDeque}};
+use std::{sync::Arc, net::SocketAddr, collections::{HashMap, VecDeque}};
 
 use rand::{thread_rng, Rng};
 use serde::{Serialize, Deserialize};
 
-use crate::{Station, LocalServer, NO_MESSAGE_CHANNEL, PING_CHANNEL, StationOperable, message_exchange::MessageOp, SERVER_CHANNEL, NO_DELIVER_CHANNEL};
+use crate::{Station, LocalServer, Capabilities, NO_MESSAGE_CHANNEL, PING_CHANNEL, StationOperable, message_exchange::MessageOp, SERVER_CHANNEL, NO_DELIVER_CHANNEL, Priority};
+use crate::{qlog, logging::LogLevel};
 
 pub(crate) type StationId = u64;
 pub(crate) type StationChannel = u32;
 pub type StationReturn<T> = (SocketAddr, StationId, T);
-#[derive(Clone, Serialize, Deserialize)]
-pub(crate) struct StationHeader{
-    from_id: StationId,
-    to_id: StationId,
-    channel: StationChannel,
+/// The same as a StationReturn but with the trace context the message was sent with
+pub type TracedReturn<T> = (SocketAddr, StationId, Option<TraceContext>, T);
+#[derive(Clone, Debug, Serialize, Deserialize)]
+///Leads the data of every station message
+pub struct StationHeader{
+    pub from_id: StationId,
+    /// For ping messages this is the channel of the pinging station
+    pub to_id: StationId,
+    pub channel: StationChannel,
+    /// Optional correlation data so a message can be followed across servers
+    pub trace: Option<TraceContext>,
 }
+/// Ties together the log lines of a single flow across every server it touches
+/// The trace id stays the same for the whole flow, each send gets a new span id
+/// and the hop count is increased every time the flow is passed on
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceContext{
+    pub trace_id: u64,
+    pub span_id: u64,
+    pub hops: u32,
+}
+#[derive(Debug)]
 pub enum StationSendError{
     AckFailure,
     UnknownStation,
 }
 pub(crate) fn make_header(channel: StationChannel, from_id: StationId, to_id:StationId) -> StationHeader {
-    StationHeader{ from_id, to_id, channel }
+    StationHeader{ from_id, to_id, channel, trace: None }
+}
+/// Splits a station message into its header and the user data that follows it
+/// The header is variable length so the data offset is taken from the header's encoded size
+pub(crate) fn split_message(message: &[u8]) -> Option<(StationHeader, &[u8])> {
+    let header: StationHeader = bincode::deserialize(message).ok()?;
+    let header_size = bincode::serialized_size(&header).ok()? as usize;
+    Some((header, message.get(header_size..)?))
 }
 /// The entry point for station messages. Is used from a receive exchange task
 pub(crate) async fn route_message(server: Arc<LocalServer>, source:SocketAddr, message: Vec<u8>){
@@ -31,11 +55,15 @@ pub(crate) async fn route_message(server: Arc<LocalServer>, source:SocketAddr, m
         return;
     }
     
+    if let Some(trace) = header.trace{
+        qlog!(LogLevel::Info, "{} Server {} routing message from {} to station {} on channel {}", trace, server.local_address(), source, header.to_id, header.channel);
+    }
+    
     // The message can be some channel or it can be a no message channel
     // The no message channel applies to all channels and routing takes place 
     // with just the station id
     if header.channel == NO_MESSAGE_CHANNEL{
-        println!("Got no message");
+        qlog!(LogLevel::Debug, "Got no message");
         // We need a list of all stations
         for channel in stations.values(){
             if let Some(station) = channel.get(&header.to_id){
@@ -71,7 +99,7 @@ pub(crate) async fn route_message(server: Arc<LocalServer>, source:SocketAddr, m
     // Essentially we just find the single station on our own
     if header.channel == SERVER_CHANNEL{
         if let Some(channel) = stations.get(&header.channel){
-            if let Some(station) = channel.get(&server.internal_station_channel){
+            if let Some(station) = channel.get(&server.internal_station_id){
                 let _ = station.send((source, message));
             }
         }
@@ -99,6 +127,11 @@ impl<T:StationOperable> Station<T>{
             id = thread_rng().gen::<StationId>();
         }
         let intake_channel = flume::unbounded();
+        // Server communication is control traffic, everything else starts out as interactive
+        let priority = match channel{
+            SERVER_CHANNEL => Priority::Control,
+            _ => Priority::Interactive,
+        };
         let station:Station<T> = Station{ 
             id,
             channel,
@@ -106,6 +139,7 @@ impl<T:StationOperable> Station<T>{
             intake: intake_channel.clone(),
             known_stations: HashMap::new(),
             message_queue: VecDeque::new(),
+            priority,
             object: None };
         
         let mut addrs:Vec<SocketAddr>;
@@ -136,6 +170,19 @@ impl<T:StationOperable> Station<T>{
     
     pub(crate) fn get_sender(&self) -> flume::Sender<(SocketAddr, Vec<u8>)> {self.intake.0.clone()}
     
+    /// Sets the class all future sends from this station are scheduled with
+    pub fn set_priority(&mut self, priority: Priority){
+        self.priority = priority;
+    }
+    pub fn get_priority(&self) -> Priority {self.priority}
+    pub fn id(&self) -> StationId {self.id}
+    pub fn get_channel(&self) -> StationChannel {self.channel}
+    /// Every other station this station has heard from
+    /// This only changes when the station processes its intake during a send or receive
+    pub fn known_stations(&self) -> Vec<StationId> {
+        self.known_stations.keys().filter(|id| **id != self.id).copied().collect()
+    }
+    
     pub fn new(server: Arc<LocalServer>, channel: StationChannel, external_id: Option<StationId>) -> Station<T> {
         server.runtime.block_on(Self::new_async(server.clone(), channel, external_id))
     }
@@ -146,11 +193,12 @@ impl<T:StationOperable> Station<T>{
             from_id: self.id,
             // Remember, for ping messages the to_id member is for the channel
             to_id: self.channel as u64,
-            channel: PING_CHANNEL };
+            channel: PING_CHANNEL,
+            trace: None };
         let header = bincode::serialize(&header).unwrap();
         
         // Then we prepare the message
-        let op = MessageOp::Send(tgt_server, true, header);
+        let op = MessageOp::Send(tgt_server, true, Priority::Control, header);
         
         // Then send
         // We use spawn here because we might be pinging a huge number of servers
@@ -158,9 +206,26 @@ impl<T:StationOperable> Station<T>{
         
     }
     
+    /// Pings every server we know of again
+    /// A station only pings when it is made, so one made before its server joined a
+    /// cluster has to announce itself to be found by stations on the other servers
+    pub async fn announce(&self){
+        let addrs: Vec<SocketAddr> = self.server.read_servers().await.keys().copied().collect();
+        for addr in addrs{
+            self.ping(addr);
+        }
+    }
+    
     async fn no_message(&self, tgt: SocketAddr){}
     
     pub async fn send(&mut self, tgt:StationId, nak: bool, object: &T) -> Result<bool, StationSendError>{
+        self.send_traced(tgt, nak, object, None).await
+    }
+    
+    /// Sends like `send` but carries the flow's trace context along with the message
+    /// The message is sent as a new span of `trace`, so a handler passing on a message it
+    /// received should hand the received context to this function
+    pub async fn send_traced(&mut self, tgt:StationId, nak: bool, object: &T, trace: Option<TraceContext>) -> Result<bool, StationSendError>{
         // First we ensure our interal state is up to date
         self.queue_intake().await;
         
@@ -171,17 +236,25 @@ impl<T:StationOperable> Station<T>{
             let data = object.to_bytes();
         
             // Then we need to prepare a header in bytes
+            // Only peers that negotiated tracing get a trace context, it means nothing to the rest
+            let tracing = *tgt_addr == self.server.local_address()
+                || self.server.peer_info(*tgt_addr).await.map_or(false, |p| p.capabilities.contains(Capabilities::TRACING));
+            let trace = trace.filter(|_| tracing).map(|t| t.child());
+            if let Some(trace) = trace{
+                qlog!(LogLevel::Info, "{} Station {} sending to station {} at {}", trace, self.id, tgt, tgt_addr);
+            }
             let header = StationHeader{ 
                 from_id: self.id,
                 to_id: tgt,
-                channel: self.channel };
+                channel: self.channel,
+                trace };
             let mut header = bincode::serialize(&header).unwrap();
         
             // Then fuse
             header.extend_from_slice(&data);
         
             // Then send
-            let op = MessageOp::Send(*tgt_addr, nak, header);
+            let op = MessageOp::Send(*tgt_addr, nak, self.priority, header);
         
             return match LocalServer::exchange(self.server.clone(), op).await{
                 Ok(_) => Ok(true),
@@ -192,74 +265,102 @@ impl<T:StationOperable> Station<T>{
     }
     
     pub async fn receive(&mut self) -> Option<StationReturn<T>>{
+        let (source, from_id, _, object) = self.receive_traced().await?;
+        Some((source, from_id, object))
+    }
+    
+    /// Receives like `receive` but also hands back the trace context of the message
+    pub async fn receive_traced(&mut self) -> Option<TracedReturn<T>>{
         //First we need to update internal state
         self.queue_intake().await;
         // Then we need to try pull the first message
         let Some((source, message)) = self.message_queue.pop_front() else {return None};
         // Then we seperate our data
-        let Ok(header) = bincode::deserialize::<StationHeader>(&message) else {return None};
-        let data = &message[size_of::<StationHeader>()..size_of::<T>()+size_of::<StationHeader>()];
-        let object = T::from_bytes(&data);
+        let Some((header, data)) = split_message(&message) else {return None};
+        let object = self.decode(source, data)?;
         
-        Some((source, header.from_id, object))
+        Some((source, header.from_id, header.trace, object))
     }
     
     pub async fn listen(&mut self) -> Option<StationReturn<T>>{
-        // here we wait till something arrives at the station
-        self.wait_intake().await;
+        let (source, from_id, _, object) = self.listen_traced().await?;
+        Some((source, from_id, object))
+    }
+    
+    /// Listens like `listen` but also hands back the trace context of the message
+    /// Both are cancel safe, so they can be raced in a select without losing messages
+    pub async fn listen_traced(&mut self) -> Option<TracedReturn<T>>{
+        // A send may already have queued messages while taking in its intake, those come
+        // first, otherwise we wait till something arrives at the station
+        if self.message_queue.is_empty(){
+            self.wait_intake().await;
+        }
         // Then we see if its a message that matters
         if let Some((source, message)) = self.message_queue.pop_front(){
             // Then we seperate our data
-            let Ok(header) = bincode::deserialize::<StationHeader>(&message) else {return None};
-            let data = &message[size_of::<StationHeader>()..size_of::<T>()+size_of::<StationHeader>()];
-            let object = T::from_bytes(&data);
+            let Some((header, data)) = split_message(&message) else {return None};
+            let object = self.decode(source, data)?;
         
-            return Some((source, header.from_id, object));
+            return Some((source, header.from_id, header.trace, object));
         }
         
         None
     }
     
     pub async fn receive_all(&mut self) -> Vec<StationReturn<T>> {
+        self.receive_all_traced().await.into_iter().map(|(source, from_id, _, object)| (source, from_id, object)).collect()
+    }
+    
+    /// Receives like `receive_all` but also hands back the trace context of each message
+    pub async fn receive_all_traced(&mut self) -> Vec<TracedReturn<T>> {
         let mut objects = vec![];
         //First we need to update internal state
         self.queue_intake().await;
         // Then we need to iterate through all messages
-        for (source, message) in self.message_queue.drain(..){
+        let messages: Vec<_> = self.message_queue.drain(..).collect();
+        for (source, message) in messages{
             // Then we seperate our data
-            let Ok(header):Result<StationHeader, _> = bincode::deserialize(&message) else {println!("Message queue drained at error"); return objects;};
-            let data = &message[size_of::<StationHeader>()..size_of::<T>()+size_of::<StationHeader>()];
-            let object = T::from_bytes(&data);
-            objects.push((source, header.from_id, object));
+            let Some((header, data)) = split_message(&message) else {qlog!(LogLevel::Error, "Message queue drained at error"); return objects;};
+            let Some(object) = self.decode(source, data) else {continue};
+            objects.push((source, header.from_id, header.trace, object));
         }
         objects
         
         
     }
 
+    fn decode(&self, source: SocketAddr, data: &[u8]) -> Option<T> {
+        let object = T::try_from_bytes(data);
+        if object.is_none(){
+            qlog!(LogLevel::Warn, "Station {} dropped an undecodable message from {}", self.id, source);
+        }
+        object
+    }
     async fn wait_intake(&mut self){
         let intake = self.intake.1.recv_async().await.unwrap();
-        self.intake(intake).await;
+        self.intake(intake);
     }
 
-    async fn intake(&mut self, intake: (SocketAddr, Vec<u8>)){
+    /// Never waits, the replies are spawned, so a listen dropped after taking an intake can not lose it
+    fn intake(&mut self, intake: (SocketAddr, Vec<u8>)){
         let (source, message) = intake;
         // First we pull the header
         let header:StationHeader = bincode::deserialize(&message).unwrap();
         
         // For all messages we just add stations we don't know
         if let None = self.known_stations.get(&header.from_id){
-            println!("Station {} discoverd station {} at {}", self.id, header.from_id, source);
+            qlog!(LogLevel::Debug, "Station {} discoverd station {} at {}", self.id, header.from_id, source);
             let _ = self.known_stations.insert(header.from_id, source);
             // If this is server communication from a new server we need to send back a no message
             if header.channel == SERVER_CHANNEL{
                 let header = StationHeader{ 
                     from_id: self.id,
                     to_id: header.from_id,
-                    channel: NO_MESSAGE_CHANNEL }; 
+                    channel: NO_MESSAGE_CHANNEL,
+                    trace: None }; 
                 let header = bincode::serialize(&header).unwrap();
-                let op = MessageOp::Send(source, true, header);
-                let _ = LocalServer::exchange(self.server.clone(), op).await;
+                let op = MessageOp::Send(source, true, Priority::Control, header);
+                tokio::spawn(LocalServer::exchange(self.server.clone(), op));
             }
         }
         
@@ -273,10 +374,11 @@ impl<T:StationOperable> Station<T>{
             let header = StationHeader{ 
                 from_id: self.id,
                 to_id: header.from_id,
-                channel: NO_MESSAGE_CHANNEL }; 
+                channel: NO_MESSAGE_CHANNEL,
+                trace: None }; 
             let header = bincode::serialize(&header).unwrap();
-            let op = MessageOp::Send(source, true, header);
-            let _ = LocalServer::exchange(self.server.clone(), op).await;
+            let op = MessageOp::Send(source, true, Priority::Control, header);
+            tokio::spawn(LocalServer::exchange(self.server.clone(), op));
             
             return;
         }
@@ -288,17 +390,56 @@ impl<T:StationOperable> Station<T>{
     async fn queue_intake(&mut self){
         let intakes:Vec<(SocketAddr, Vec<u8>)> = self.intake.1.try_iter().collect();
         for intake in intakes{
-            self.intake(intake).await;
+            self.intake(intake);
         }
     }
     
 }
+impl std::fmt::Display for StationSendError{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self{
+            StationSendError::AckFailure => write!(f, "The message was never acknowledged"),
+            StationSendError::UnknownStation => write!(f, "The target station is unknown"),
+        }
+    }
+}
+
 impl StationHeader{
     pub(crate) fn no_message() -> StationHeader {
         StationHeader{ 
             from_id: 0,
             to_id: 0,
-            channel: NO_MESSAGE_CHANNEL }
+            channel: NO_MESSAGE_CHANNEL,
+            trace: None }
+    }
+}
+
+impl TraceContext{
+    /// Starts a brand new flow
+    pub fn new() -> TraceContext {
+        TraceContext{ 
+            trace_id: thread_rng().gen(),
+            span_id: thread_rng().gen(),
+            hops: 0 }
+    }
+    /// The context for passing this flow on to the next hop
+    pub fn child(&self) -> TraceContext {
+        TraceContext{ 
+            trace_id: self.trace_id,
+            span_id: thread_rng().gen(),
+            hops: self.hops.saturating_add(1) }
+    }
+}
+
+impl Default for TraceContext{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Display for TraceContext{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[trace {:016x} span {:016x} hop {}]", self.trace_id, self.span_id, self.hops)
     }
 }
                 
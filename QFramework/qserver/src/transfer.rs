@@ -0,0 +1,496 @@
+use std::{fmt, io, collections::{BTreeMap, HashMap}, io::SeekFrom};
+use rand::{thread_rng, Rng};
+use serde::{Serialize, Deserialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+use tokio::time::{Duration, timeout};
+
+use crate::{Station, StationOperable, Priority, station::StationId};
+use crate::{qlog, logging::LogLevel};
+
+/// The default number of bytes sent per chunk exchange
+pub const DEFAULT_CHUNK_SIZE: usize = 32 * 1024;
+/// How many times a single chunk is retried before the transfer counts as interrupted
+const CHUNK_RETRIES: usize = 3;
+/// How long the sender waits for the receiver to answer a query or a finish
+const REPLY_TIMEOUT: u64 = 2000;
+/// The most bytes a receiver holds ahead of a gap for one transfer, chunks past it are dropped
+/// and sent again when the sender resumes from what the receiver holds
+const MAX_AHEAD_BYTES: usize = 64 * DEFAULT_CHUNK_SIZE;
+/// The most unfinished transfers a receiver keeps, chunks of any further transfer are dropped
+const MAX_PARTIAL_TRANSFERS: usize = 16;
+
+/// The messages of the bulk transfer protocol
+/// Every chunk is sent as a reliable exchange, so the sender always knows how much of
+/// the object the receiver has
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum TransferMessage{
+    Chunk{transfer_id: u64, offset: u64, data: Vec<u8>},
+    /// Sent after the last chunk, asks the receiver to verify the whole object
+    Finish{transfer_id: u64, length: u64, checksum: u64},
+    /// Asks the receiver how many bytes of a transfer it holds
+    Query{transfer_id: u64},
+    Status{transfer_id: u64, received: u64},
+    Verified{transfer_id: u64, ok: bool},
+}
+
+/// Reported after every confirmed chunk
+#[derive(Clone, Copy, Debug)]
+pub struct TransferProgress{
+    pub transfer_id: u64,
+    /// Bytes the receiver has confirmed
+    pub confirmed: u64,
+}
+
+#[derive(Debug)]
+pub enum TransferError{
+    Io(io::Error),
+    /// The target station is not known to the sending station
+    UnknownStation,
+    /// The transfer stopped part way and can be resumed with `BulkSender::resume`
+    Interrupted{transfer_id: u64, confirmed: u64},
+    /// The receiver got the whole object but it did not match the checksum
+    ChecksumMismatch{transfer_id: u64},
+}
+
+/// A 64 bit FNV-1a hash that can be fed incrementally
+#[derive(Clone, Copy)]
+pub struct Checksum(u64);
+
+/// Streams objects to a remote BulkReceiver
+pub struct BulkSender{
+    station: Station<TransferMessage>,
+    chunk_size: usize,
+}
+
+/// A completely received and verified object
+pub struct ReceivedObject{
+    pub transfer_id: u64,
+    pub from: StationId,
+    pub data: Vec<u8>,
+}
+
+/// Collects objects sent by BulkSenders
+/// Partial objects are kept so an interrupted transfer can be resumed
+pub struct BulkReceiver{
+    station: Station<TransferMessage>,
+    partial: HashMap<u64, PartialObject>,
+}
+
+/// The chunks of an object received so far
+/// Chunks that arrive ahead of a gap are held until the gap is filled
+#[derive(Default)]
+struct PartialObject{
+    /// Everything from the start of the object without a gap
+    data: Vec<u8>,
+    ahead: BTreeMap<u64, Vec<u8>>,
+    /// The bytes held in `ahead`
+    ahead_bytes: usize,
+}
+
+impl BulkSender{
+    /// Bulk transfers are scheduled as bulk traffic so they never hold up other exchanges
+    pub fn new(mut station: Station<TransferMessage>) -> BulkSender {
+        station.set_priority(Priority::Bulk);
+        BulkSender{ station, chunk_size: DEFAULT_CHUNK_SIZE }
+    }
+    pub fn set_chunk_size(&mut self, chunk_size: usize){
+        self.chunk_size = chunk_size.max(1);
+    }
+    pub fn station(&mut self) -> &mut Station<TransferMessage> {
+        &mut self.station
+    }
+    /// Streams everything `source` produces to the receiver station `tgt`
+    pub async fn send<R: AsyncRead + Unpin, F: FnMut(TransferProgress)>(&mut self, tgt: StationId, source: &mut R, progress: F) -> Result<u64, TransferError>{
+        let transfer_id = thread_rng().gen::<u64>();
+        self.stream(tgt, transfer_id, 0, Checksum::new(), source, progress).await
+    }
+    /// Continues an interrupted transfer from wherever the receiver got to
+    /// `source` must produce the same object as the original send
+    pub async fn resume<R: AsyncRead + AsyncSeek + Unpin, F: FnMut(TransferProgress)>(&mut self, tgt: StationId, transfer_id: u64, source: &mut R, progress: F) -> Result<u64, TransferError>{
+        let received = self.query(tgt, transfer_id).await?;
+        // The checksum covers the whole object so we have to hash what the receiver already has
+        source.seek(SeekFrom::Start(0)).await.map_err(TransferError::Io)?;
+        let mut checksum = Checksum::new();
+        let mut remaining = received;
+        let mut buffer = vec![0; self.chunk_size];
+        while remaining > 0{
+            let len = (remaining as usize).min(buffer.len());
+            source.read_exact(&mut buffer[..len]).await.map_err(TransferError::Io)?;
+            checksum.update(&buffer[..len]);
+            remaining -= len as u64;
+        }
+        qlog!(LogLevel::Info, "Resuming transfer {} at byte {}", transfer_id, received);
+        self.stream(tgt, transfer_id, received, checksum, source, progress).await
+    }
+    async fn stream<R: AsyncRead + Unpin, F: FnMut(TransferProgress)>(&mut self, tgt: StationId, transfer_id: u64, mut offset: u64, mut checksum: Checksum, source: &mut R, mut progress: F) -> Result<u64, TransferError>{
+        let mut buffer = vec![0; self.chunk_size];
+        loop{
+            let len = fill(source, &mut buffer).await.map_err(TransferError::Io)?;
+            if len == 0{
+                break;
+            }
+            checksum.update(&buffer[..len]);
+            let chunk = TransferMessage::Chunk{ transfer_id, offset, data: buffer[..len].to_vec() };
+            self.send_reliable(tgt, transfer_id, offset, &chunk).await?;
+            offset += len as u64;
+            progress(TransferProgress{ transfer_id, confirmed: offset });
+        }
+
+        // With everything confirmed we ask the receiver to verify the object
+        let finish = TransferMessage::Finish{ transfer_id, length: offset, checksum: checksum.value() };
+        self.send_reliable(tgt, transfer_id, offset, &finish).await?;
+        match self.wait_reply(transfer_id).await{
+            Some(TransferMessage::Verified{ ok: true, .. }) => Ok(offset),
+            Some(TransferMessage::Verified{ ok: false, .. }) => Err(TransferError::ChecksumMismatch{ transfer_id }),
+            _ => Err(TransferError::Interrupted{ transfer_id, confirmed: offset }),
+        }
+    }
+    /// Asks the receiver how much of a transfer it holds
+    async fn query(&mut self, tgt: StationId, transfer_id: u64) -> Result<u64, TransferError>{
+        self.send_reliable(tgt, transfer_id, 0, &TransferMessage::Query{ transfer_id }).await?;
+        match self.wait_reply(transfer_id).await{
+            Some(TransferMessage::Status{ received, .. }) => Ok(received),
+            _ => Err(TransferError::Interrupted{ transfer_id, confirmed: 0 }),
+        }
+    }
+    async fn send_reliable(&mut self, tgt: StationId, transfer_id: u64, confirmed: u64, message: &TransferMessage) -> Result<(), TransferError>{
+        for _ in 0..CHUNK_RETRIES{
+            match self.station.send(tgt, true, message).await{
+                Ok(_) => return Ok(()),
+                Err(crate::station::StationSendError::UnknownStation) => return Err(TransferError::UnknownStation),
+                Err(crate::station::StationSendError::AckFailure) => continue,
+            }
+        }
+        Err(TransferError::Interrupted{ transfer_id, confirmed })
+    }
+    /// Waits for the receiver's answer for `transfer_id`, anything else is dropped
+    async fn wait_reply(&mut self, transfer_id: u64) -> Option<TransferMessage> {
+        loop{
+            let Ok(message) = timeout(Duration::from_millis(REPLY_TIMEOUT), self.station.listen()).await else {return None};
+            if let Some((_, _, message)) = message{
+                match &message{
+                    TransferMessage::Status{ transfer_id: id, .. } | TransferMessage::Verified{ transfer_id: id, .. } if *id == transfer_id => return Some(message),
+                    _ => {},
+                }
+            }
+        }
+    }
+}
+
+impl BulkReceiver{
+    pub fn new(station: Station<TransferMessage>) -> BulkReceiver {
+        BulkReceiver{ station, partial: HashMap::new() }
+    }
+    pub fn station(&mut self) -> &mut Station<TransferMessage> {
+        &mut self.station
+    }
+    /// The number of bytes held for an unfinished transfer
+    pub fn received(&self, transfer_id: u64) -> Option<u64> {
+        self.partial.get(&transfer_id).map(|partial| partial.received())
+    }
+    /// Drops an unfinished transfer that will never be resumed
+    pub fn abandon(&mut self, transfer_id: u64){
+        self.partial.remove(&transfer_id);
+    }
+    /// Processes transfer traffic until an object is complete and verified
+    pub async fn receive(&mut self) -> ReceivedObject {
+        loop{
+            let Some((_, from, message)) = self.station.listen().await else {continue};
+            match message{
+                TransferMessage::Chunk{ transfer_id, offset, data } => {
+                    if !take_chunk(&mut self.partial, transfer_id, offset, data){
+                        qlog!(LogLevel::Warn, "Dropped a chunk of transfer {} from station {}, the receiver is holding too much", transfer_id, from);
+                    }
+                },
+                TransferMessage::Query{ transfer_id } => {
+                    let received = self.received(transfer_id).unwrap_or(0);
+                    let _ = self.station.send(from, true, &TransferMessage::Status{ transfer_id, received }).await;
+                },
+                TransferMessage::Finish{ transfer_id, length, checksum } => {
+                    let partial = self.partial.remove(&transfer_id).unwrap_or_default();
+                    let ok = partial.verify(length, checksum);
+                    let data = partial.data;
+                    let _ = self.station.send(from, true, &TransferMessage::Verified{ transfer_id, ok }).await;
+                    if ok{
+                        return ReceivedObject{ transfer_id, from, data };
+                    }
+                    qlog!(LogLevel::Warn, "Transfer {} from station {} failed verification", transfer_id, from);
+                },
+                _ => {},
+            }
+        }
+    }
+}
+
+/// Adds a chunk to its unfinished transfer, false if it was dropped because the receiver holds too much
+fn take_chunk(partial: &mut HashMap<u64, PartialObject>, transfer_id: u64, offset: u64, data: Vec<u8>) -> bool {
+    if !partial.contains_key(&transfer_id) && partial.len() >= MAX_PARTIAL_TRANSFERS{
+        return false;
+    }
+    partial.entry(transfer_id).or_default().insert(offset, data)
+}
+
+impl PartialObject{
+    /// False if the chunk was dropped because too much is already held ahead of a gap
+    fn insert(&mut self, offset: u64, data: Vec<u8>) -> bool {
+        if offset > self.received(){
+            let replaced = self.ahead.get(&offset).map_or(0, |held| held.len());
+            if self.ahead_bytes - replaced + data.len() > MAX_AHEAD_BYTES{
+                return false;
+            }
+            self.ahead_bytes = self.ahead_bytes - replaced + data.len();
+            self.ahead.insert(offset, data);
+            return true;
+        }
+        self.append(offset, &data);
+        // The chunk may have closed the gap before chunks we are holding
+        while let Some((&offset, _)) = self.ahead.first_key_value(){
+            if offset > self.received(){
+                break;
+            }
+            let data = self.ahead.remove(&offset).unwrap();
+            self.ahead_bytes -= data.len();
+            self.append(offset, &data);
+        }
+        true
+    }
+    /// Appends whatever part of a chunk starting at or before the end of `data` is new
+    /// Duplicates of chunks we already have add nothing
+    fn append(&mut self, offset: u64, chunk: &[u8]){
+        let skip = (self.received() - offset) as usize;
+        if skip < chunk.len(){
+            self.data.extend_from_slice(&chunk[skip..]);
+        }
+    }
+    /// Bytes held from the start of the object without a gap, where a resumed transfer carries on from
+    fn received(&self) -> u64 {
+        self.data.len() as u64
+    }
+    fn verify(&self, length: u64, checksum: u64) -> bool {
+        self.ahead.is_empty() && self.received() == length && Checksum::of(&self.data).value() == checksum
+    }
+}
+
+/// Reads until `buffer` is full or the source ends
+async fn fill<R: AsyncRead + Unpin>(source: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buffer.len(){
+        let read = source.read(&mut buffer[len..]).await?;
+        if read == 0{
+            break;
+        }
+        len += read;
+    }
+    Ok(len)
+}
+
+impl Default for Checksum{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Checksum{
+    pub fn new() -> Checksum {
+        Checksum(0xcbf29ce484222325)
+    }
+    pub fn of(data: &[u8]) -> Checksum {
+        let mut checksum = Checksum::new();
+        checksum.update(data);
+        checksum
+    }
+    pub fn update(&mut self, data: &[u8]){
+        for byte in data{
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for TransferError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            TransferError::Io(e) => write!(f, "Transfer source failed: {}", e),
+            TransferError::UnknownStation => write!(f, "Transfer target station is unknown"),
+            TransferError::Interrupted { transfer_id, confirmed } => write!(f, "Transfer {} interrupted after {} confirmed bytes", transfer_id, confirmed),
+            TransferError::ChecksumMismatch { transfer_id } => write!(f, "Transfer {} failed checksum verification", transfer_id),
+        }
+    }
+}
+
+impl StationOperable for TransferMessage{
+    fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        bincode::deserialize(bytes).unwrap()
+    }
+
+    /// Any station can send us anything, so what does not decode is dropped rather than unwrapped
+    fn try_from_bytes(bytes: &[u8]) -> Option<Self> {
+        bincode::deserialize(bytes).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const CHUNK: usize = 7;
+
+    fn object() -> Vec<u8> {
+        (0..100u8).map(|b| b.wrapping_mul(31)).collect()
+    }
+
+    fn chunks(data: &[u8]) -> Vec<(u64, Vec<u8>)> {
+        data.chunks(CHUNK).enumerate().map(|(i, c)| ((i * CHUNK) as u64, c.to_vec())).collect()
+    }
+
+    fn assemble(chunks: impl IntoIterator<Item = (u64, Vec<u8>)>) -> PartialObject {
+        let mut partial = PartialObject::default();
+        for (offset, data) in chunks{
+            assert!(partial.insert(offset, data));
+        }
+        partial
+    }
+
+    #[test]
+    fn checksum_is_fnv1a(){
+        assert_eq!(Checksum::default().value(), 0xcbf29ce484222325);
+        assert_eq!(Checksum::of(b"a").value(), 0xaf63dc4c8601ec8c);
+        assert_eq!(Checksum::of(b"foobar").value(), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn checksum_fed_in_pieces_matches_checksum_of_the_whole(){
+        let data = object();
+        let mut checksum = Checksum::new();
+        for (_, chunk) in chunks(&data){
+            checksum.update(&chunk);
+        }
+        assert_eq!(checksum.value(), Checksum::of(&data).value());
+    }
+
+    #[test]
+    fn in_order_chunks_round_trip(){
+        let data = object();
+        let partial = assemble(chunks(&data));
+        assert!(partial.verify(data.len() as u64, Checksum::of(&data).value()));
+        assert_eq!(partial.data, data);
+    }
+
+    #[test]
+    fn out_of_order_chunks_round_trip(){
+        let data = object();
+        let mut shuffled = chunks(&data);
+        shuffled.reverse();
+        shuffled.swap(2, 7);
+        let mut partial = PartialObject::default();
+        let (last, rest) = shuffled.split_last().unwrap();
+        for (offset, chunk) in rest.iter().cloned(){
+            partial.insert(offset, chunk);
+        }
+        // Nothing counts as received until the first chunk is in
+        assert_eq!(partial.received(), 0);
+        assert!(!partial.verify(data.len() as u64, Checksum::of(&data).value()));
+        partial.insert(last.0, last.1.clone());
+        assert_eq!(partial.received(), data.len() as u64);
+        assert!(partial.verify(data.len() as u64, Checksum::of(&data).value()));
+        assert_eq!(partial.data, data);
+    }
+
+    #[test]
+    fn duplicate_chunks_are_ignored(){
+        let data = object();
+        let mut with_duplicates = vec![];
+        for (offset, chunk) in chunks(&data){
+            with_duplicates.push((offset, chunk.clone()));
+            with_duplicates.push((offset, chunk));
+        }
+        // A duplicate that arrives after later chunks, and one of a chunk still held ahead of a gap
+        with_duplicates.push((0, data[..CHUNK].to_vec()));
+        let partial = assemble(with_duplicates);
+        assert_eq!(partial.data, data);
+
+        let mut ahead = chunks(&data);
+        ahead.insert(0, ahead[3].clone());
+        let partial = assemble(ahead);
+        assert_eq!(partial.data, data);
+    }
+
+    #[test]
+    fn overlapping_chunks_only_add_what_is_new(){
+        let data = object();
+        let partial = assemble([(0, data[..10].to_vec()), (5, data[5..30].to_vec()), (30, data[30..].to_vec())]);
+        assert_eq!(partial.data, data);
+    }
+
+    #[test]
+    fn corrupted_chunk_fails_verification(){
+        let data = object();
+        let mut corrupted = chunks(&data);
+        corrupted[4].1[2] ^= 0x40;
+        let partial = assemble(corrupted);
+        assert_eq!(partial.received(), data.len() as u64);
+        assert!(!partial.verify(data.len() as u64, Checksum::of(&data).value()));
+    }
+
+    #[test]
+    fn missing_chunk_fails_verification(){
+        let data = object();
+        let mut missing = chunks(&data);
+        missing.remove(5);
+        let partial = assemble(missing);
+        assert_eq!(partial.received(), 5 * CHUNK as u64);
+        assert!(!partial.verify(data.len() as u64, Checksum::of(&data).value()));
+    }
+
+    #[test]
+    fn junk_is_dropped_not_a_panic(){
+        assert!(TransferMessage::try_from_bytes(&[]).is_none());
+        assert!(TransferMessage::try_from_bytes(&[0xff; 16]).is_none());
+        let chunk = TransferMessage::Chunk{ transfer_id: 1, offset: 0, data: vec![1, 2, 3] }.to_bytes();
+        assert!(TransferMessage::try_from_bytes(&chunk[..chunk.len() - 1]).is_none());
+        assert!(TransferMessage::try_from_bytes(&chunk).is_some());
+    }
+
+    #[test]
+    fn chunks_far_ahead_are_dropped_once_the_hold_is_full(){
+        let mut partial = PartialObject::default();
+        let chunk = vec![1; DEFAULT_CHUNK_SIZE];
+        // One byte is missing at the start, everything after it has to be held
+        let held = MAX_AHEAD_BYTES / DEFAULT_CHUNK_SIZE;
+        for i in 0..held{
+            assert!(partial.insert(1 + (i * DEFAULT_CHUNK_SIZE) as u64, chunk.clone()));
+        }
+        assert!(!partial.insert(u64::MAX / 2, chunk.clone()));
+        assert!(!partial.insert(1 + (held * DEFAULT_CHUNK_SIZE) as u64, chunk.clone()));
+        // Sending a held chunk again does not count twice
+        assert!(partial.insert(1, chunk.clone()));
+        assert_eq!(partial.ahead_bytes, MAX_AHEAD_BYTES);
+        // Filling the gap frees the hold for the rest of the object
+        assert!(partial.insert(0, vec![1]));
+        assert_eq!(partial.ahead_bytes, 0);
+        assert_eq!(partial.received(), 1 + MAX_AHEAD_BYTES as u64);
+        assert!(partial.insert(partial.received(), chunk));
+    }
+
+    #[test]
+    fn transfers_past_the_limit_are_dropped(){
+        let mut partial = HashMap::new();
+        for transfer_id in 0..MAX_PARTIAL_TRANSFERS as u64{
+            assert!(take_chunk(&mut partial, transfer_id, 0, vec![1]));
+        }
+        assert!(!take_chunk(&mut partial, u64::MAX, 0, vec![1]));
+        // Transfers already open carry on
+        assert!(take_chunk(&mut partial, 0, 1, vec![2]));
+        assert_eq!(partial[&0].data, vec![1, 2]);
+        // And a finished one makes room for the next
+        partial.remove(&3);
+        assert!(take_chunk(&mut partial, u64::MAX, 0, vec![1]));
+    }
+}
@@ -0,0 +1,84 @@
+use std::fmt;
+use serde::{Serialize, Deserialize};
+
+use crate::{LocalServer, KEEP_ALIVE_TIMEOUT, KEEP_ALIVE_BUDGET, DRAIN_TIMEOUT, message_exchange::{SEND_TIMEOUT_TIME, SEND_TIMEOUT_CYCLES, RECEIVE_TIMEOUT_TIME, RECEIVE_TIMEOUT_CYCLES, MESSAGE_COMPLETE_TIMEOUT}};
+
+/// The timers of the keep alive and message exchange protocols
+/// Every time is in milliseconds. Changes apply to keep alives and exchanges started afterwards
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Tuning{
+    /// How long a keep alive waits between messages
+    pub keep_alive_interval: u64,
+    /// How many keep alive messages can go unanswered before a server is dropped
+    pub keep_alive_budget: usize,
+    /// How long a reliable send waits for the receiver before asking it for an update
+    pub send_timeout: u64,
+    /// How many updates a reliable send asks for before it gives up
+    pub send_timeout_cycles: usize,
+    /// How long a receive waits for the next fragment before asking for retransmits
+    pub receive_timeout: u64,
+    /// How many times a receive asks for retransmits before it drops the message
+    pub receive_timeout_cycles: usize,
+    /// How long a finished receive keeps answering the sender
+    pub message_complete_timeout: u64,
+    /// How long a leaving server waits for its exchanges to finish
+    pub drain_timeout: u64,
+}
+
+/// A tuning value that would stall or spin the protocols
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TuningError{
+    pub field: &'static str,
+}
+
+impl Default for Tuning{
+    fn default() -> Self {
+        Tuning{
+            keep_alive_interval: KEEP_ALIVE_TIMEOUT,
+            keep_alive_budget: KEEP_ALIVE_BUDGET,
+            send_timeout: SEND_TIMEOUT_TIME,
+            send_timeout_cycles: SEND_TIMEOUT_CYCLES,
+            receive_timeout: RECEIVE_TIMEOUT_TIME,
+            receive_timeout_cycles: RECEIVE_TIMEOUT_CYCLES,
+            message_complete_timeout: MESSAGE_COMPLETE_TIMEOUT,
+            drain_timeout: DRAIN_TIMEOUT }
+    }
+}
+
+impl Tuning{
+    /// Every interval and budget has to be at least 1, a zero budget gives up before the first
+    /// try and a zero interval busy loops. The drain and complete timeouts may be zero
+    pub fn check(&self) -> Result<(), TuningError> {
+        let fields = [
+            ("keep_alive_interval", self.keep_alive_interval as usize),
+            ("keep_alive_budget", self.keep_alive_budget),
+            ("send_timeout", self.send_timeout as usize),
+            ("send_timeout_cycles", self.send_timeout_cycles),
+            ("receive_timeout", self.receive_timeout as usize),
+            ("receive_timeout_cycles", self.receive_timeout_cycles)];
+        match fields.iter().find(|(_, value)| *value == 0){
+            Some((field, _)) => Err(TuningError{ field }),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for TuningError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid tuning: {} can not be zero", self.field)
+    }
+}
+
+/// Protocol timing functionality
+impl LocalServer{
+    /// Invalid tuning is refused and the current tuning kept
+    pub fn set_tuning(&self, tuning: Tuning) -> Result<(), TuningError>{
+        tuning.check()?;
+        *self.tuning.write().unwrap() = tuning;
+        Ok(())
+    }
+    pub fn tuning(&self) -> Tuning {
+        *self.tuning.read().unwrap()
+    }
+}
@@ -0,0 +1,33 @@
+//! What the integration tests share
+//! Every test file builds its own crate and uses only some of this
+#![allow(dead_code)]
+
+use std::time::Duration;
+
+use qserver::{StationOperable, Tuning};
+
+pub const CONVERGENCE_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Keep alives and sends that give up on a dead server within a couple of seconds
+pub fn fast_expiry() -> Tuning {
+    Tuning{
+        keep_alive_interval: 100,
+        keep_alive_budget: 3,
+        send_timeout: 50,
+        send_timeout_cycles: 3,
+        ..Tuning::default()
+    }
+}
+
+/// A number sent as its little endian bytes
+pub struct Payload(pub u64);
+
+impl StationOperable for Payload{
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_le_bytes().to_vec()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        Payload(u64::from_le_bytes(bytes[..8].try_into().unwrap()))
+    }
+}
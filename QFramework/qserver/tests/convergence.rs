@@ -0,0 +1,57 @@
+use std::time::Duration;
+
+use qserver::{NodeRuntime, TestCluster};
+
+mod common;
+use common::{CONVERGENCE_TIMEOUT, fast_expiry};
+
+#[test]
+fn shared_runtime_cluster_converges(){
+    let cluster = TestCluster::start(NodeRuntime::Shared, 4, 1).unwrap();
+    cluster.wait_converged(CONVERGENCE_TIMEOUT).unwrap();
+    // The private node is only known to the discoverable ones
+    assert_eq!(cluster.peers(0).len(), 3);
+    assert_eq!(cluster.peers(3).len(), 3);
+}
+
+#[test]
+fn per_node_runtime_cluster_converges(){
+    let mut cluster = TestCluster::new(NodeRuntime::PerNode);
+    for _ in 0..3{
+        cluster.add_node(true);
+    }
+    let private = cluster.add_node(false);
+    // A chain, so every node past the second is only told about the rest
+    for node in 1..cluster.len(){
+        cluster.join(node, node - 1).unwrap();
+    }
+    cluster.wait_converged(CONVERGENCE_TIMEOUT).unwrap();
+    assert_eq!(cluster.peers(private).len(), 3);
+}
+
+#[test]
+fn killed_node_expires_from_every_table(){
+    let mut cluster = TestCluster::new(NodeRuntime::Shared);
+    cluster.set_tuning(fast_expiry()).unwrap();
+    for node in 0..4{
+        cluster.add_node(node < 3);
+        if node > 0{
+            cluster.join(node, 0).unwrap();
+        }
+    }
+    cluster.wait_converged(CONVERGENCE_TIMEOUT).unwrap();
+
+    cluster.kill(1).unwrap();
+    cluster.wait_removed(1, CONVERGENCE_TIMEOUT).unwrap();
+    // The rest of the cluster is left intact
+    cluster.wait_converged(CONVERGENCE_TIMEOUT).unwrap();
+}
+
+#[test]
+fn leaving_node_is_dropped_straight_away(){
+    let mut cluster = TestCluster::start(NodeRuntime::Shared, 3, 0).unwrap();
+    cluster.wait_converged(CONVERGENCE_TIMEOUT).unwrap();
+    cluster.leave(2).unwrap();
+    // Without the leave message this would take the default keep alive budget
+    cluster.wait_removed(2, Duration::from_secs(2)).unwrap();
+}
@@ -0,0 +1,27 @@
+use std::{thread::sleep, time::Duration};
+
+use qserver::{NodeRuntime, TestCluster};
+
+mod common;
+use common::CONVERGENCE_TIMEOUT;
+
+/// A settled node only sends keep alives, one per peer every interval, about 8 in the window
+const SETTLED_SENDS: u64 = 15;
+const WINDOW: Duration = Duration::from_secs(2);
+
+#[test]
+fn settled_cluster_stops_pinging(){
+    // The third node is told about the second by the first and the first by the second,
+    // so re-pinging known servers on every download would never end
+    let cluster = TestCluster::start(NodeRuntime::Shared, 3, 0).unwrap();
+    cluster.wait_converged(CONVERGENCE_TIMEOUT).unwrap();
+    sleep(Duration::from_millis(500));
+    let rt = cluster.server(0).unwrap().get_runtime();
+    let sent = |node: usize| rt.block_on(cluster.server(node).unwrap().exchange_counts()).sent;
+    let before: Vec<u64> = (0..3).map(sent).collect();
+    sleep(WINDOW);
+    for (node, before) in before.into_iter().enumerate(){
+        let during = sent(node) - before;
+        assert!(during < SETTLED_SENDS, "Node {} sent {} exchanges after settling", node, during);
+    }
+}
@@ -0,0 +1,8 @@
+use qserver::{Dissection, MessageExchangeHeader};
+
+#[test]
+fn last_possible_fragment_index_displays(){
+    let header = MessageExchangeHeader{ exchange_id: 1, fragment_count: u32::MAX, fragment_index: u32::MAX, fragment_data: 0, nak: false, message_complete: false };
+    let dissection = Dissection::new(&bincode::serialize(&header).unwrap()).unwrap();
+    assert!(dissection.to_string().contains("fragment 4294967296/4294967295"));
+}
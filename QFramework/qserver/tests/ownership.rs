@@ -0,0 +1,62 @@
+use std::{thread, time::Duration};
+
+use qserver::{NodeRuntime, OwnershipError, OwnershipMessage, ShardOwnership, Station, StationOperable, TestCluster};
+
+mod common;
+use common::{CONVERGENCE_TIMEOUT, fast_expiry};
+
+const INDEX_CHANNEL: u32 = 40;
+const OWNERSHIP_CHANNEL: u32 = 41;
+const NEW_OWNER_ID: u64 = 0x0ee0;
+const SHARD: u64 = 3;
+
+#[test]
+fn lost_commit_keeps_the_shard_with_its_owner(){
+    let mut cluster = TestCluster::new(NodeRuntime::Shared);
+    cluster.set_tuning(fast_expiry()).unwrap();
+    cluster.add_node(true);
+    cluster.add_node(true);
+    cluster.join(1, 0).unwrap();
+    cluster.wait_converged(CONVERGENCE_TIMEOUT).unwrap();
+    let (owner_server, new_owner_server) = (cluster.server(0).unwrap(), cluster.server(1).unwrap());
+    let rt = owner_server.get_runtime();
+
+    let mut owner = ShardOwnership::new(
+        Station::new(owner_server.clone(), INDEX_CHANNEL, None),
+        Station::new(owner_server.clone(), OWNERSHIP_CHANNEL, None));
+    let mut new_owner: Station<OwnershipMessage> = Station::new(new_owner_server.clone(), OWNERSHIP_CHANNEL, Some(NEW_OWNER_ID));
+    rt.block_on(owner.claim(SHARD)).unwrap();
+
+    // The new owner answers the prepare and then goes away before the commit reaches it
+    let new_owner_rt = rt.clone();
+    let new_owner_task = thread::spawn(move || {
+        new_owner_rt.block_on(async {
+            loop{
+                if let Some((_, from, OwnershipMessage::Prepare{ shard, epoch, .. })) = new_owner.listen().await{
+                    new_owner.send(from, true, &OwnershipMessage::Prepared{ shard, epoch }).await.unwrap();
+                    break;
+                }
+            }
+        });
+        new_owner_server.shutdown();
+    });
+    // Both stations have to have heard each other's pings before the handoff can be addressed
+    thread::sleep(Duration::from_millis(300));
+    let result = rt.block_on(owner.hand_off(SHARD, NEW_OWNER_ID, b"state".to_vec()));
+    new_owner_task.join().unwrap();
+
+    assert!(matches!(result, Err(OwnershipError::HandoffFailed{ shard: SHARD, to: NEW_OWNER_ID })), "{:?}", result);
+    assert_eq!(rt.block_on(owner.owner(SHARD)), Some(owner.id()));
+    assert!(rt.block_on(owner.owned()).contains(&SHARD));
+    cluster.stop();
+}
+
+#[test]
+fn junk_is_dropped_not_a_panic(){
+    assert!(OwnershipMessage::try_from_bytes(&[]).is_none());
+    assert!(OwnershipMessage::try_from_bytes(&[0xff; 16]).is_none());
+    // A prepare whose state runs past the end of the datagram
+    let prepare = OwnershipMessage::Prepare{ shard: SHARD, epoch: 1, state: b"state".to_vec() }.to_bytes();
+    assert!(OwnershipMessage::try_from_bytes(&prepare[..prepare.len() - 1]).is_none());
+    assert!(matches!(OwnershipMessage::try_from_bytes(&prepare), Some(OwnershipMessage::Prepare{ shard: SHARD, epoch: 1, .. })));
+}
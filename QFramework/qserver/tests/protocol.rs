@@ -0,0 +1,42 @@
+use qserver::{Capabilities, Handshake, ServerInternalComm, StationOperable, PROTOCOL_VERSION};
+
+#[test]
+fn messages_round_trip_with_the_version_in_front(){
+    let bytes = ServerInternalComm::Ping(Handshake::local(true)).to_bytes();
+    assert_eq!(u16::from_le_bytes([bytes[0], bytes[1]]), PROTOCOL_VERSION);
+    match ServerInternalComm::try_from_bytes(&bytes){
+        Some(ServerInternalComm::Ping(handshake)) => assert!(handshake.discoverable && handshake.version == PROTOCOL_VERSION),
+        _ => panic!("The ping did not survive the round trip"),
+    }
+}
+
+#[test]
+fn junk_is_dropped_not_a_panic(){
+    assert!(ServerInternalComm::try_from_bytes(&[]).is_none());
+    assert!(ServerInternalComm::try_from_bytes(&[PROTOCOL_VERSION as u8]).is_none());
+    let mut junk = PROTOCOL_VERSION.to_le_bytes().to_vec();
+    junk.extend_from_slice(&[0xff; 32]);
+    assert!(ServerInternalComm::try_from_bytes(&junk).is_none());
+    // from_bytes has to give something back, and it is something we refuse
+    assert!(matches!(ServerInternalComm::from_bytes(&junk), ServerInternalComm::Incompatible(_)));
+}
+
+#[test]
+fn other_versions_are_recognised_whatever_follows(){
+    let mut newer = (PROTOCOL_VERSION + 1).to_le_bytes().to_vec();
+    newer.extend_from_slice(&[0xab; 7]);
+    assert!(matches!(ServerInternalComm::try_from_bytes(&newer), Some(ServerInternalComm::Incompatible(v)) if v == PROTOCOL_VERSION + 1));
+    assert!(matches!(ServerInternalComm::try_from_bytes(&[0, 0, 0, 0]), Some(ServerInternalComm::Incompatible(0))));
+}
+
+#[test]
+fn only_shared_capabilities_are_negotiated(){
+    // A newer peer may advertise bits this build has never heard of
+    let unknown = Capabilities(1 << 31);
+    let peer = Handshake{ version: PROTOCOL_VERSION, capabilities: Capabilities(Capabilities::TRACING.0 | unknown.0), discoverable: true };
+    let info = peer.check().unwrap();
+    assert!(info.capabilities.contains(Capabilities::TRACING));
+    assert!(!info.capabilities.contains(unknown));
+    let untraced = Handshake{ capabilities: Capabilities::NONE, ..peer };
+    assert!(!untraced.check().unwrap().capabilities.contains(Capabilities::TRACING));
+}
@@ -0,0 +1,151 @@
+use proptest::prelude::*;
+
+use qserver::{fragment_message, MessageExchangeHeader, Reassembly, ReassemblyError, MAX_FRAGMENT_COUNT};
+
+const EXCHANGE_ID: u64 = 0x5eed;
+
+fn first_header(datagram: &[u8]) -> MessageExchangeHeader {
+    bincode::deserialize(datagram).unwrap()
+}
+
+/// A message plus the order its fragments arrive in
+/// Indices are taken modulo the fragment count, so any list is a mix of reorders,
+/// duplicates and, for fragments never picked, losses
+fn arrivals() -> impl Strategy<Value = (Vec<u8>, Vec<usize>)> {
+    (prop::collection::vec(any::<u8>(), 1..6000), prop::collection::vec(any::<usize>(), 0..40))
+}
+
+proptest!{
+    #[test]
+    fn every_header_fits_its_space(exchange_id in any::<u64>(), fragment_count in any::<u32>(), fragment_index in any::<u32>(), fragment_data in any::<u32>(), nak in any::<bool>(), message_complete in any::<bool>()){
+        let header = MessageExchangeHeader{ exchange_id, fragment_count, fragment_index, fragment_data, nak, message_complete };
+        prop_assert!(bincode::serialized_size(&header).unwrap() as usize <= std::mem::size_of::<MessageExchangeHeader>());
+    }
+
+    #[test]
+    fn all_fragments_in_any_order_round_trip((message, order) in arrivals(), nak in any::<bool>()){
+        let datagrams = fragment_message(EXCHANGE_ID, nak, &message);
+        let mut reassembly = Reassembly::new(&first_header(&datagrams[0])).unwrap();
+        // Whatever the order, every fragment shows up at least once
+        for index in order.iter().map(|i| i % datagrams.len()).chain(0..datagrams.len()){
+            reassembly.insert(&datagrams[index]).unwrap();
+        }
+        prop_assert!(reassembly.is_complete());
+        prop_assert_eq!(reassembly.assemble().unwrap(), message);
+    }
+
+    #[test]
+    fn lost_fragments_fail_cleanly((message, order) in arrivals()){
+        let datagrams = fragment_message(EXCHANGE_ID, true, &message);
+        let mut reassembly = Reassembly::new(&first_header(&datagrams[0])).unwrap();
+        let mut arrived = vec![false; datagrams.len()];
+        for index in order.iter().map(|i| i % datagrams.len()){
+            reassembly.insert(&datagrams[index]).unwrap();
+            arrived[index] = true;
+        }
+        let lost: Vec<u32> = (0..datagrams.len() as u32).filter(|i| !arrived[*i as usize]).collect();
+        prop_assert_eq!(reassembly.missing(), lost.clone());
+        match reassembly.assemble(){
+            Ok(assembled) => {
+                prop_assert!(lost.is_empty());
+                prop_assert_eq!(assembled, message);
+            },
+            Err(e) => {
+                prop_assert!(!lost.is_empty());
+                prop_assert_eq!(e, ReassemblyError::Incomplete);
+            },
+        }
+    }
+
+    #[test]
+    fn malformed_datagrams_never_panic(message in prop::collection::vec(any::<u8>(), 1..3000), junk in prop::collection::vec(prop::collection::vec(any::<u8>(), 0..1100), 0..20)){
+        let datagrams = fragment_message(EXCHANGE_ID, true, &message);
+        let mut reassembly = Reassembly::new(&first_header(&datagrams[0])).unwrap();
+        for datagram in junk.iter(){
+            let _ = reassembly.insert(datagram);
+            // Junk can also be the first datagram of an exchange
+            if let Ok(header) = bincode::deserialize::<MessageExchangeHeader>(datagram){
+                if let Ok(mut other) = Reassembly::new(&header){
+                    let _ = other.insert(datagram);
+                    let _ = other.assemble();
+                }
+            }
+        }
+        // Junk only ever fills a slot with a well formed fragment of our exchange, so once the
+        // real fragments are put in on top the message comes out intact
+        for datagram in datagrams.iter(){
+            reassembly.insert(datagram).unwrap();
+        }
+        prop_assert_eq!(reassembly.assemble().unwrap(), message);
+    }
+
+    #[test]
+    fn tampered_headers_are_refused(message in prop::collection::vec(any::<u8>(), 1..3000), fragment_index in any::<u32>(), fragment_data in any::<u32>(), fragment_count in any::<u32>()){
+        let datagrams = fragment_message(EXCHANGE_ID, true, &message);
+        let header = first_header(&datagrams[0]);
+        let mut reassembly = Reassembly::new(&header).unwrap();
+        let tampered = MessageExchangeHeader{ fragment_index, fragment_data, fragment_count, ..header };
+        let mut datagram = datagrams[0].clone();
+        bincode::serialize_into(&mut datagram[..], &tampered).unwrap();
+        match reassembly.insert(&datagram){
+            Ok(_) => {
+                prop_assert!(fragment_count == header.fragment_count && fragment_index < fragment_count);
+                prop_assert!(std::mem::size_of::<MessageExchangeHeader>() + fragment_data as usize <= datagram.len());
+            },
+            Err(ReassemblyError::BadFragmentCount(_)) => prop_assert_ne!(fragment_count, header.fragment_count),
+            Err(ReassemblyError::BadIndex(index)) => prop_assert!(index >= header.fragment_count),
+            Err(ReassemblyError::BadLength(_)) => {},
+            Err(e) => prop_assert!(false, "unexpected {}", e),
+        }
+        let _ = reassembly.assemble();
+    }
+}
+
+#[test]
+fn empty_message_has_no_fragments(){
+    assert!(fragment_message(EXCHANGE_ID, true, &[]).is_empty());
+}
+
+#[test]
+fn unreasonable_fragment_counts_are_refused(){
+    let header = |fragment_count| MessageExchangeHeader{ exchange_id: EXCHANGE_ID, fragment_count, fragment_index: 0, fragment_data: 0, nak: true, message_complete: false };
+    assert_eq!(Reassembly::new(&header(0)).err(), Some(ReassemblyError::BadFragmentCount(0)));
+    assert_eq!(Reassembly::new(&header(MAX_FRAGMENT_COUNT + 1)).err(), Some(ReassemblyError::BadFragmentCount(MAX_FRAGMENT_COUNT + 1)));
+    assert_eq!(Reassembly::new(&header(u32::MAX)).err(), Some(ReassemblyError::BadFragmentCount(u32::MAX)));
+}
+
+#[test]
+fn announcing_the_most_fragments_is_accepted(){
+    let message = vec![7; 100];
+    let datagrams = fragment_message(EXCHANGE_ID, true, &message);
+    let header = MessageExchangeHeader{ fragment_count: MAX_FRAGMENT_COUNT, ..first_header(&datagrams[0]) };
+    let mut datagram = datagrams[0].clone();
+    bincode::serialize_into(&mut datagram[..], &header).unwrap();
+    let mut reassembly = Reassembly::new(&header).unwrap();
+    reassembly.insert(&datagram).unwrap();
+    assert_eq!(reassembly.missing().len(), MAX_FRAGMENT_COUNT as usize - 1);
+    assert_eq!(reassembly.assemble().err(), Some(ReassemblyError::Incomplete));
+}
+
+#[test]
+fn datagrams_longer_than_a_fragment_are_taken_without_their_trailer(){
+    let message = vec![3; 100];
+    let datagrams = fragment_message(EXCHANGE_ID, true, &message);
+    let mut reassembly = Reassembly::new(&first_header(&datagrams[0])).unwrap();
+    let mut oversized = datagrams[0].clone();
+    oversized.resize(4096, 0xff);
+    reassembly.insert(&oversized).unwrap();
+    assert_eq!(reassembly.assemble().unwrap(), message);
+}
+
+#[test]
+fn data_running_past_a_fragment_is_refused(){
+    let datagrams = fragment_message(EXCHANGE_ID, true, &[1; 100]);
+    let header = first_header(&datagrams[0]);
+    let mut reassembly = Reassembly::new(&header).unwrap();
+    // The datagram really holds this much data, but no fragment can
+    let tampered = MessageExchangeHeader{ fragment_data: 2048, ..header };
+    let mut datagram = vec![0; 4096];
+    bincode::serialize_into(&mut datagram[..], &tampered).unwrap();
+    assert_eq!(reassembly.insert(&datagram).err(), Some(ReassemblyError::BadLength(2048)));
+}
@@ -0,0 +1,66 @@
+use std::{net::SocketAddr, thread::sleep, time::Duration};
+
+use qserver::{CaptureReader, LocalServer, Station};
+
+mod common;
+use common::Payload;
+
+const CHANNEL: u32 = 7;
+const RECEIVER_ID: u64 = 0x5151;
+
+#[test]
+fn replayed_capture_redelivers_station_messages(){
+    let capture = std::env::temp_dir().join(format!("qserver-replay-{}.qcap", std::process::id()));
+    let loopback: SocketAddr = "127.0.0.1:0".parse().unwrap();
+
+    // First we record a station exchange on a live server
+    let original = LocalServer::new(Some(loopback), true, None, None);
+    let rt = original.get_runtime();
+    original.start_capture(&capture).unwrap();
+    let mut receiver: Station<Payload> = Station::new(original.clone(), CHANNEL, Some(RECEIVER_ID));
+    let mut sender: Station<Payload> = Station::new(original.clone(), CHANNEL, None);
+    // The receiver has to process the sender's ping before the sender knows of it
+    sleep(Duration::from_millis(200));
+    rt.block_on(receiver.receive());
+    sleep(Duration::from_millis(200));
+    assert!(rt.block_on(sender.send(RECEIVER_ID, true, &Payload(42))).is_ok());
+    sleep(Duration::from_millis(200));
+    let delivered = rt.block_on(receiver.receive_all());
+    assert!(delivered.iter().any(|(_, _, p)| p.0 == 42));
+    original.stop_capture();
+
+    // Then we feed the capture into a fresh server with the same receiving station
+    let fresh = LocalServer::new(Some(loopback), true, None, None);
+    let rt = fresh.get_runtime();
+    let mut replayed: Station<Payload> = Station::new(fresh.clone(), CHANNEL, Some(RECEIVER_ID));
+    let report = rt.block_on(LocalServer::replay_capture(fresh.clone(), &capture, false)).unwrap();
+    assert!(report.replayed > 0);
+    sleep(Duration::from_millis(200));
+    let delivered = rt.block_on(replayed.receive_all());
+    assert!(delivered.iter().any(|(_, _, p)| p.0 == 42));
+
+    let _ = std::fs::remove_file(capture);
+}
+
+#[test]
+fn corrupt_capture_is_an_error_not_an_end(){
+    let capture = std::env::temp_dir().join(format!("qserver-corrupt-{}.qcap", std::process::id()));
+    let loopback: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let server = LocalServer::new(Some(loopback), true, None, None);
+    server.start_capture(&capture).unwrap();
+    let _station: Station<Payload> = Station::new(server.clone(), CHANNEL, None);
+    sleep(Duration::from_millis(200));
+    server.stop_capture();
+    server.shutdown();
+
+    // A record that claims more bytes than the file holds
+    let mut bytes = std::fs::read(&capture).unwrap();
+    bytes.extend_from_slice(&1000u32.to_le_bytes());
+    bytes.extend_from_slice(&[0; 10]);
+    std::fs::write(&capture, bytes).unwrap();
+
+    let records: Vec<_> = CaptureReader::open(&capture).unwrap().collect();
+    assert!(records.last().unwrap().is_err());
+    assert!(records[..records.len() - 1].iter().all(|r| r.is_ok()));
+    let _ = std::fs::remove_file(capture);
+}
@@ -0,0 +1,47 @@
+use std::{net::SocketAddr, time::Duration};
+
+use qserver::{LocalServer, ReplicatedStore, Station, StationOperable};
+
+const CHANNEL: u32 = 12;
+const REPLICA_ID: u64 = 0x4e11;
+
+/// Whatever bytes it was made from, sent as they are
+struct Junk(Vec<u8>);
+
+impl StationOperable for Junk{
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.clone()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        Junk(bytes.to_vec())
+    }
+}
+
+#[test]
+fn junk_sent_to_a_replica_is_dropped(){
+    let loopback: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let server = LocalServer::new(Some(loopback), true, None, None);
+    let rt = server.get_runtime();
+    let mut replica: ReplicatedStore<u64> = ReplicatedStore::new(Station::new(server.clone(), CHANNEL, Some(REPLICA_ID)));
+    let mut writer: ReplicatedStore<u64> = ReplicatedStore::new(Station::new(server.clone(), CHANNEL, None));
+    let mut junk: Station<Junk> = Station::new(server.clone(), CHANNEL, None);
+    rt.block_on(async {
+        // Every station has to have heard the others' pings before it can address them
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        replica.process().await;
+        writer.process().await;
+        junk.receive_all().await;
+        for bytes in [vec![], vec![0xff; 32], vec![7]]{
+            junk.send(REPLICA_ID, true, &Junk(bytes)).await.unwrap();
+        }
+        writer.put("survived", &1).await;
+        // The replica drops the junk and still takes in the write that follows it
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(2);
+        while replica.get("survived").await.is_none() && tokio::time::Instant::now() < deadline{
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(replica.get("survived").await, Some(1));
+    });
+    server.shutdown();
+}
@@ -0,0 +1,81 @@
+use std::{net::SocketAddr, time::Duration};
+
+use qserver::{LocalServer, Station};
+
+mod common;
+use common::Payload;
+
+const CHANNEL: u32 = 9;
+const LISTENER_ID: u64 = 0x1157;
+const PINGERS: usize = 20;
+
+#[test]
+fn listen_raced_against_a_timer_loses_nothing(){
+    let loopback: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let server = LocalServer::new(Some(loopback), true, None, None);
+    let rt = server.get_runtime();
+    let mut listener: Station<Payload> = Station::new(server.clone(), CHANNEL, Some(LISTENER_ID));
+
+    // Every new station pings the listener, which only answers while it listens
+    let pingers: Vec<Station<Payload>> = (0..PINGERS).map(|_| Station::new(server.clone(), CHANNEL, None)).collect();
+    let mut delivered = vec![];
+    rt.block_on(async {
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(2);
+        while tokio::time::Instant::now() < deadline{
+            tokio::select!{
+                message = listener.listen() => delivered.extend(message.map(|(_, _, p)| p.0)),
+                _ = tokio::time::sleep(Duration::from_micros(100)) => {},
+            }
+        }
+    });
+
+    // Every pinger heard back from the listener
+    let mut pingers = pingers;
+    for (index, pinger) in pingers.iter_mut().enumerate(){
+        rt.block_on(pinger.receive_all());
+        assert!(pinger.known_stations().contains(&LISTENER_ID), "Pinger {} never heard back", index);
+    }
+
+    // And every message sent while the listener keeps getting interrupted arrives
+    let mut sender = pingers.pop().unwrap();
+    rt.spawn(async move {
+        for n in 0..50{
+            sender.send(LISTENER_ID, true, &Payload(n)).await.unwrap();
+        }
+    });
+    rt.block_on(async {
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        while delivered.len() < 50 && tokio::time::Instant::now() < deadline{
+            tokio::select!{
+                message = listener.listen() => delivered.extend(message.map(|(_, _, p)| p.0)),
+                _ = tokio::time::sleep(Duration::from_micros(100)) => {},
+            }
+        }
+    });
+    // Exchanges run side by side so only the set of messages is certain, not their order
+    delivered.sort_unstable();
+    assert_eq!(delivered, (0..50).collect::<Vec<u64>>());
+}
+
+#[test]
+fn listen_returns_messages_a_send_already_queued(){
+    let loopback: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let server = LocalServer::new(Some(loopback), true, None, None);
+    let rt = server.get_runtime();
+    let mut listener: Station<Payload> = Station::new(server.clone(), CHANNEL, Some(LISTENER_ID));
+    let mut sender: Station<Payload> = Station::new(server.clone(), CHANNEL, None);
+    rt.block_on(async {
+        // Both have to know each other, the listener learns of the sender from its ping
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        listener.receive_all().await;
+        sender.receive_all().await;
+        sender.send(LISTENER_ID, true, &Payload(7)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // Sending takes in the listener's intake, which queues the message from the sender
+        listener.send(sender.id(), true, &Payload(8)).await.unwrap();
+        // Nothing else is coming, so the queued message is all there is to return
+        let message = tokio::time::timeout(Duration::from_secs(1), listener.listen()).await;
+        assert!(matches!(message, Ok(Some((_, _, Payload(7))))), "The queued message was never returned");
+    });
+    server.shutdown();
+}
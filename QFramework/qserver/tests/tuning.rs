@@ -0,0 +1,35 @@
+use std::net::SocketAddr;
+
+use qserver::{LocalServer, Tuning, TuningError};
+
+#[test]
+fn default_tuning_is_valid(){
+    assert_eq!(Tuning::default().check(), Ok(()));
+}
+
+#[test]
+fn zero_budgets_and_intervals_are_refused(){
+    let zeroed: [(&str, Tuning); 6] = [
+        ("keep_alive_interval", Tuning{ keep_alive_interval: 0, ..Tuning::default() }),
+        ("keep_alive_budget", Tuning{ keep_alive_budget: 0, ..Tuning::default() }),
+        ("send_timeout", Tuning{ send_timeout: 0, ..Tuning::default() }),
+        ("send_timeout_cycles", Tuning{ send_timeout_cycles: 0, ..Tuning::default() }),
+        ("receive_timeout", Tuning{ receive_timeout: 0, ..Tuning::default() }),
+        ("receive_timeout_cycles", Tuning{ receive_timeout_cycles: 0, ..Tuning::default() })];
+    for (field, tuning) in zeroed{
+        assert_eq!(tuning.check(), Err(TuningError{ field }));
+    }
+    // Leaving without draining is fine
+    assert_eq!(Tuning{ drain_timeout: 0, message_complete_timeout: 0, ..Tuning::default() }.check(), Ok(()));
+}
+
+#[test]
+fn refused_tuning_leaves_the_server_as_it_was(){
+    let loopback: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let server = LocalServer::new(Some(loopback), true, None, None);
+    let fast = Tuning{ send_timeout: 20, ..Tuning::default() };
+    server.set_tuning(fast).unwrap();
+    assert!(server.set_tuning(Tuning{ send_timeout_cycles: 0, ..fast }).is_err());
+    assert_eq!(server.tuning(), fast);
+    server.shutdown();
+}
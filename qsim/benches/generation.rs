@@ -0,0 +1,25 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use quniverse::{Galaxy, ProceduralGenerationSettings};
+use rayon::ThreadPoolBuilder;
+
+const SYSTEM_COUNT: usize = 1_000_000;
+
+fn generation(c: &mut Criterion) {
+    let settings = ProceduralGenerationSettings::default().with_system_count(SYSTEM_COUNT);
+    let threadpool = ThreadPoolBuilder::new().build().unwrap();
+    let mut group = c.benchmark_group("galaxy_generation");
+    //A million systems takes about a second per run serially, so keep the sample count at the minimum
+    group.sample_size(10);
+    group.bench_with_input(BenchmarkId::new("serial", SYSTEM_COUNT), &settings, |b, settings| {
+        b.iter(|| Galaxy::generate(settings))
+    });
+    group.bench_with_input(
+        BenchmarkId::new("parallel", SYSTEM_COUNT),
+        &settings,
+        |b, settings| b.iter(|| threadpool.install(|| Galaxy::generate_parallel(settings))),
+    );
+    group.finish();
+}
+
+criterion_group!(benches, generation);
+criterion_main!(benches);
@@ -1,5 +1,5 @@
 use plotters::{prelude::*, style::colors};
-use std::net::SocketAddr;
+use std::{f32::consts::TAU, net::SocketAddr, sync::Arc};
 
 use glam;
 use qforce::{
@@ -7,22 +7,87 @@ use qforce::{
     data::Vector,
     engine::{self, Engine},
 };
-use qserver::{self, ClusterTerminal};
+use qserver::{self, LocalServer};
 use qvk::{self, init::Initializer};
 use rand::{Rng, SeedableRng};
 use rand_xoshiro::Xoshiro256Plus;
 use rayon::{self, prelude::*, vec, ThreadPool, ThreadPoolBuilder};
 use tokio::{self, runtime::Runtime};
 
+mod morphology;
+mod octree;
+mod query;
+mod save;
+mod stellar;
+
+pub use morphology::Morphology;
+pub use octree::{Frustum, Octree};
+pub use query::{SystemId, SystemQuery};
+pub use save::{SaveError, SaveHeader, SystemReader, SAVE_VERSION};
+pub use stellar::SpectralClass;
+use stellar::{Planet, Star};
+
+//The seed galaxies are generated from unless another is asked for
+const DEFAULT_GALAXY_SEED: u64 = 1;
+//The increment of the SplitMix64 generator, used to spread system indices over the seed space
+const SEED_GAMMA: u64 = 0x9E37_79B9_7F4A_7C15;
+//How many units of system space make up an AU
+const SYSTEM_UNITS_PER_AU: f32 = 10.0;
+
 #[derive(Clone)]
 pub struct ProceduralGenerationSettings {
+    //Every system gets its own seed derived from this and its index
+    seed: u64,
     galaxy_gen: ProceduralGalaxyGenSettings,
 }
 impl Default for ProceduralGenerationSettings {
     fn default() -> Self {
         let galaxy_gen = ProceduralGalaxyGenSettings::default();
-        ProceduralGenerationSettings { galaxy_gen }
+        ProceduralGenerationSettings {
+            seed: DEFAULT_GALAXY_SEED,
+            galaxy_gen,
+        }
+    }
+}
+impl ProceduralGenerationSettings {
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+    pub fn seed(&self) -> u64 {
+        self.seed
     }
+    pub fn with_system_count(mut self, system_count: usize) -> Self {
+        self.galaxy_gen.system_count = system_count;
+        self
+    }
+    pub fn system_count(&self) -> usize {
+        self.galaxy_gen.system_count
+    }
+    pub fn with_morphology(mut self, morphology: Morphology) -> Self {
+        self.galaxy_gen.morphology = morphology;
+        self
+    }
+    pub fn morphology(&self) -> Morphology {
+        self.galaxy_gen.morphology
+    }
+    //Generates the system at `index` on its own
+    //A system only depends on the seed, its index and the settings so every node
+    //generating the same index gets the same system
+    pub fn generate_system(&self, index: usize) -> PlanetarySystem {
+        let mut rng = Xoshiro256Plus::seed_from_u64(system_seed(self.seed, index as u64));
+        PlanetarySystem::generate(&self.galaxy_gen, self.seed, &mut rng)
+    }
+}
+
+//The seed of the system at `index`
+//This is the SplitMix64 output at position `index` of the stream started at the galaxy seed,
+//so neighbouring systems get unrelated seeds
+pub fn system_seed(galaxy_seed: u64, index: u64) -> u64 {
+    let mut z = galaxy_seed.wrapping_add(index.wrapping_add(1).wrapping_mul(SEED_GAMMA));
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
 }
 
 //Idea for client-cluster cluster-cluster communication
@@ -42,41 +107,81 @@ impl Default for ProceduralGenerationSettings {
 //to change about the order.
 
 pub struct Universe {
-    rt: Runtime,
+    rt: Arc<Runtime>,
     engine: Engine<Initializer>,
     threadpool: ThreadPool,
-    udp_server: ClusterTerminal,
+    //Runs on the universe runtime
+    udp_server: Arc<LocalServer>,
     rng: Xoshiro256Plus,
+    //What the galaxy was generated from, kept so a save can regenerate it
+    settings: ProceduralGenerationSettings,
     galaxy: Galaxy,
 }
 impl Universe {
-    pub fn load(host_addr: SocketAddr, save_file: Option<String>) -> Universe {
-        let rt = tokio::runtime::Builder::new_multi_thread()
-            .enable_all()
+    //Loads the galaxy from `save_file`, or generates the default one when there is none
+    pub fn load(host_addr: SocketAddr, save_file: Option<String>) -> Result<Universe, SaveError> {
+        let threadpool = Self::build_threadpool();
+        let (settings, galaxy) = match save_file {
+            Some(path) => {
+                let (header, galaxy) = Galaxy::load_file(&path)?;
+                (header.settings, galaxy)
+            }
+            None => {
+                let settings = ProceduralGenerationSettings::default();
+                let galaxy = threadpool.install(|| Galaxy::generate_parallel(&settings));
+                (settings, galaxy)
+            }
+        };
+        Ok(Self::start(host_addr, threadpool, settings, galaxy))
+    }
+    //Generates a new galaxy from `settings` on the universe threadpool
+    pub fn generate(host_addr: SocketAddr, settings: ProceduralGenerationSettings) -> Universe {
+        let threadpool = Self::build_threadpool();
+        let galaxy = threadpool.install(|| Galaxy::generate_parallel(&settings));
+        Self::start(host_addr, threadpool, settings, galaxy)
+    }
+    fn build_threadpool() -> ThreadPool {
+        ThreadPoolBuilder::new()
             .build()
-            .expect("Could not start tokio runtime");
+            .expect("Could not start rayon threadpool")
+    }
+    fn start(
+        host_addr: SocketAddr,
+        threadpool: ThreadPool,
+        settings: ProceduralGenerationSettings,
+        galaxy: Galaxy,
+    ) -> Universe {
+        let rt = Arc::new(
+            tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .expect("Could not start tokio runtime"),
+        );
         let engine = engine::new();
-        let threadpool = ThreadPoolBuilder::new()
-            .build()
-            .expect("Could not start rayon threadpool");
-        let udp_server = ClusterTerminal::new(host_addr, true);
-        let mut rng = Xoshiro256Plus::seed_from_u64(1);
-        let galaxy = match save_file {
-            Some(n) => todo!(),
-            None => Self::generate_universe(1000000, &mut rng),
-        };
+        let udp_server = LocalServer::new(Some(host_addr), true, Some(rt.clone()), None);
+        let rng = Xoshiro256Plus::seed_from_u64(settings.seed);
         Universe {
             rt,
             engine,
             threadpool,
             udp_server,
             rng,
+            settings,
             galaxy,
         }
     }
-    fn generate_universe<R: Rng>(system_count: usize, rng: &mut R) -> Galaxy {
-        let settings = ProceduralGenerationSettings::default();
-        Galaxy::generate(&settings.galaxy_gen, rng)
+    //Writes the galaxy and what it was generated from to `path`
+    pub fn save<P: AsRef<std::path::Path>>(&self, path: P) -> Result<(), SaveError> {
+        self.galaxy.save_file(&self.settings, path)
+    }
+    //Generates the system `id` again from the settings, without touching the galaxy
+    //Every save that can be loaded was generated one seed per system, so this matches the loaded system
+    pub fn regenerate_system(&self, id: SystemId) -> Option<PlanetarySystem> {
+        let index = id.index() as usize;
+        if index >= self.galaxy.plantary_systems.len() {
+            return None;
+        }
+        Some(self.settings.generate_system(index))
     }
     pub fn plot_galaxy(&self) {
         self.galaxy.plot_galaxy();
@@ -92,21 +197,32 @@ pub struct ProceduralGalaxyGenSettings {
     planetary_system_max_size: f32,
     planetary_system_max_cb_count: usize,
     co_max_size: f32,
+    //Where in the galaxy systems end up
+    morphology: Morphology,
 }
 //These not only systems, but also celstial bodies
 #[derive(Clone)]
-struct PlanetarySystem {
+pub struct PlanetarySystem {
     galaxy_pos: Vector,
     spatial_bound: f32,
+    //The star is main sequence, its size is in solar radii, mass and luminosity in solar units and temperature in kelvin
     star_size: f32,
     star_temp: f32,
+    star_mass: f32,
+    star_luminosity: f32,
+    //0-1, how habitable the best body of the system is
+    habitability: f32,
     c_bodies: Vec<CelestialBody>,
 }
 //These might be planets, moon, dwarf planets, comets. The only requirement is that we assume they are static. That is, they do not dynamically spawn and despawn
 //like an asteroid would. They are also big enough to render at far distances meaning upon
 #[derive(Clone)]
-struct CelestialBody {
+pub struct CelestialBody {
+    //The star sits at the centre of the system cube
     system_pos: Vector,
+    //In AU
+    orbital_radius: f32,
+    //In earth radii
     size: f32,
     //0-1 where 0 is none and 1 is Venus like. 0.5 is Earth like
     atmosphere_quality: f32,
@@ -117,23 +233,44 @@ struct CelestialBody {
 //Will handle galaxy loading/saving, procedual generation, spatial data structure generation, gpu memory placement, and descriptor generation
 //of static galaxy structure data
 #[derive(Clone)]
-struct Galaxy {
+pub struct Galaxy {
     bound: f32,
     plantary_systems: Vec<PlanetarySystem>,
+    //Over galaxy_pos, systems are referred to by their index
+    octree: Octree,
 }
 
 //Galaxy Structure impl block
 impl Galaxy {
-    pub fn generate<R: Rng>(settings: &ProceduralGalaxyGenSettings, rng: &mut R) -> Galaxy {
-        let mut psystems = vec![PlanetarySystem::generate(settings, rng); settings.system_count];
-        for system in psystems.iter_mut() {
-            *system = PlanetarySystem::generate(settings, rng);
-        }
+    fn new(bound: f32, plantary_systems: Vec<PlanetarySystem>) -> Galaxy {
+        let positions = plantary_systems.iter().map(|ps| ps.position()).collect();
         Galaxy {
-            bound: settings.galaxy_max_size,
-            plantary_systems: psystems,
+            bound,
+            plantary_systems,
+            octree: Octree::build(positions),
         }
     }
+    pub fn octree(&self) -> &Octree {
+        &self.octree
+    }
+    pub fn system(&self, index: usize) -> Option<&PlanetarySystem> {
+        self.plantary_systems.get(index)
+    }
+    pub fn generate(settings: &ProceduralGenerationSettings) -> Galaxy {
+        let psystems = (0..settings.galaxy_gen.system_count)
+            .map(|index| settings.generate_system(index))
+            .collect();
+        Galaxy::new(settings.galaxy_gen.galaxy_max_size, psystems)
+    }
+    //Same as generate but spread over the current rayon pool, run it inside `ThreadPool::install` to pick the pool
+    //Systems only depend on their own seed and collect keeps the index order, so the galaxy is identical to a serial one
+    pub fn generate_parallel(settings: &ProceduralGenerationSettings) -> Galaxy {
+        let psystems = (0..settings.galaxy_gen.system_count)
+            .into_par_iter()
+            .map(|index| settings.generate_system(index))
+            .collect();
+        Galaxy::new(settings.galaxy_gen.galaxy_max_size, psystems)
+    }
     pub fn plot_galaxy(&self) {
         let area = BitMapBackend::gif("plot.gif", (1000, 1000), 10)
             .unwrap()
@@ -144,7 +281,6 @@ impl Galaxy {
         let z_axis = (0.0..self.bound).step(0.1);
 
         let mut chart = ChartBuilder::on(&area)
-            .caption(format!("Galaxy"), ("sans", 29))
             .build_cartesian_3d(x_axis.clone(), y_axis.clone(), z_axis.clone())
             .unwrap();
         chart
@@ -163,7 +299,6 @@ impl Galaxy {
         for pitch in 0..1570 {
             area.fill(&BLACK).unwrap();
             let mut chart = ChartBuilder::on(&area)
-                .caption(format!("Galaxy"), ("sans", 29))
                 .build_cartesian_3d(x_axis.clone(), y_axis.clone(), z_axis.clone())
                 .unwrap();
             chart.with_projection(|mut p| {
@@ -184,36 +319,97 @@ impl Galaxy {
     }
 }
 impl PlanetarySystem {
-    fn generate<R: Rng>(settings: &ProceduralGalaxyGenSettings, rng: &mut R) -> PlanetarySystem {
-        let spatial_bound = rng.gen_range(100.0..=1000.0);
-        let mut c_bodies = vec![
-            CelestialBody::generate(settings, spatial_bound, rng);
-            rng.gen_range(1..=settings.planetary_system_max_cb_count)
-        ];
-        for co in c_bodies.iter_mut() {
-            *co = CelestialBody::generate(settings, spatial_bound, rng);
+    fn generate<R: Rng>(settings: &ProceduralGalaxyGenSettings, galaxy_seed: u64, rng: &mut R) -> PlanetarySystem {
+        let galaxy_pos = settings.morphology.place(settings.galaxy_max_size, galaxy_seed, rng);
+        let star = Star::generate(rng);
+        //Orbits are spaced roughly geometrically, starting inside the habitable zone
+        //Whatever would not fit in the system is never formed, so bright stars can end up with no planets
+        let max_orbit = settings.planetary_system_max_size / 2.0 / SYSTEM_UNITS_PER_AU;
+        let cb_count = rng.gen_range(1..=settings.planetary_system_max_cb_count);
+        let mut orbit = star.habitable_zone().0 * rng.gen_range(0.1..0.6);
+        let mut orbits = Vec::with_capacity(cb_count);
+        while orbits.len() < cb_count && orbit <= max_orbit {
+            orbits.push(orbit);
+            orbit *= rng.gen_range(1.4..2.2);
         }
+        let outermost = orbits.last().copied().unwrap_or(0.0);
+        let spatial_bound = (outermost * SYSTEM_UNITS_PER_AU * 2.2).clamp(1.0, settings.planetary_system_max_size);
+        let mut habitability: f32 = 0.0;
+        let c_bodies = orbits
+            .into_iter()
+            .map(|orbit| {
+                let (body, score) = CelestialBody::generate(&star, orbit, spatial_bound, rng);
+                habitability = habitability.max(score);
+                body
+            })
+            .collect();
         PlanetarySystem {
-            galaxy_pos: Vector::random_cube(settings.galaxy_max_size, rng),
+            galaxy_pos,
             spatial_bound,
-            star_size: rng.gen_range(1.0..=10.0),
-            star_temp: rng.gen_range(1000.0..=10000.0),
+            star_size: star.radius,
+            star_temp: star.temperature,
+            star_mass: star.mass,
+            star_luminosity: star.luminosity,
+            habitability,
             c_bodies,
         }
     }
+    pub fn position(&self) -> glam::Vec3 {
+        glam::Vec3::new(self.galaxy_pos.x, self.galaxy_pos.y, self.galaxy_pos.z)
+    }
+    pub fn spectral_class(&self) -> SpectralClass {
+        SpectralClass::from_temperature(self.star_temp)
+    }
+    pub fn star_temperature(&self) -> f32 {
+        self.star_temp
+    }
+    pub fn star_mass(&self) -> f32 {
+        self.star_mass
+    }
+    pub fn star_luminosity(&self) -> f32 {
+        self.star_luminosity
+    }
+    pub fn habitability(&self) -> f32 {
+        self.habitability
+    }
+    pub fn bodies(&self) -> &[CelestialBody] {
+        &self.c_bodies
+    }
 }
 impl CelestialBody {
-    fn generate<R: Rng>(
-        settings: &ProceduralGalaxyGenSettings,
-        spatial_bound: f32,
-        rng: &mut R,
-    ) -> CelestialBody {
-        CelestialBody {
-            system_pos: Vector::random_cube(spatial_bound, rng),
-            size: rng.gen_range(10.0..=100.0),
-            atmosphere_quality: rng.gen_range(0.0..1.0),
-            water_content: rng.gen_range(0.0..1.0),
-        }
+    //Returns the body along with how habitable it is
+    fn generate<R: Rng>(star: &Star, orbit: f32, spatial_bound: f32, rng: &mut R) -> (CelestialBody, f32) {
+        let planet = Planet::generate(star, orbit, rng);
+        let habitability = planet.habitability(star, orbit);
+        //Orbits are nearly coplanar
+        let angle = rng.gen_range(0.0..TAU);
+        let inclination: f32 = rng.gen_range(-0.05..0.05);
+        let distance = orbit * SYSTEM_UNITS_PER_AU;
+        let centre = spatial_bound / 2.0;
+        let body = CelestialBody {
+            system_pos: Vector {
+                x: centre + distance * angle.cos() * inclination.cos(),
+                y: centre + distance * angle.sin() * inclination.cos(),
+                z: centre + distance * inclination.sin(),
+            },
+            orbital_radius: orbit,
+            size: planet.radius,
+            atmosphere_quality: planet.atmosphere_quality,
+            water_content: planet.water_content,
+        };
+        (body, habitability)
+    }
+    pub fn orbital_radius(&self) -> f32 {
+        self.orbital_radius
+    }
+    pub fn size(&self) -> f32 {
+        self.size
+    }
+    pub fn atmosphere_quality(&self) -> f32 {
+        self.atmosphere_quality
+    }
+    pub fn water_content(&self) -> f32 {
+        self.water_content
     }
 }
 impl Default for ProceduralGalaxyGenSettings {
@@ -225,6 +421,77 @@ impl Default for ProceduralGalaxyGenSettings {
             planetary_system_max_size: 1000.0,
             planetary_system_max_cb_count: 10,
             co_max_size: 100.0,
+            morphology: Morphology::default(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_same_vector(a: &Vector, b: &Vector) {
+        assert_eq!((a.x, a.y, a.z), (b.x, b.y, b.z));
+    }
+    //Compares every field, systems have to match bit for bit
+    pub(crate) fn assert_same_system(a: &PlanetarySystem, b: &PlanetarySystem) {
+        assert_same_vector(&a.galaxy_pos, &b.galaxy_pos);
+        assert_eq!(a.spatial_bound, b.spatial_bound);
+        assert_eq!(a.star_size, b.star_size);
+        assert_eq!(a.star_temp, b.star_temp);
+        assert_eq!(a.star_mass, b.star_mass);
+        assert_eq!(a.star_luminosity, b.star_luminosity);
+        assert_eq!(a.habitability, b.habitability);
+        assert_eq!(a.c_bodies.len(), b.c_bodies.len());
+        for (a, b) in a.c_bodies.iter().zip(b.c_bodies.iter()) {
+            assert_same_vector(&a.system_pos, &b.system_pos);
+            assert_eq!(a.orbital_radius, b.orbital_radius);
+            assert_eq!(a.size, b.size);
+            assert_eq!(a.atmosphere_quality, b.atmosphere_quality);
+            assert_eq!(a.water_content, b.water_content);
+        }
+    }
+    pub(crate) fn assert_same_galaxy(a: &Galaxy, b: &Galaxy) {
+        assert_eq!(a.bound, b.bound);
+        assert_eq!(a.plantary_systems.len(), b.plantary_systems.len());
+        for (a, b) in a.plantary_systems.iter().zip(b.plantary_systems.iter()) {
+            assert_same_system(a, b);
+        }
+        assert_eq!(a.octree.len(), b.octree.len());
+        for index in 0..a.octree.len() {
+            assert_eq!(a.octree.position(index), b.octree.position(index));
+        }
+    }
+
+    #[test]
+    fn system_generated_alone_matches_the_galaxy() {
+        let settings = ProceduralGenerationSettings::default().with_seed(11).with_system_count(200);
+        let galaxy = Galaxy::generate(&settings);
+        for index in [0, 1, 99, 199] {
+            assert_same_system(&settings.generate_system(index), galaxy.system(index).unwrap());
+        }
+    }
+    #[test]
+    fn parallel_generation_matches_serial() {
+        let settings = ProceduralGenerationSettings::default().with_seed(5).with_system_count(5000);
+        let threadpool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        let parallel = threadpool.install(|| {
+            assert!(rayon::current_num_threads() > 1);
+            Galaxy::generate_parallel(&settings)
+        });
+        assert_same_galaxy(&Galaxy::generate(&settings), &parallel);
+    }
+    #[test]
+    fn different_seeds_give_different_galaxies() {
+        let settings = ProceduralGenerationSettings::default().with_system_count(200);
+        let a = Galaxy::generate(&settings.clone().with_seed(1));
+        let b = Galaxy::generate(&settings.with_seed(2));
+        let same = a
+            .plantary_systems
+            .iter()
+            .zip(b.plantary_systems.iter())
+            .filter(|(a, b)| a.position() == b.position() && a.star_mass == b.star_mass)
+            .count();
+        assert_eq!(same, 0);
+    }
+}
@@ -0,0 +1,208 @@
+use std::f32::consts::TAU;
+
+use qforce::data::Vector;
+use rand::{Rng, SeedableRng};
+use rand_distr::{Distribution, StandardNormal, UnitSphere};
+use rand_xoshiro::Xoshiro256Plus;
+
+use crate::system_seed;
+
+//Every morphology is centred in the galaxy cube and fits in the sphere touching its faces
+//Sizes below are fractions of that radius
+
+//Share of a spiral's systems that sit in the bulge rather than the disk
+const BULGE_FRACTION: f32 = 0.2;
+//The bulge is a slightly flattened sphere
+const BULGE_FLATTENING: f32 = 0.6;
+//Exponential scale length of a spiral disk
+const DISK_SCALE_LENGTH: f32 = 0.3;
+//Scale height of a disk at its centre, the disk flares to twice this at its edge
+const DISK_SCALE_HEIGHT: f32 = 0.02;
+//Share of the disk systems spread between the arms instead of along them
+const INTERARM_FRACTION: f32 = 0.3;
+//How far in radians a system strays from the centre line of its arm
+const ARM_SPREAD: f32 = 0.3;
+//Hernquist scale radius of an elliptical
+const ELLIPTICAL_SCALE_RADIUS: f32 = 0.15;
+//Clumps of an irregular sit within this radius and are this big
+const CLUMP_REACH: f32 = 0.6;
+const CLUMP_MIN_SIZE: f32 = 0.08;
+const CLUMP_MAX_SIZE: f32 = 0.25;
+//Irregulars are roughly half as thick as they are wide
+const IRREGULAR_FLATTENING: f32 = 0.5;
+//Mixed into the galaxy seed so clump seeds never match system seeds
+const CLUMP_SEED_SALT: u64 = 0x636C_756D_7073_0000;
+
+//The overall shape of a galaxy, deciding where its systems are placed
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Morphology {
+    //A bulge surrounded by a thin disk with logarithmic spiral arms
+    //Disk density falls off exponentially with radius and the disk thickens towards its edge
+    Spiral {
+        arms: u32,
+        //Angle between an arm and a circle around the centre, in degrees
+        pitch_angle: f32,
+        //As a fraction of the galaxy radius
+        bulge_radius: f32,
+    },
+    //A featureless ellipsoid with a Hernquist density profile, densest at the centre
+    //0 is a sphere, 0.7 is about the flattest ellipticals get
+    Elliptical { ellipticity: f32 },
+    //Loose gaussian clumps of systems scattered around the centre
+    Irregular { clumps: u32 },
+    //Systems spread evenly over the whole galaxy cube
+    //Galaxies generated before there were morphologies were uniform cubes, so the default stays one
+    #[default]
+    UniformCube,
+}
+
+impl Morphology {
+    //Places a system in a galaxy cube of side `bound`
+    //The galaxy seed is needed by shapes that are the same for every system, like the clumps of an irregular
+    pub(crate) fn place<R: Rng>(&self, bound: f32, galaxy_seed: u64, rng: &mut R) -> Vector {
+        let radius = bound / 2.0;
+        let (x, y, z) = match *self {
+            Morphology::Spiral {
+                arms,
+                pitch_angle,
+                bulge_radius,
+            } => {
+                if rng.gen::<f32>() < BULGE_FRACTION {
+                    Self::bulge(bulge_radius.clamp(0.01, 1.0), rng)
+                } else {
+                    Self::disk(arms.max(1), pitch_angle.clamp(1.0, 89.0), bulge_radius.clamp(0.01, 1.0), rng)
+                }
+            }
+            Morphology::Elliptical { ellipticity } => Self::elliptical(ellipticity.clamp(0.0, 0.9), rng),
+            Morphology::Irregular { clumps } => Self::irregular(clumps.max(1), galaxy_seed, rng),
+            Morphology::UniformCube => return Vector::random_cube(bound, rng),
+        };
+        //Tails of the profiles can reach past the galaxy, those systems are pulled back onto its edge
+        let place = |v: f32| (radius + v * radius).clamp(0.0, bound);
+        Vector {
+            x: place(x),
+            y: place(y),
+            z: place(z),
+        }
+    }
+
+    fn bulge<R: Rng>(bulge_radius: f32, rng: &mut R) -> (f32, f32, f32) {
+        let r = truncated_exponential(bulge_radius / 3.0, bulge_radius, rng);
+        let [x, y, z]: [f32; 3] = UnitSphere.sample(rng);
+        (x * r, y * r, z * r * BULGE_FLATTENING)
+    }
+    fn disk<R: Rng>(arms: u32, pitch_angle: f32, bulge_radius: f32, rng: &mut R) -> (f32, f32, f32) {
+        let r = truncated_exponential(DISK_SCALE_LENGTH, 1.0, rng);
+        let angle = if rng.gen::<f32>() < INTERARM_FRACTION {
+            rng.gen_range(0.0..TAU)
+        } else {
+            //A logarithmic spiral starting at the edge of the bulge
+            let arm = rng.gen_range(0..arms) as f32 * TAU / arms as f32;
+            let winding = (r.max(bulge_radius) / bulge_radius).ln() / pitch_angle.to_radians().tan();
+            let spread: f32 = StandardNormal.sample(rng);
+            arm + winding + spread * ARM_SPREAD
+        };
+        let height = laplace(DISK_SCALE_HEIGHT * (1.0 + r), rng);
+        (r * angle.cos(), r * angle.sin(), height)
+    }
+    fn elliptical<R: Rng>(ellipticity: f32, rng: &mut R) -> (f32, f32, f32) {
+        //Inverse of the Hernquist mass profile, cut off at the galaxy radius
+        let a = ELLIPTICAL_SCALE_RADIUS;
+        let max = (1.0 / (1.0 + a)).powi(2);
+        let m = rng.gen_range(0.0..max).sqrt();
+        let r = a * m / (1.0 - m);
+        let [x, y, z]: [f32; 3] = UnitSphere.sample(rng);
+        (x * r, y * r * (1.0 - ellipticity / 2.0), z * r * (1.0 - ellipticity))
+    }
+    fn irregular<R: Rng>(clumps: u32, galaxy_seed: u64, rng: &mut R) -> (f32, f32, f32) {
+        //Only the picked clump is rebuilt, from its own seed, so every system agrees on where it is
+        let clump = rng.gen_range(0..clumps) as u64;
+        let mut clump_rng = Xoshiro256Plus::seed_from_u64(system_seed(galaxy_seed ^ CLUMP_SEED_SALT, clump));
+        let reach = CLUMP_REACH * clump_rng.gen::<f32>().cbrt();
+        let [cx, cy, cz]: [f32; 3] = UnitSphere.sample(&mut clump_rng);
+        let size = clump_rng.gen_range(CLUMP_MIN_SIZE..=CLUMP_MAX_SIZE);
+        let mut offset = || -> f32 { StandardNormal.sample(rng) };
+        (
+            cx * reach + offset() * size,
+            cy * reach + offset() * size,
+            (cz * reach + offset() * size) * IRREGULAR_FLATTENING,
+        )
+    }
+}
+
+//A radius below `max` from an exponential profile with scale length `scale`
+fn truncated_exponential<R: Rng>(scale: f32, max: f32, rng: &mut R) -> f32 {
+    let cut = 1.0 - (-max / scale).exp();
+    -scale * (1.0 - rng.gen::<f32>() * cut).ln()
+}
+//A height from a double exponential profile with scale height `scale`
+fn laplace<R: Rng>(scale: f32, rng: &mut R) -> f32 {
+    let u: f32 = rng.gen_range(-0.5..0.5);
+    -scale * u.signum() * (1.0 - 2.0 * u.abs()).ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOUND: f32 = 1000.0;
+    const PLACEMENTS: usize = 20_000;
+
+    //Every shape, including settings far outside what makes sense
+    fn morphologies() -> Vec<Morphology> {
+        vec![
+            Morphology::Spiral {
+                arms: 2,
+                pitch_angle: 12.0,
+                bulge_radius: 0.15,
+            },
+            Morphology::Spiral {
+                arms: 0,
+                pitch_angle: 0.0,
+                bulge_radius: 0.0,
+            },
+            Morphology::Spiral {
+                arms: 1000,
+                pitch_angle: 90.0,
+                bulge_radius: 5.0,
+            },
+            Morphology::Elliptical { ellipticity: 0.0 },
+            Morphology::Elliptical { ellipticity: 0.7 },
+            Morphology::Elliptical { ellipticity: 5.0 },
+            Morphology::Irregular { clumps: 0 },
+            Morphology::Irregular { clumps: 5 },
+            Morphology::UniformCube,
+        ]
+    }
+    fn placements(morphology: Morphology, galaxy_seed: u64) -> Vec<(f32, f32, f32)> {
+        (0..PLACEMENTS)
+            .map(|index| {
+                let mut rng = Xoshiro256Plus::seed_from_u64(system_seed(galaxy_seed, index as u64));
+                let v = morphology.place(BOUND, galaxy_seed, &mut rng);
+                (v.x, v.y, v.z)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn default_is_a_uniform_cube() {
+        assert_eq!(Morphology::default(), Morphology::UniformCube);
+    }
+    #[test]
+    fn placements_stay_in_the_galaxy() {
+        for morphology in morphologies() {
+            for (x, y, z) in placements(morphology, 3) {
+                for v in [x, y, z] {
+                    assert!((0.0..=BOUND).contains(&v), "{:?} placed a system at {}", morphology, v);
+                }
+            }
+        }
+    }
+    #[test]
+    fn placements_are_deterministic_per_seed() {
+        for morphology in morphologies() {
+            assert_eq!(placements(morphology, 3), placements(morphology, 3), "{:?}", morphology);
+            assert_ne!(placements(morphology, 3), placements(morphology, 4), "{:?}", morphology);
+        }
+    }
+}
@@ -0,0 +1,403 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+};
+
+use glam::{Mat4, Vec3, Vec4};
+
+//Nodes with this many systems or fewer are not split any further
+const LEAF_SIZE: usize = 16;
+//Stops runaway splitting when many systems share a position
+const MAX_DEPTH: u32 = 20;
+const NO_CHILDREN: u32 = u32::MAX;
+
+//An octree over the positions of the systems in a galaxy
+//Systems are referred to by their index in the galaxy, the tree only keeps their positions
+#[derive(Clone)]
+pub struct Octree {
+    nodes: Vec<Node>,
+    //System indices, ordered so every node owns a contiguous range
+    order: Vec<u32>,
+    points: Vec<Vec3>,
+}
+
+#[derive(Clone)]
+struct Node {
+    centre: Vec3,
+    half: f32,
+    start: u32,
+    end: u32,
+    //Index of the first of eight consecutive children
+    children: u32,
+}
+
+//The six planes of a view frustum, normals pointing inwards
+#[derive(Clone, Copy, Debug)]
+pub struct Frustum {
+    planes: [Vec4; 6],
+}
+
+//How a volume relates to a node
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Overlap {
+    Outside,
+    Partial,
+    Inside,
+}
+
+impl Octree {
+    pub fn build(points: Vec<Vec3>) -> Octree {
+        let (min, max) = points.iter().fold(
+            (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+            |(min, max), p| (min.min(*p), max.max(*p)),
+        );
+        let (centre, half) = if points.is_empty() {
+            (Vec3::ZERO, 0.0)
+        } else {
+            ((min + max) / 2.0, ((max - min) / 2.0).max_element())
+        };
+        let mut octree = Octree {
+            nodes: vec![Node {
+                centre,
+                half,
+                start: 0,
+                end: points.len() as u32,
+                children: NO_CHILDREN,
+            }],
+            order: (0..points.len() as u32).collect(),
+            points,
+        };
+        octree.split(0, 0);
+        octree
+    }
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+    pub fn position(&self, system: usize) -> Option<Vec3> {
+        self.points.get(system).copied()
+    }
+    //Every system inside the axis aligned box from `min` to `max`
+    pub fn within_box(&self, min: Vec3, max: Vec3) -> Vec<usize> {
+        self.collect(
+            |lo, hi| {
+                if hi.cmplt(min).any() || lo.cmpgt(max).any() {
+                    Overlap::Outside
+                } else if lo.cmpge(min).all() && hi.cmple(max).all() {
+                    Overlap::Inside
+                } else {
+                    Overlap::Partial
+                }
+            },
+            |p| p.cmpge(min).all() && p.cmple(max).all(),
+        )
+    }
+    //Every system at most `radius` away from `centre`
+    pub fn within_sphere(&self, centre: Vec3, radius: f32) -> Vec<usize> {
+        let radius2 = radius * radius;
+        self.collect(
+            |lo, hi| {
+                let nearest = centre.clamp(lo, hi);
+                let farthest = (centre - lo).abs().max((centre - hi).abs());
+                if nearest.distance_squared(centre) > radius2 {
+                    Overlap::Outside
+                } else if farthest.length_squared() <= radius2 {
+                    Overlap::Inside
+                } else {
+                    Overlap::Partial
+                }
+            },
+            |p| p.distance_squared(centre) <= radius2,
+        )
+    }
+    //Every system inside the frustum, for culling before rendering
+    pub fn within_frustum(&self, frustum: &Frustum) -> Vec<usize> {
+        self.collect(|lo, hi| frustum.classify(lo, hi), |p| frustum.contains(p))
+    }
+    //The `k` systems closest to `point`, nearest first, with their distances
+    //Ties go to the lower index, so the result does not depend on how the tree was split
+    pub fn nearest(&self, point: Vec3, k: usize) -> Vec<(usize, f32)> {
+        if k == 0 || self.is_empty() {
+            return vec![];
+        }
+        //Nodes by how close they could possibly be, and the best k found so far with the worst on top
+        let mut queue = BinaryHeap::new();
+        let mut best: BinaryHeap<(Distance, u32)> = BinaryHeap::with_capacity(k + 1);
+        queue.push(Reverse((Distance(self.nodes[0].distance_squared(point)), 0)));
+        while let Some(Reverse((Distance(reach), node))) = queue.pop() {
+            if best.len() == k && best.peek().map_or(false, |(worst, _)| reach > worst.0) {
+                break;
+            }
+            let node = &self.nodes[node];
+            if node.children == NO_CHILDREN {
+                for &system in &self.order[node.start as usize..node.end as usize] {
+                    let distance = self.points[system as usize].distance_squared(point);
+                    if best.len() < k {
+                        best.push((Distance(distance), system));
+                    } else if best.peek().map_or(false, |worst| (Distance(distance), system) < *worst) {
+                        best.pop();
+                        best.push((Distance(distance), system));
+                    }
+                }
+            } else {
+                for child in node.children..node.children + 8 {
+                    let child_node = &self.nodes[child as usize];
+                    if child_node.start != child_node.end {
+                        queue.push(Reverse((Distance(child_node.distance_squared(point)), child as usize)));
+                    }
+                }
+            }
+        }
+        best.into_sorted_vec()
+            .into_iter()
+            .map(|(Distance(d), system)| (system as usize, d.sqrt()))
+            .collect()
+    }
+
+    fn split(&mut self, node: usize, depth: u32) {
+        let Node {
+            centre,
+            half,
+            start,
+            end,
+            ..
+        } = self.nodes[node];
+        if (end - start) as usize <= LEAF_SIZE || depth >= MAX_DEPTH {
+            return;
+        }
+        let points = &self.points;
+        let octant = |system: &u32| {
+            let p = points[*system as usize];
+            (p.x >= centre.x) as usize | ((p.y >= centre.y) as usize) << 1 | ((p.z >= centre.z) as usize) << 2
+        };
+        let range = &mut self.order[start as usize..end as usize];
+        range.sort_unstable_by_key(octant);
+        let mut counts = [0u32; 8];
+        for system in range.iter() {
+            counts[octant(system)] += 1;
+        }
+        let first = self.nodes.len();
+        let quarter = half / 2.0;
+        let mut child_start = start;
+        for (octant, count) in counts.iter().enumerate() {
+            let sign = |bit: usize| if octant & bit != 0 { quarter } else { -quarter };
+            self.nodes.push(Node {
+                centre: centre + Vec3::new(sign(1), sign(2), sign(4)),
+                half: quarter,
+                start: child_start,
+                end: child_start + count,
+                children: NO_CHILDREN,
+            });
+            child_start += count;
+        }
+        self.nodes[node].children = first as u32;
+        for child in first..first + 8 {
+            self.split(child, depth + 1);
+        }
+    }
+    //Walks the tree, taking whole nodes that are inside and testing single systems only in partial ones
+    fn collect<N, P>(&self, node_test: N, point_test: P) -> Vec<usize>
+    where
+        N: Fn(Vec3, Vec3) -> Overlap,
+        P: Fn(Vec3) -> bool,
+    {
+        let mut found = vec![];
+        if self.is_empty() {
+            return found;
+        }
+        let mut stack = vec![0];
+        while let Some(node) = stack.pop() {
+            let node: &Node = &self.nodes[node];
+            if node.start == node.end {
+                continue;
+            }
+            let range = &self.order[node.start as usize..node.end as usize];
+            match node_test(node.centre - node.half, node.centre + node.half) {
+                Overlap::Outside => {}
+                Overlap::Inside => found.extend(range.iter().map(|s| *s as usize)),
+                Overlap::Partial if node.children == NO_CHILDREN => found.extend(
+                    range
+                        .iter()
+                        .filter(|s| point_test(self.points[**s as usize]))
+                        .map(|s| *s as usize),
+                ),
+                Overlap::Partial => stack.extend(node.children as usize..node.children as usize + 8),
+            }
+        }
+        found
+    }
+}
+
+impl Node {
+    //Squared distance from `point` to the closest point of the node
+    fn distance_squared(&self, point: Vec3) -> f32 {
+        let offset = ((point - self.centre).abs() - self.half).max(Vec3::ZERO);
+        offset.length_squared()
+    }
+}
+
+impl Frustum {
+    //Each plane is (normal, distance) with the normal pointing into the frustum
+    pub fn from_planes(planes: [Vec4; 6]) -> Frustum {
+        Frustum {
+            planes: planes.map(|p| p / p.truncate().length()),
+        }
+    }
+    //Extracts the planes of a view projection matrix with the 0 to 1 depth range vulkan uses
+    pub fn from_view_projection(matrix: Mat4) -> Frustum {
+        let (x, y, z, w) = (matrix.row(0), matrix.row(1), matrix.row(2), matrix.row(3));
+        Self::from_planes([w + x, w - x, w + y, w - y, z, w - z])
+    }
+    pub fn contains(&self, point: Vec3) -> bool {
+        self.planes.iter().all(|p| p.truncate().dot(point) + p.w >= 0.0)
+    }
+    fn classify(&self, min: Vec3, max: Vec3) -> Overlap {
+        let mut overlap = Overlap::Inside;
+        for plane in self.planes.iter() {
+            let normal = plane.truncate();
+            //The corners of the box furthest along and against the normal
+            let positive = Vec3::select(normal.cmpge(Vec3::ZERO), max, min);
+            let negative = Vec3::select(normal.cmpge(Vec3::ZERO), min, max);
+            if normal.dot(positive) + plane.w < 0.0 {
+                return Overlap::Outside;
+            }
+            if normal.dot(negative) + plane.w < 0.0 {
+                overlap = Overlap::Partial;
+            }
+        }
+        overlap
+    }
+}
+
+//A squared distance that can be kept in a heap
+#[derive(Clone, Copy, PartialEq)]
+struct Distance(f32);
+
+impl Eq for Distance {}
+impl PartialOrd for Distance {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Distance {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand_xoshiro::Xoshiro256Plus;
+
+    fn random_points(count: usize, seed: u64) -> Vec<Vec3> {
+        let mut rng = Xoshiro256Plus::seed_from_u64(seed);
+        (0..count)
+            .map(|_| Vec3::new(rng.gen_range(0.0..1000.0), rng.gen_range(0.0..1000.0), rng.gen_range(0.0..1000.0)))
+            .collect()
+    }
+    //Every corner of an integer grid, so many points sit exactly on the planes nodes are split along
+    fn grid_points(side: u32) -> Vec<Vec3> {
+        let mut points = vec![];
+        for x in 0..=side {
+            for y in 0..=side {
+                for z in 0..=side {
+                    points.push(Vec3::new(x as f32, y as f32, z as f32));
+                }
+            }
+        }
+        points
+    }
+    //Far more systems than fit in a leaf at one spot, with a few others around them
+    fn duplicate_points() -> Vec<Vec3> {
+        let mut points = vec![Vec3::splat(3.0); LEAF_SIZE * 5];
+        points.extend(random_points(50, 9).into_iter().map(|p| p / 100.0));
+        points.extend(vec![Vec3::splat(7.5); LEAF_SIZE + 1]);
+        points
+    }
+    fn brute_force<F: Fn(Vec3) -> bool>(points: &[Vec3], test: F) -> Vec<usize> {
+        (0..points.len()).filter(|i| test(points[*i])).collect()
+    }
+    fn brute_force_nearest(points: &[Vec3], point: Vec3, k: usize) -> Vec<(usize, f32)> {
+        let mut all: Vec<(f32, usize)> = points.iter().enumerate().map(|(i, p)| (p.distance_squared(point), i)).collect();
+        all.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        all.into_iter().take(k).map(|(d, i)| (i, d.sqrt())).collect()
+    }
+    fn sorted(mut found: Vec<usize>) -> Vec<usize> {
+        found.sort_unstable();
+        found
+    }
+    fn frustum(eye: Vec3, target: Vec3) -> Frustum {
+        let projection = Mat4::perspective_rh(1.0, 1.5, 1.0, 600.0);
+        Frustum::from_view_projection(projection * Mat4::look_at_rh(eye, target, Vec3::Z))
+    }
+    //Runs every kind of query from each probe point against a brute force over the same points
+    fn check_against_brute_force(points: Vec<Vec3>, probes: &[Vec3], sizes: &[f32]) {
+        let octree = Octree::build(points.clone());
+        assert_eq!(octree.len(), points.len());
+        for &probe in probes {
+            for &size in sizes {
+                let (min, max) = (probe - size, probe + size);
+                assert_eq!(
+                    sorted(octree.within_box(min, max)),
+                    brute_force(&points, |p| p.cmpge(min).all() && p.cmple(max).all()),
+                    "box {} {}",
+                    min,
+                    max
+                );
+                assert_eq!(
+                    sorted(octree.within_sphere(probe, size)),
+                    brute_force(&points, |p| p.distance_squared(probe) <= size * size),
+                    "sphere {} {}",
+                    probe,
+                    size
+                );
+                //The eye has to stay off the probe it looks at
+                let eye = probe - (size + 1.0);
+                let frustum = frustum(eye, probe);
+                assert_eq!(
+                    sorted(octree.within_frustum(&frustum)),
+                    brute_force(&points, |p| frustum.contains(p)),
+                    "frustum from {}",
+                    eye
+                );
+            }
+            for k in [0, 1, 2, 7, LEAF_SIZE + 1, 100, points.len(), points.len() + 10] {
+                assert_eq!(octree.nearest(probe, k), brute_force_nearest(&points, probe, k), "{} nearest to {}", k, probe);
+            }
+        }
+    }
+
+    #[test]
+    fn random_points_match_brute_force() {
+        let points = random_points(5000, 1);
+        let probes: Vec<Vec3> = random_points(10, 2).into_iter().chain([Vec3::ZERO, Vec3::splat(2000.0), points[42]]).collect();
+        check_against_brute_force(points, &probes, &[0.0, 1.0, 50.0, 300.0, 5000.0]);
+    }
+    #[test]
+    fn points_on_node_boundaries_match_brute_force() {
+        //Probes on the grid put query edges exactly on node boundaries as well
+        let probes = [Vec3::ZERO, Vec3::splat(4.0), Vec3::new(2.0, 4.0, 6.0), Vec3::splat(8.0), Vec3::splat(3.5)];
+        check_against_brute_force(grid_points(8), &probes, &[0.0, 1.0, 2.0, 4.0, 16.0]);
+    }
+    #[test]
+    fn duplicate_positions_match_brute_force() {
+        let probes = [Vec3::splat(3.0), Vec3::splat(7.5), Vec3::splat(5.0), Vec3::ZERO];
+        check_against_brute_force(duplicate_points(), &probes, &[0.0, 0.5, 5.0]);
+        //Nothing but duplicates leaves the tree with no extent at all
+        check_against_brute_force(vec![Vec3::ONE; LEAF_SIZE * 3], &[Vec3::ONE, Vec3::ZERO], &[0.0, 2.0]);
+    }
+    #[test]
+    fn empty_tree_finds_nothing() {
+        let octree = Octree::build(vec![]);
+        assert!(octree.is_empty());
+        assert_eq!(octree.position(0), None);
+        assert!(octree.within_box(Vec3::splat(-1e9), Vec3::splat(1e9)).is_empty());
+        assert!(octree.within_sphere(Vec3::ZERO, 1e9).is_empty());
+        assert!(octree.within_frustum(&frustum(Vec3::splat(-10.0), Vec3::ZERO)).is_empty());
+        assert!(octree.nearest(Vec3::ZERO, 10).is_empty());
+    }
+}
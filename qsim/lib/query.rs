@@ -0,0 +1,249 @@
+use std::fmt;
+
+use glam::Vec3;
+
+use crate::{Frustum, Galaxy, PlanetarySystem, SpectralClass, Universe};
+
+//Identifies a system for the life of a galaxy
+//It is the index the system was generated at, so it survives saving and loading and
+//regenerating from the same seed and settings gives the same system for the same id
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SystemId(u64);
+
+//Where a query looks for systems
+#[derive(Clone, Copy)]
+enum Region {
+    Everywhere,
+    Sphere(Vec3, f32),
+    Box(Vec3, Vec3),
+    Frustum(Frustum),
+}
+
+//Narrows down the systems of a galaxy, built through `Universe::query`
+//Every condition has to hold for a system to be returned
+#[derive(Clone)]
+pub struct SystemQuery<'a> {
+    galaxy: &'a Galaxy,
+    region: Region,
+    temperature: Option<(f32, f32)>,
+    class: Option<SpectralClass>,
+    min_habitability: Option<f32>,
+}
+
+impl SystemId {
+    pub fn new(index: u64) -> SystemId {
+        SystemId(index)
+    }
+    pub fn index(&self) -> u64 {
+        self.0
+    }
+}
+
+//Query functionality
+impl Universe {
+    pub fn system(&self, id: SystemId) -> Option<&PlanetarySystem> {
+        self.galaxy.system(id.0 as usize)
+    }
+    pub fn system_count(&self) -> usize {
+        self.galaxy.plantary_systems.len()
+    }
+    //The `n` systems closest to `point`, nearest first, with their distances
+    pub fn nearest_systems(&self, point: Vec3, n: usize) -> Vec<(SystemId, f32)> {
+        self.query().nearest(point, n)
+    }
+    //Every system at most `radius` away from `centre`
+    pub fn systems_within(&self, centre: Vec3, radius: f32) -> Vec<SystemId> {
+        self.query().within_radius(centre, radius).ids()
+    }
+    //Starts a query over every system
+    pub fn query(&self) -> SystemQuery<'_> {
+        SystemQuery::new(&self.galaxy)
+    }
+}
+
+impl<'a> SystemQuery<'a> {
+    fn new(galaxy: &'a Galaxy) -> SystemQuery<'a> {
+        SystemQuery {
+            galaxy,
+            region: Region::Everywhere,
+            temperature: None,
+            class: None,
+            min_habitability: None,
+        }
+    }
+    //The region conditions replace each other, only the last one applies
+    pub fn within_radius(mut self, centre: Vec3, radius: f32) -> Self {
+        self.region = Region::Sphere(centre, radius);
+        self
+    }
+    pub fn within_box(mut self, min: Vec3, max: Vec3) -> Self {
+        self.region = Region::Box(min, max);
+        self
+    }
+    pub fn within_frustum(mut self, frustum: Frustum) -> Self {
+        self.region = Region::Frustum(frustum);
+        self
+    }
+    //Star surface temperature in kelvin, both ends included
+    pub fn star_temperature(mut self, min: f32, max: f32) -> Self {
+        self.temperature = Some((min, max));
+        self
+    }
+    pub fn spectral_class(mut self, class: SpectralClass) -> Self {
+        self.class = Some(class);
+        self
+    }
+    pub fn min_habitability(mut self, habitability: f32) -> Self {
+        self.min_habitability = Some(habitability);
+        self
+    }
+    //Every matching system in id order
+    pub fn ids(&self) -> Vec<SystemId> {
+        let octree = self.galaxy.octree();
+        let mut candidates = match self.region {
+            Region::Everywhere => (0..self.galaxy.plantary_systems.len()).collect(),
+            Region::Sphere(centre, radius) => octree.within_sphere(centre, radius),
+            Region::Box(min, max) => octree.within_box(min, max),
+            Region::Frustum(frustum) => octree.within_frustum(&frustum),
+        };
+        candidates.sort_unstable();
+        candidates
+            .into_iter()
+            .filter(|index| self.matches(*index))
+            .map(|index| SystemId(index as u64))
+            .collect()
+    }
+    pub fn systems(&self) -> Vec<(SystemId, &'a PlanetarySystem)> {
+        let galaxy = self.galaxy;
+        self.ids()
+            .into_iter()
+            .map(|id| (id, &galaxy.plantary_systems[id.0 as usize]))
+            .collect()
+    }
+    pub fn count(&self) -> usize {
+        self.ids().len()
+    }
+    //The `n` matching systems closest to `point`, nearest first, with their distances
+    pub fn nearest(&self, point: Vec3, n: usize) -> Vec<(SystemId, f32)> {
+        let octree = self.galaxy.octree();
+        //Filters can reject any number of the closest systems, so widen the search until enough pass
+        let mut k = n;
+        loop {
+            let nearest = octree.nearest(point, k);
+            let exhausted = nearest.len() < k;
+            let found: Vec<(SystemId, f32)> = nearest
+                .into_iter()
+                .filter(|(index, _)| self.in_region(*index) && self.matches(*index))
+                .map(|(index, distance)| (SystemId(index as u64), distance))
+                .take(n)
+                .collect();
+            if found.len() == n || exhausted {
+                return found;
+            }
+            k = k.saturating_mul(4);
+        }
+    }
+
+    fn in_region(&self, index: usize) -> bool {
+        let Some(p) = self.galaxy.octree().position(index) else {
+            return false;
+        };
+        match self.region {
+            Region::Everywhere => true,
+            Region::Sphere(centre, radius) => p.distance_squared(centre) <= radius * radius,
+            Region::Box(min, max) => p.cmpge(min).all() && p.cmple(max).all(),
+            Region::Frustum(frustum) => frustum.contains(p),
+        }
+    }
+    fn matches(&self, index: usize) -> bool {
+        let system = &self.galaxy.plantary_systems[index];
+        if let Some((min, max)) = self.temperature {
+            if system.star_temp < min || system.star_temp > max {
+                return false;
+            }
+        }
+        if let Some(class) = self.class {
+            if system.spectral_class() != class {
+                return false;
+            }
+        }
+        if let Some(habitability) = self.min_habitability {
+            if system.habitability < habitability {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl fmt::Display for SystemId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ProceduralGenerationSettings;
+
+    fn galaxy() -> Galaxy {
+        Galaxy::generate(&ProceduralGenerationSettings::default().with_seed(3).with_system_count(4000))
+    }
+    //What nearest has to return, found by checking every system
+    fn linear_nearest(query: &SystemQuery, point: Vec3, n: usize) -> Vec<(SystemId, f32)> {
+        let mut found: Vec<(f32, usize)> = (0..query.galaxy.plantary_systems.len())
+            .filter(|index| query.in_region(*index) && query.matches(*index))
+            .map(|index| (query.galaxy.octree().position(index).unwrap().distance_squared(point), index))
+            .collect();
+        found.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        found
+            .into_iter()
+            .take(n)
+            .map(|(distance, index)| (SystemId(index as u64), distance.sqrt()))
+            .collect()
+    }
+
+    #[test]
+    fn filtered_nearest_matches_a_linear_scan() {
+        let galaxy = galaxy();
+        let queries = [
+            SystemQuery::new(&galaxy),
+            SystemQuery::new(&galaxy).spectral_class(SpectralClass::G),
+            SystemQuery::new(&galaxy).spectral_class(SpectralClass::O),
+            SystemQuery::new(&galaxy).star_temperature(4000.0, 6000.0),
+            SystemQuery::new(&galaxy).min_habitability(0.05),
+            SystemQuery::new(&galaxy).min_habitability(2.0),
+            SystemQuery::new(&galaxy)
+                .within_radius(Vec3::splat(500.0), 200.0)
+                .spectral_class(SpectralClass::K),
+        ];
+        //Start at systems themselves, so the very closest candidate is often one the filter rejects
+        let points: Vec<Vec3> = [0, 17, 999, 3999]
+            .iter()
+            .map(|index| galaxy.octree().position(*index).unwrap())
+            .chain([Vec3::ZERO, Vec3::splat(500.0), Vec3::splat(5000.0)])
+            .collect();
+        for query in queries.iter() {
+            for point in points.iter() {
+                for n in [1, 5, 50, 10_000] {
+                    assert_eq!(query.nearest(*point, n), linear_nearest(query, *point, n));
+                }
+            }
+        }
+    }
+    #[test]
+    fn region_and_filters_match_a_linear_scan() {
+        let galaxy = galaxy();
+        let query = SystemQuery::new(&galaxy)
+            .within_box(Vec3::splat(100.0), Vec3::splat(700.0))
+            .star_temperature(3000.0, 8000.0);
+        let expected: Vec<SystemId> = (0..galaxy.plantary_systems.len())
+            .filter(|index| query.in_region(*index) && query.matches(*index))
+            .map(|index| SystemId(index as u64))
+            .collect();
+        assert!(!expected.is_empty());
+        assert_eq!(query.ids(), expected);
+        assert_eq!(query.count(), expected.len());
+    }
+}
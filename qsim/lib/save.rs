@@ -0,0 +1,394 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use qforce::data::Vector;
+
+use crate::{CelestialBody, Galaxy, Morphology, PlanetarySystem, ProceduralGalaxyGenSettings, ProceduralGenerationSettings};
+
+//Every save starts with the magic and the version of the layout that follows
+//The version has to be bumped whenever anything below changes
+const SAVE_MAGIC: [u8; 4] = *b"QGAL";
+//Only saves of this exact version are read, there is no older layout to fall back to
+pub const SAVE_VERSION: u32 = 1;
+//A header can claim any system count, so we never reserve more than this up front
+const MAX_PREALLOCATED_SYSTEMS: u64 = 1 << 20;
+
+//Layout, all little endian
+//magic, version: u32, settings starting with the seed: u64, bound: f32, system_count: u64
+//The morphology is a u8 tag followed by the fields of that shape
+//then system_count systems, each followed directly by its celestial bodies
+//Since every system is self contained a save can be read and written one system at a time
+
+#[derive(Debug)]
+pub enum SaveError {
+    Io(io::Error),
+    //The file does not start with the save magic
+    NotASave,
+    //The save was written by a version of qsim with another layout
+    UnsupportedVersion(u32),
+    //The file ended before the save did
+    Truncated,
+    //The file holds a value that makes no sense
+    Corrupt(&'static str),
+}
+
+//Everything a save holds before its systems
+#[derive(Clone)]
+pub struct SaveHeader {
+    pub version: u32,
+    pub settings: ProceduralGenerationSettings,
+    pub bound: f32,
+    pub system_count: u64,
+}
+
+//Reads the systems of a save one at a time
+//Lets a caller build its own structures from a save without a second copy of the galaxy
+pub struct SystemReader<R: Read> {
+    reader: R,
+    header: SaveHeader,
+    remaining: u64,
+}
+
+impl<R: Read> SystemReader<R> {
+    pub fn new(mut reader: R) -> Result<SystemReader<R>, SaveError> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic).map_err(eof)?;
+        if magic != SAVE_MAGIC {
+            return Err(SaveError::NotASave);
+        }
+        let version = read_u32(&mut reader)?;
+        if version != SAVE_VERSION {
+            return Err(SaveError::UnsupportedVersion(version));
+        }
+        let settings = read_settings(&mut reader)?;
+        let bound = read_f32(&mut reader)?;
+        let system_count = read_u64(&mut reader)?;
+        let header = SaveHeader {
+            version,
+            settings,
+            bound,
+            system_count,
+        };
+        Ok(SystemReader {
+            reader,
+            header,
+            remaining: system_count,
+        })
+    }
+    pub fn header(&self) -> &SaveHeader {
+        &self.header
+    }
+}
+
+impl<R: Read> Iterator for SystemReader<R> {
+    type Item = Result<PlanetarySystem, SaveError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let system = read_system(&mut self.reader);
+        //There is no resyncing after a bad system so we stop
+        if system.is_err() {
+            self.remaining = 0;
+        }
+        Some(system)
+    }
+}
+
+impl Galaxy {
+    //Writes the galaxy along with what it was generated from
+    pub fn write<W: Write>(&self, settings: &ProceduralGenerationSettings, mut writer: W) -> Result<(), SaveError> {
+        writer.write_all(&SAVE_MAGIC)?;
+        writer.write_all(&SAVE_VERSION.to_le_bytes())?;
+        write_settings(&mut writer, settings)?;
+        writer.write_all(&self.bound.to_le_bytes())?;
+        writer.write_all(&(self.plantary_systems.len() as u64).to_le_bytes())?;
+        for system in self.plantary_systems.iter() {
+            write_system(&mut writer, system)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+    //Reads a galaxy back, systems are moved straight from the file into the galaxy
+    pub fn read<R: Read>(reader: R) -> Result<(SaveHeader, Galaxy), SaveError> {
+        let mut systems = SystemReader::new(reader)?;
+        let header = systems.header().clone();
+        let mut plantary_systems = Vec::with_capacity(header.system_count.min(MAX_PREALLOCATED_SYSTEMS) as usize);
+        for system in systems.by_ref() {
+            plantary_systems.push(system?);
+        }
+        let galaxy = Galaxy::new(header.bound, plantary_systems);
+        Ok((header, galaxy))
+    }
+    pub fn save_file<P: AsRef<Path>>(&self, settings: &ProceduralGenerationSettings, path: P) -> Result<(), SaveError> {
+        let file = File::create(path)?;
+        self.write(settings, BufWriter::new(file))
+    }
+    pub fn load_file<P: AsRef<Path>>(path: P) -> Result<(SaveHeader, Galaxy), SaveError> {
+        let file = File::open(path)?;
+        Self::read(BufReader::new(file))
+    }
+}
+
+fn write_settings<W: Write>(writer: &mut W, settings: &ProceduralGenerationSettings) -> io::Result<()> {
+    let galaxy = &settings.galaxy_gen;
+    writer.write_all(&settings.seed.to_le_bytes())?;
+    writer.write_all(&galaxy.galaxy_max_size.to_le_bytes())?;
+    writer.write_all(&(galaxy.system_count as u64).to_le_bytes())?;
+    writer.write_all(&galaxy.max_system_size.to_le_bytes())?;
+    writer.write_all(&galaxy.planetary_system_max_size.to_le_bytes())?;
+    writer.write_all(&(galaxy.planetary_system_max_cb_count as u64).to_le_bytes())?;
+    writer.write_all(&galaxy.co_max_size.to_le_bytes())?;
+    write_morphology(writer, &galaxy.morphology)
+}
+fn read_settings<R: Read>(reader: &mut R) -> Result<ProceduralGenerationSettings, SaveError> {
+    let seed = read_u64(reader)?;
+    let galaxy_gen = ProceduralGalaxyGenSettings {
+        galaxy_max_size: read_f32(reader)?,
+        system_count: read_u64(reader)? as usize,
+        max_system_size: read_f32(reader)?,
+        planetary_system_max_size: read_f32(reader)?,
+        planetary_system_max_cb_count: read_u64(reader)? as usize,
+        co_max_size: read_f32(reader)?,
+        morphology: read_morphology(reader)?,
+    };
+    Ok(ProceduralGenerationSettings { seed, galaxy_gen })
+}
+fn write_morphology<W: Write>(writer: &mut W, morphology: &Morphology) -> io::Result<()> {
+    match *morphology {
+        Morphology::Spiral {
+            arms,
+            pitch_angle,
+            bulge_radius,
+        } => {
+            writer.write_all(&[0])?;
+            writer.write_all(&arms.to_le_bytes())?;
+            writer.write_all(&pitch_angle.to_le_bytes())?;
+            writer.write_all(&bulge_radius.to_le_bytes())
+        }
+        Morphology::Elliptical { ellipticity } => {
+            writer.write_all(&[1])?;
+            writer.write_all(&ellipticity.to_le_bytes())
+        }
+        Morphology::Irregular { clumps } => {
+            writer.write_all(&[2])?;
+            writer.write_all(&clumps.to_le_bytes())
+        }
+        Morphology::UniformCube => writer.write_all(&[3]),
+    }
+}
+fn read_morphology<R: Read>(reader: &mut R) -> Result<Morphology, SaveError> {
+    let mut tag = [0; 1];
+    reader.read_exact(&mut tag).map_err(eof)?;
+    match tag[0] {
+        0 => Ok(Morphology::Spiral {
+            arms: read_u32(reader)?,
+            pitch_angle: read_f32(reader)?,
+            bulge_radius: read_f32(reader)?,
+        }),
+        1 => Ok(Morphology::Elliptical {
+            ellipticity: read_f32(reader)?,
+        }),
+        2 => Ok(Morphology::Irregular {
+            clumps: read_u32(reader)?,
+        }),
+        3 => Ok(Morphology::UniformCube),
+        _ => Err(SaveError::Corrupt("morphology")),
+    }
+}
+fn write_system<W: Write>(writer: &mut W, system: &PlanetarySystem) -> io::Result<()> {
+    write_vector(writer, &system.galaxy_pos)?;
+    writer.write_all(&system.spatial_bound.to_le_bytes())?;
+    writer.write_all(&system.star_size.to_le_bytes())?;
+    writer.write_all(&system.star_temp.to_le_bytes())?;
+    writer.write_all(&system.star_mass.to_le_bytes())?;
+    writer.write_all(&system.star_luminosity.to_le_bytes())?;
+    writer.write_all(&system.habitability.to_le_bytes())?;
+    writer.write_all(&(system.c_bodies.len() as u32).to_le_bytes())?;
+    for body in system.c_bodies.iter() {
+        write_vector(writer, &body.system_pos)?;
+        writer.write_all(&body.orbital_radius.to_le_bytes())?;
+        writer.write_all(&body.size.to_le_bytes())?;
+        writer.write_all(&body.atmosphere_quality.to_le_bytes())?;
+        writer.write_all(&body.water_content.to_le_bytes())?;
+    }
+    Ok(())
+}
+fn read_system<R: Read>(reader: &mut R) -> Result<PlanetarySystem, SaveError> {
+    let galaxy_pos = read_vector(reader)?;
+    let spatial_bound = read_f32(reader)?;
+    let star_size = read_f32(reader)?;
+    let star_temp = read_f32(reader)?;
+    let star_mass = read_f32(reader)?;
+    let star_luminosity = read_f32(reader)?;
+    let habitability = read_f32(reader)?;
+    let body_count = read_u32(reader)?;
+    //No sane system has this many bodies, so the count is garbage rather than a reason to allocate
+    if body_count > u16::MAX as u32 {
+        return Err(SaveError::Corrupt("celestial body count"));
+    }
+    let mut c_bodies = Vec::with_capacity(body_count as usize);
+    for _ in 0..body_count {
+        c_bodies.push(CelestialBody {
+            system_pos: read_vector(reader)?,
+            orbital_radius: read_f32(reader)?,
+            size: read_f32(reader)?,
+            atmosphere_quality: read_f32(reader)?,
+            water_content: read_f32(reader)?,
+        });
+    }
+    Ok(PlanetarySystem {
+        galaxy_pos,
+        spatial_bound,
+        star_size,
+        star_temp,
+        star_mass,
+        star_luminosity,
+        habitability,
+        c_bodies,
+    })
+}
+fn write_vector<W: Write>(writer: &mut W, vector: &Vector) -> io::Result<()> {
+    writer.write_all(&vector.x.to_le_bytes())?;
+    writer.write_all(&vector.y.to_le_bytes())?;
+    writer.write_all(&vector.z.to_le_bytes())
+}
+fn read_vector<R: Read>(reader: &mut R) -> Result<Vector, SaveError> {
+    Ok(Vector {
+        x: read_f32(reader)?,
+        y: read_f32(reader)?,
+        z: read_f32(reader)?,
+    })
+}
+fn read_f32<R: Read>(reader: &mut R) -> Result<f32, SaveError> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes).map_err(eof)?;
+    Ok(f32::from_le_bytes(bytes))
+}
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32, SaveError> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes).map_err(eof)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64, SaveError> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes).map_err(eof)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+fn eof(e: io::Error) -> SaveError {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof => SaveError::Truncated,
+        _ => SaveError::Io(e),
+    }
+}
+
+impl From<io::Error> for SaveError {
+    fn from(e: io::Error) -> Self {
+        SaveError::Io(e)
+    }
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveError::Io(e) => write!(f, "Could not access the save: {}", e),
+            SaveError::NotASave => write!(f, "The file is not a galaxy save"),
+            SaveError::UnsupportedVersion(v) => write!(f, "Save version {} is not the supported version {}", v, SAVE_VERSION),
+            SaveError::Truncated => write!(f, "The save ends early"),
+            SaveError::Corrupt(what) => write!(f, "The save has a bad {}", what),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{assert_same_galaxy, assert_same_system};
+
+    fn small_galaxy() -> (ProceduralGenerationSettings, Galaxy, Vec<u8>) {
+        let settings = ProceduralGenerationSettings::default()
+            .with_seed(7)
+            .with_system_count(64)
+            .with_morphology(Morphology::Spiral {
+                arms: 3,
+                pitch_angle: 0.3,
+                bulge_radius: 0.1,
+            });
+        let galaxy = Galaxy::generate(&settings);
+        let mut bytes = vec![];
+        galaxy.write(&settings, &mut bytes).unwrap();
+        (settings, galaxy, bytes)
+    }
+    //The offset of the system count, right before the first system
+    fn system_count_offset(settings: &ProceduralGenerationSettings) -> usize {
+        let mut settings_bytes = vec![];
+        write_settings(&mut settings_bytes, settings).unwrap();
+        SAVE_MAGIC.len() + 4 + settings_bytes.len() + 4
+    }
+
+    #[test]
+    fn written_galaxy_reads_back_the_same() {
+        let (settings, galaxy, bytes) = small_galaxy();
+        let (header, read) = Galaxy::read(&bytes[..]).unwrap();
+        assert_eq!(header.version, SAVE_VERSION);
+        assert_eq!(header.system_count, 64);
+        assert_eq!(header.settings.seed(), settings.seed());
+        assert_eq!(header.settings.system_count(), settings.system_count());
+        assert_eq!(header.settings.morphology(), settings.morphology());
+        assert_same_galaxy(&galaxy, &read);
+        //The systems read one at a time are the same as well
+        let systems = SystemReader::new(&bytes[..]).unwrap();
+        for (index, system) in systems.enumerate() {
+            assert_same_system(galaxy.system(index).unwrap(), &system.unwrap());
+        }
+    }
+    #[test]
+    fn every_truncation_is_refused() {
+        let (_, _, bytes) = small_galaxy();
+        for len in 0..bytes.len() {
+            match Galaxy::read(&bytes[..len]) {
+                Err(SaveError::Truncated) => {}
+                Err(e) => panic!("Cut at {} gave {}", len, e),
+                Ok(_) => panic!("Cut at {} read back", len),
+            }
+        }
+    }
+    #[test]
+    fn wrong_magic_is_not_a_save() {
+        let (_, _, mut bytes) = small_galaxy();
+        bytes[0] = b'X';
+        assert!(matches!(Galaxy::read(&bytes[..]), Err(SaveError::NotASave)));
+    }
+    #[test]
+    fn other_versions_are_refused() {
+        let (_, _, mut bytes) = small_galaxy();
+        for version in [0, SAVE_VERSION + 1, u32::MAX] {
+            bytes[4..8].copy_from_slice(&version.to_le_bytes());
+            match Galaxy::read(&bytes[..]) {
+                Err(SaveError::UnsupportedVersion(v)) => assert_eq!(v, version),
+                _ => panic!("Version {} was not refused", version),
+            }
+        }
+    }
+    #[test]
+    fn huge_body_count_is_corrupt() {
+        let (settings, _, mut bytes) = small_galaxy();
+        //The body count follows the position, spatial bound and the five star fields of the first system
+        let offset = system_count_offset(&settings) + 8 + 4 * 3 + 4 + 4 * 5;
+        bytes[offset..offset + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(Galaxy::read(&bytes[..]), Err(SaveError::Corrupt(_))));
+    }
+    #[test]
+    fn huge_system_count_runs_out_of_save() {
+        let (settings, _, mut bytes) = small_galaxy();
+        let offset = system_count_offset(&settings);
+        bytes[offset..offset + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(Galaxy::read(&bytes[..]), Err(SaveError::Truncated)));
+    }
+}
@@ -0,0 +1,280 @@
+use std::fmt;
+
+use rand::Rng;
+
+//Stars are main sequence only, units are solar masses, luminosities and radii, kelvin and AU
+
+//The mass range stars are drawn from, below it there is no fusion
+const MIN_STAR_MASS: f32 = 0.08;
+const MAX_STAR_MASS: f32 = 100.0;
+//Where the two slopes of the Kroupa initial mass function meet, and the slopes on either side
+const IMF_BREAK: f32 = 0.5;
+const IMF_LOW_SLOPE: f32 = 1.3;
+const IMF_HIGH_SLOPE: f32 = 2.3;
+const SUN_TEMPERATURE: f32 = 5772.0;
+//Equilibrium temperature of a black body at 1 AU from the sun
+const EQUILIBRIUM_AT_1AU: f32 = 278.0;
+//Stellar flux in units of the solar constant at the inner and outer edge of the habitable zone
+const HZ_INNER_FLUX: f32 = 1.1;
+const HZ_OUTER_FLUX: f32 = 0.53;
+//Distance of the frost line for a star as bright as the sun
+const FROST_LINE_AT_1L: f32 = 2.7;
+//Planets with a radius in earth radii above this are gas giants with no surface
+const GIANT_RADIUS: f32 = 3.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SpectralClass {
+    O,
+    B,
+    A,
+    F,
+    G,
+    K,
+    M,
+}
+
+impl SpectralClass {
+    pub fn from_temperature(temperature: f32) -> SpectralClass {
+        match temperature {
+            t if t >= 30000.0 => SpectralClass::O,
+            t if t >= 10000.0 => SpectralClass::B,
+            t if t >= 7500.0 => SpectralClass::A,
+            t if t >= 6000.0 => SpectralClass::F,
+            t if t >= 5200.0 => SpectralClass::G,
+            t if t >= 3700.0 => SpectralClass::K,
+            _ => SpectralClass::M,
+        }
+    }
+    //How well life could do around such a star, 0-1
+    //Hot stars burn out before life gets going, M dwarfs flare and tidally lock their habitable planets
+    fn suitability(&self) -> f32 {
+        match self {
+            SpectralClass::O | SpectralClass::B => 0.0,
+            SpectralClass::A => 0.3,
+            SpectralClass::F => 0.8,
+            SpectralClass::G => 1.0,
+            SpectralClass::K => 0.9,
+            SpectralClass::M => 0.5,
+        }
+    }
+}
+
+//A main sequence star, everything follows from its mass
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Star {
+    pub mass: f32,
+    pub luminosity: f32,
+    pub radius: f32,
+    pub temperature: f32,
+}
+
+impl Star {
+    //Draws the mass from the Kroupa initial mass function, so most stars are red dwarfs
+    pub fn generate<R: Rng>(rng: &mut R) -> Star {
+        let low = power_law_weight(MIN_STAR_MASS, IMF_BREAK, IMF_LOW_SLOPE);
+        //The high segment is scaled so the two meet at the break
+        let high = IMF_BREAK.powf(IMF_HIGH_SLOPE - IMF_LOW_SLOPE) * power_law_weight(IMF_BREAK, MAX_STAR_MASS, IMF_HIGH_SLOPE);
+        let mass = if rng.gen_range(0.0..low + high) < low {
+            sample_power_law(MIN_STAR_MASS, IMF_BREAK, IMF_LOW_SLOPE, rng)
+        } else {
+            sample_power_law(IMF_BREAK, MAX_STAR_MASS, IMF_HIGH_SLOPE, rng)
+        };
+        Self::from_mass(mass)
+    }
+    pub fn from_mass(mass: f32) -> Star {
+        //Piecewise mass-luminosity and mass-radius relations of the main sequence
+        let luminosity = match mass {
+            m if m < 0.43 => 0.23 * m.powf(2.3),
+            m if m < 2.0 => m.powi(4),
+            m if m < 55.0 => 1.4 * m.powf(3.5),
+            m => 32000.0 * m,
+        };
+        let radius = if mass < 1.0 { mass.powf(0.8) } else { mass.powf(0.57) };
+        //Stefan-Boltzmann relative to the sun
+        let temperature = SUN_TEMPERATURE * (luminosity / (radius * radius)).powf(0.25);
+        Star {
+            mass,
+            luminosity,
+            radius,
+            temperature,
+        }
+    }
+    pub fn class(&self) -> SpectralClass {
+        SpectralClass::from_temperature(self.temperature)
+    }
+    //Inner and outer edge of the habitable zone
+    pub fn habitable_zone(&self) -> (f32, f32) {
+        ((self.luminosity / HZ_INNER_FLUX).sqrt(), (self.luminosity / HZ_OUTER_FLUX).sqrt())
+    }
+    //Past this water condenses into ice
+    pub fn frost_line(&self) -> f32 {
+        FROST_LINE_AT_1L * self.luminosity.sqrt()
+    }
+    pub fn equilibrium_temperature(&self, orbit: f32) -> f32 {
+        EQUILIBRIUM_AT_1AU * self.luminosity.powf(0.25) / orbit.sqrt()
+    }
+}
+
+//What a planet is made of, decided by its size and how far out it formed
+pub(crate) struct Planet {
+    //In earth radii
+    pub radius: f32,
+    pub atmosphere_quality: f32,
+    pub water_content: f32,
+}
+
+impl Planet {
+    pub fn generate<R: Rng>(star: &Star, orbit: f32, rng: &mut R) -> Planet {
+        let beyond_frost = orbit > star.frost_line();
+        //Giants form where there is ice to build cores from
+        let radius = if beyond_frost && rng.gen_bool(0.6) {
+            log_uniform(GIANT_RADIUS, 15.0, rng)
+        } else {
+            log_uniform(0.3, 2.5, rng)
+        };
+        if radius > GIANT_RADIUS {
+            return Planet {
+                radius,
+                atmosphere_quality: 1.0,
+                water_content: 0.0,
+            };
+        }
+        let temperature = star.equilibrium_temperature(orbit);
+        //Small planets can not hold on to an atmosphere, and flares strip close in planets of red dwarfs
+        let mut retention = smoothstep(0.3, 1.2, radius);
+        if star.class() == SpectralClass::M && orbit < star.habitable_zone().1 {
+            retention *= 0.6;
+        }
+        //Warmth thickens an atmosphere towards a runaway greenhouse, cold thins it
+        let greenhouse = ((temperature - 270.0) / 150.0).clamp(-1.0, 1.0);
+        let atmosphere_quality = (retention * (0.5 + 0.5 * greenhouse) + rng.gen_range(-0.05..0.05)).clamp(0.0, 1.0);
+        let water_content = if beyond_frost {
+            //Ice
+            rng.gen_range(0.2..0.8)
+        } else if (255.0..=320.0).contains(&temperature) {
+            retention * rng.gen_range(0.3..0.9)
+        } else if temperature > 320.0 {
+            rng.gen_range(0.0..0.05)
+        } else {
+            retention * rng.gen_range(0.0..0.3)
+        };
+        Planet {
+            radius,
+            atmosphere_quality,
+            water_content,
+        }
+    }
+    //How likely the planet is to support life, 0-1
+    pub fn habitability(&self, star: &Star, orbit: f32) -> f32 {
+        if self.radius > GIANT_RADIUS {
+            return 0.0;
+        }
+        let (inner, outer) = star.habitable_zone();
+        let centre = (inner + outer) / 2.0;
+        let zone = (1.0 - ((orbit - centre) / (outer - centre)).abs()).max(0.0);
+        let size = (1.0 - ((self.radius - 1.0) / 0.8).abs()).max(0.0);
+        //An earth like atmosphere is 0.5
+        let atmosphere = (1.0 - (self.atmosphere_quality - 0.5).abs() * 2.0).max(0.0);
+        let water = (1.0 - (self.water_content - 0.7).abs() / 0.7).max(0.0);
+        star.class().suitability() * zone * size * atmosphere * water
+    }
+}
+
+impl fmt::Display for SpectralClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+//Integral of m^-slope over [low, high]
+fn power_law_weight(low: f32, high: f32, slope: f32) -> f32 {
+    let e = 1.0 - slope;
+    (high.powf(e) - low.powf(e)) / e
+}
+//A value in [low, high] with density proportional to m^-slope
+fn sample_power_law<R: Rng>(low: f32, high: f32, slope: f32, rng: &mut R) -> f32 {
+    let e = 1.0 - slope;
+    let (l, h) = (low.powf(e), high.powf(e));
+    (l + rng.gen::<f32>() * (h - l)).powf(1.0 / e).clamp(low, high)
+}
+fn log_uniform<R: Rng>(low: f32, high: f32, rng: &mut R) -> f32 {
+    rng.gen_range(low.ln()..high.ln()).exp()
+}
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_xoshiro::Xoshiro256Plus;
+
+    const SAMPLES: usize = 100_000;
+
+    //Masses spread evenly in log space over the whole range stars are drawn from
+    fn masses() -> impl Iterator<Item = f32> {
+        let steps = 2000;
+        (0..=steps).map(move |i| MIN_STAR_MASS * (MAX_STAR_MASS / MIN_STAR_MASS).powf(i as f32 / steps as f32))
+    }
+
+    #[test]
+    fn star_of_one_solar_mass_is_the_sun() {
+        let sun = Star::from_mass(1.0);
+        assert_eq!(sun.luminosity, 1.0);
+        assert_eq!(sun.radius, 1.0);
+        assert_eq!(sun.temperature, SUN_TEMPERATURE);
+        assert_eq!(sun.class(), SpectralClass::G);
+    }
+    #[test]
+    fn spectral_class_follows_mass() {
+        assert_eq!(Star::from_mass(0.1).class(), SpectralClass::M);
+        assert_eq!(Star::from_mass(0.8).class(), SpectralClass::K);
+        assert_eq!(Star::from_mass(5.0).class(), SpectralClass::B);
+        assert_eq!(Star::from_mass(20.0).class(), SpectralClass::O);
+        //A heavier star is never of a later class
+        let classes: Vec<SpectralClass> = masses().map(|m| Star::from_mass(m).class()).collect();
+        assert!(classes.windows(2).all(|pair| pair[1] <= pair[0]));
+    }
+    #[test]
+    fn spectral_classes_order_hottest_first() {
+        use SpectralClass::*;
+        assert!(O < B && B < A && A < F && F < G && G < K && K < M);
+        let mut previous = SpectralClass::from_temperature(100_000.0);
+        for t in (0..=100_000).rev().step_by(50) {
+            let class = SpectralClass::from_temperature(t as f32);
+            assert!(class >= previous, "{} K is {} after {}", t, class, previous);
+            previous = class;
+        }
+    }
+    #[test]
+    fn imf_masses_stay_in_range() {
+        let mut rng = Xoshiro256Plus::seed_from_u64(1);
+        let mut below_break = 0;
+        for _ in 0..SAMPLES {
+            let star = Star::generate(&mut rng);
+            assert!((MIN_STAR_MASS..=MAX_STAR_MASS).contains(&star.mass), "{}", star.mass);
+            if star.mass < IMF_BREAK {
+                below_break += 1;
+            }
+        }
+        //About three quarters of stars fall below the break
+        assert!(below_break > SAMPLES * 6 / 10, "{}", below_break);
+    }
+    #[test]
+    fn habitability_stays_in_range() {
+        let mut rng = Xoshiro256Plus::seed_from_u64(2);
+        for mass in masses() {
+            let star = Star::from_mass(mass);
+            for _ in 0..20 {
+                let orbit = log_uniform(0.01, 1000.0, &mut rng);
+                let planet = Planet::generate(&star, orbit, &mut rng);
+                assert!((0.0..=1.0).contains(&planet.atmosphere_quality));
+                assert!((0.0..=1.0).contains(&planet.water_content));
+                let habitability = planet.habitability(&star, orbit);
+                assert!((0.0..=1.0).contains(&habitability), "{} at {} AU around {} Msun", habitability, orbit, mass);
+            }
+        }
+    }
+}
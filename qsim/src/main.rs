@@ -4,5 +4,5 @@ use quniverse::Universe;
 
 fn main() {
     let addr = "127.0.0.1:0".to_socket_addrs().unwrap().last().unwrap();
-    let universe = Universe::load(addr, None);
+    let universe = Universe::load(addr, None).unwrap_or_else(|e| panic!("Could not load the universe: {}", e));
 }
@@ -0,0 +1,189 @@
+use std::{fmt::Write, net::SocketAddr, str::FromStr, sync::Arc};
+use qserver::{LocalServer, LogLevel, qlog};
+use tokio::{runtime::Runtime, time::{Duration, Instant, sleep}};
+
+use crate::Switch;
+
+/// How long a new node waits for the handshake with the node it joined
+const JOIN_TIMEOUT: u64 = 5000;
+const JOIN_POLL: u64 = 50;
+
+/// How the nodes of a development cluster find each other
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Topology{
+    /// Every node joins the discoverable node started before it
+    Chain,
+    /// Every node joins the first node
+    Star,
+}
+
+/// A node of a development cluster as seen by the membership table
+#[derive(Clone, Debug)]
+pub struct Member{
+    pub index: usize,
+    pub address: SocketAddr,
+    pub discoverable: bool,
+    /// False once the node was killed or left
+    pub alive: bool,
+    /// The indices of the nodes in this node's peer table
+    pub peers: Vec<usize>,
+    /// The indices of the live nodes this node should have as peers but does not
+    pub missing: Vec<usize>,
+}
+
+/// Many switches on loopback in a single process, sharing one runtime
+/// Meant for watching discovery converge and for injecting failures by hand
+pub struct Cluster{
+    runtime: Arc<Runtime>,
+    topology: Topology,
+    /// Every node ever started, killed nodes included so their index stays stable
+    nodes: Vec<(Switch, bool)>,
+}
+
+impl Cluster{
+    /// Starts `nodes` switches of which the last `private` are not discoverable
+    /// The first node is always discoverable since everyone else joins through it
+    pub fn start(nodes: usize, private: usize, topology: Topology) -> Cluster {
+        let runtime = Arc::new(tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap());
+        let mut cluster = Cluster{ runtime, topology, nodes: vec![] };
+        let private = private.min(nodes.saturating_sub(1));
+        for index in 0..nodes{
+            cluster.add(index < nodes - private);
+        }
+        cluster
+    }
+    /// Starts another node and joins it according to the topology
+    pub fn add(&mut self, discoverable: bool) -> usize {
+        let bind = SocketAddr::from(([127, 0, 0, 1], 0));
+        let switch = Switch::start_on(Some(bind), discoverable, Some(self.runtime.clone()));
+        let target = match self.topology{
+            Topology::Chain => self.nodes.iter().rev().find(|(s, alive)| *alive && s.server.is_discoverable()),
+            Topology::Star => self.nodes.iter().find(|(s, alive)| *alive && s.server.is_discoverable()),
+        };
+        if let Some((target, _)) = target{
+            // Peers only hand out the peers they already have, so the next node may only
+            // join once this one has finished its handshake
+            let target = target.address();
+            switch.join(target);
+            if !self.runtime.block_on(Self::handshake(&switch.server, target)){
+                qlog!(LogLevel::Warn, "Switch {} got no handshake from {}", switch.address(), target);
+            }
+        }
+        self.nodes.push((switch, true));
+        self.nodes.len() - 1
+    }
+    /// Stops a node without telling anyone, its peers have to notice through keep alives
+    pub fn kill(&mut self, index: usize) -> bool {
+        let Some((switch, alive)) = self.nodes.get_mut(index) else {return false};
+        switch.stop();
+        let was_alive = *alive;
+        *alive = false;
+        was_alive
+    }
+    /// Makes a node leave the cluster gracefully
+    pub fn leave(&mut self, index: usize) -> bool {
+        let Some((switch, alive)) = self.nodes.get_mut(index) else {return false};
+        let was_alive = *alive;
+        if was_alive{
+            switch.leave();
+        }
+        *alive = false;
+        was_alive
+    }
+    pub fn node(&self, index: usize) -> Option<&Switch> {
+        self.nodes.get(index).map(|n| &n.0)
+    }
+    /// The peer table of every node
+    pub fn membership(&self) -> Vec<Member> {
+        self.runtime.block_on(async{
+            let addresses: Vec<SocketAddr> = self.nodes.iter().map(|n| n.0.address()).collect();
+            let mut members = vec![];
+            for (index, (switch, alive)) in self.nodes.iter().enumerate(){
+                let server = switch.server();
+                let known = server.peer_addresses().await;
+                let peers: Vec<usize> = known.iter().filter_map(|a| addresses.iter().position(|b| b == a)).collect();
+                let mut missing = vec![];
+                if *alive{
+                    for (other, (other_switch, other_alive)) in self.nodes.iter().enumerate(){
+                        if other != index && *other_alive && Self::should_know(&server, &other_switch.server) && !peers.contains(&other){
+                            missing.push(other);
+                        }
+                    }
+                }
+                members.push(Member{ index, address: switch.address(), discoverable: server.is_discoverable(), alive: *alive, peers, missing });
+            }
+            members
+        })
+    }
+    /// Has every live node got every peer it should
+    pub fn converged(&self) -> bool {
+        self.membership().iter().all(|m| m.missing.is_empty())
+    }
+    /// The membership table as text, one row per node and one column per peer
+    /// `x` is a peer, `?` a peer that should be there but is not, stopped nodes have no row
+    pub fn render(&self) -> String {
+        let members = self.membership();
+        let mut table = String::new();
+        let _ = write!(table, "{:>4}  {:<21} {:<4} {:<5} ", "node", "address", "disc", "state");
+        for member in members.iter(){
+            let _ = write!(table, "{:>3}", member.index);
+        }
+        table.push('\n');
+        for member in members.iter(){
+            let state = if member.alive {"up"} else {"down"};
+            let discoverable = if member.discoverable {"yes"} else {"no"};
+            let _ = write!(table, "{:>4}  {:<21} {:<4} {:<5} ", member.index, member.address, discoverable, state);
+            for other in members.iter(){
+                // A stopped node's table is stale so it is left out
+                let cell = if other.index == member.index {"-"}
+                    else if !member.alive {""}
+                    else if member.peers.contains(&other.index) {"x"}
+                    else if member.missing.contains(&other.index) {"?"}
+                    else {"."};
+                let _ = write!(table, "{:>3}", cell);
+            }
+            table.push('\n');
+        }
+        let missing: usize = members.iter().map(|m| m.missing.len()).sum();
+        if missing == 0{
+            table.push_str("converged\n");
+        }
+        else{
+            let _ = writeln!(table, "converging, {} peer entries missing", missing);
+        }
+        table
+    }
+    /// Stops every node that is still running
+    pub fn stop(&mut self){
+        for index in 0..self.nodes.len(){
+            self.kill(index);
+        }
+    }
+
+    async fn handshake(server: &LocalServer, target: SocketAddr) -> bool {
+        let deadline = Instant::now() + Duration::from_millis(JOIN_TIMEOUT);
+        while server.peer_info(target).await.is_none(){
+            if Instant::now() > deadline{
+                return false;
+            }
+            sleep(Duration::from_millis(JOIN_POLL)).await;
+        }
+        true
+    }
+    /// Discoverable servers are told about everyone, private ones only about discoverable servers
+    fn should_know(server: &LocalServer, other: &LocalServer) -> bool {
+        server.is_discoverable() || other.is_discoverable()
+    }
+}
+
+impl FromStr for Topology{
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str(){
+            "chain" => Ok(Topology::Chain),
+            "star" => Ok(Topology::Star),
+            _ => Err(format!("Unknown topology {}, expected chain or star", s)),
+        }
+    }
+}
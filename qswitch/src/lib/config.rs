@@ -0,0 +1,223 @@
+use std::{fmt, fs, io, net::{SocketAddr, ToSocketAddrs}, path::{Path, PathBuf}, time::SystemTime};
+use serde::Deserialize;
+use qserver::{LogLevel, RateLimits, Cidr, Tuning, qlog};
+use tokio::time::{Duration, sleep};
+
+use crate::Switch;
+
+/// How often a watched config file is checked for changes
+const RELOAD_INTERVAL: u64 = 1000;
+
+/// The settings of a switch, read from a toml file
+/// Anything missing from the file takes its default. The bootstrap list, log level,
+/// tuning and limits are applied again whenever the file changes, the rest only at start
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Config{
+    /// The address the server binds, the local ip on an ephemeral port if not set
+    pub bind: Option<SocketAddr>,
+    /// Where the control socket is opened
+    pub control: Option<PathBuf>,
+    /// Servers to join as host:port
+    pub bootstrap: Vec<String>,
+    pub discoverable: bool,
+    pub log_level: LogLevel,
+    /// The keep alive and exchange timers in milliseconds
+    pub tuning: Tuning,
+    pub limits: Limits,
+}
+
+/// The abuse protection settings of a switch
+/// The ranges are written as strings such as "10.0.0.0/8"
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Limits{
+    pub datagrams_per_second: f64,
+    pub burst: f64,
+    pub max_sources: usize,
+    pub max_peers: usize,
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError{
+    Io(io::Error),
+    Parse(String),
+    /// The file parsed but a value in it makes no sense
+    Invalid(String),
+}
+
+impl Default for Config{
+    fn default() -> Self {
+        Config{
+            bind: None,
+            control: None,
+            bootstrap: vec![],
+            discoverable: true,
+            log_level: LogLevel::Info,
+            tuning: Tuning::default(),
+            limits: Limits::default() }
+    }
+}
+
+impl Default for Limits{
+    fn default() -> Self {
+        let limits = RateLimits::default();
+        Limits{
+            datagrams_per_second: limits.datagrams_per_second,
+            burst: limits.burst,
+            max_sources: limits.max_sources,
+            max_peers: limits.max_peers,
+            allow: vec![],
+            deny: vec![] }
+    }
+}
+
+impl Config{
+    /// Reads and checks a config file
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let text = fs::read_to_string(path).map_err(ConfigError::Io)?;
+        let config: Config = toml::from_str(&text).map_err(|e| ConfigError::Parse(e.to_string()))?;
+        config.limits.rate_limits()?;
+        config.tuning.check().map_err(|e| ConfigError::Invalid(e.to_string()))?;
+        Ok(config)
+    }
+    /// Every bootstrap entry that resolves
+    pub fn bootstrap_addrs(&self) -> Vec<SocketAddr> {
+        let mut addrs = vec![];
+        for entry in self.bootstrap.iter(){
+            match entry.to_socket_addrs().map(|mut a| a.next()){
+                Ok(Some(addr)) => if !addrs.contains(&addr) {addrs.push(addr)},
+                _ => qlog!(LogLevel::Warn, "Could not resolve bootstrap server {}", entry),
+            }
+        }
+        addrs
+    }
+    /// The settings that only apply when a switch starts
+    fn restart_required(&self, other: &Config) -> bool {
+        self.bind != other.bind || self.control != other.control || self.discoverable != other.discoverable
+    }
+}
+
+impl Limits{
+    pub fn rate_limits(&self) -> Result<RateLimits, ConfigError> {
+        let parse = |ranges: &Vec<String>| -> Result<Vec<Cidr>, ConfigError> {
+            ranges.iter().map(|r| r.parse().map_err(|e: qserver::CidrParseError| ConfigError::Invalid(e.to_string()))).collect()
+        };
+        Ok(RateLimits{
+            datagrams_per_second: self.datagrams_per_second,
+            burst: self.burst,
+            max_sources: self.max_sources,
+            max_peers: self.max_peers,
+            allow: parse(&self.allow)?,
+            deny: parse(&self.deny)? })
+    }
+}
+
+impl fmt::Display for ConfigError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            ConfigError::Io(e) => write!(f, "Could not read config: {}", e),
+            ConfigError::Parse(e) => write!(f, "Could not parse config: {}", e),
+            ConfigError::Invalid(e) => write!(f, "Invalid config: {}", e),
+        }
+    }
+}
+
+/// Configuration functionality
+impl Switch{
+    /// Starts a switch with the start only settings of `config` and then applies the rest
+    pub fn from_config(config: &Config) -> Switch {
+        let switch = Switch::start(config.bind, config.discoverable);
+        switch.apply_config(config);
+        switch
+    }
+    /// Applies the settings that can change while running
+    pub fn apply_config(&self, config: &Config){
+        self.runtime.block_on(self.apply_config_async(config));
+    }
+    /// Reloads the config file at `path` whenever it changes or `reload_config` is called
+    pub fn watch_config(&self, path: &Path, config: Config){
+        self.runtime.spawn(Self::config_watcher(self.clone(), path.to_path_buf(), config));
+    }
+    /// Makes the config watcher reload now, even if the file looks unchanged
+    pub fn reload_config(&self){
+        let _ = self.reload.0.send(());
+    }
+
+    pub(crate) async fn apply_config_async(&self, config: &Config){
+        qserver::set_log_level(config.log_level);
+        // Load validates the tuning and limits so these only fail for configs built in code
+        if let Err(e) = self.server.set_tuning(config.tuning){
+            qlog!(LogLevel::Warn, "Switch {} kept its tuning: {}", self.address(), e);
+        }
+        match config.limits.rate_limits(){
+            Ok(limits) => self.server.set_rate_limits(limits),
+            Err(e) => qlog!(LogLevel::Warn, "Switch {} kept its rate limits: {}", self.address(), e),
+        }
+        // Any bootstrap server we are not connected to is joined again
+        let known = self.server.known_servers().await;
+        for addr in config.bootstrap_addrs(){
+            if !known.contains(&addr) && addr != self.address(){
+                self.join_async(addr).await;
+            }
+        }
+    }
+    async fn config_watcher(switch: Switch, path: PathBuf, mut config: Config){
+        let modified = |path: &Path| -> Option<SystemTime> {fs::metadata(path).and_then(|m| m.modified()).ok()};
+        let mut last = modified(&path);
+        loop{
+            let forced = tokio::select!{
+                _ = switch.server.stopped() => break,
+                _ = switch.reload.1.recv_async() => true,
+                _ = sleep(Duration::from_millis(RELOAD_INTERVAL)) => false,
+            };
+            let current = modified(&path);
+            if current == last && !forced{
+                continue;
+            }
+            last = current;
+            match Config::load(&path){
+                Ok(new) => {
+                    if new == config{
+                        if forced{
+                            qlog!(LogLevel::Debug, "Switch {} config is unchanged", switch.address());
+                        }
+                        continue;
+                    }
+                    qlog!(LogLevel::Info, "Switch {} reloading config from {}", switch.address(), path.display());
+                    if new.restart_required(&config){
+                        qlog!(LogLevel::Warn, "Switch {} needs a restart to apply the bind, control and discoverable settings", switch.address());
+                    }
+                    switch.apply_config_async(&new).await;
+                    config = new;
+                },
+                Err(e) => qlog!(LogLevel::Warn, "Switch {} kept its config: {}", switch.address(), e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn only_start_settings_need_a_restart(){
+        let config = Config::default();
+        let mut reloadable = config.clone();
+        reloadable.bootstrap.push("127.0.0.1:4000".to_string());
+        reloadable.log_level = LogLevel::Debug;
+        reloadable.tuning.keep_alive_interval += 1;
+        reloadable.limits.max_peers += 1;
+        assert!(!reloadable.restart_required(&config));
+
+        let bound = Config{ bind: Some("127.0.0.1:4000".parse().unwrap()), ..config.clone() };
+        let controlled = Config{ control: Some(PathBuf::from("/tmp/elsewhere.sock")), ..config.clone() };
+        let private = Config{ discoverable: false, ..config.clone() };
+        for changed in [bound, controlled, private]{
+            assert!(changed.restart_required(&config));
+        }
+    }
+}
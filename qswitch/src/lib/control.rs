@@ -0,0 +1,198 @@
+use std::{fs::DirBuilder, io::{self, BufRead, BufReader, Write}, net::SocketAddr, os::unix::{fs::{DirBuilderExt, MetadataExt}, net::UnixStream}, path::{Path, PathBuf}};
+use serde::{Serialize, Deserialize};
+use qserver::{LocalServer, LogLevel, ServerStatus, ExchangeCounts, qlog};
+use tokio::{io::{AsyncBufReadExt, AsyncWriteExt}, net::{UnixListener, UnixStream as AsyncUnixStream}};
+
+use crate::Switch;
+
+/// A command for a running switch, sent as a single line of json
+/// Commands without arguments are plain strings such as `"peers"`, the rest are objects
+/// such as `{"join":{"address":"10.0.0.2:4000"}}`. Every line is answered with one ControlResponse line
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlRequest{
+    Status,
+    /// The servers in the keep alive table
+    Peers,
+    /// The stations on the switch's server by channel
+    Stations,
+    Exchanges,
+    /// Sends a test message from the switch's message station
+    Send{station: u64, payload: String},
+    /// Joins the cluster `address` is part of
+    Join{address: SocketAddr},
+    /// Tells the switch's peers it is leaving and stops it
+    Leave,
+    /// Stops the switch without telling anyone
+    Shutdown,
+}
+
+/// The answer to a ControlRequest, sent as a single line of json
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlResponse{
+    Status{status: ServerStatus, station: u64},
+    Peers(Vec<PeerEntry>),
+    Stations(Vec<ChannelEntry>),
+    Exchanges(ExchangeCounts),
+    Sent,
+    Joining,
+    Leaving,
+    ShuttingDown,
+    Error(String),
+}
+
+/// A server in the keep alive table of a switch
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PeerEntry{
+    pub address: SocketAddr,
+    /// Has the server completed a handshake with us
+    pub handshake: bool,
+    pub discoverable: Option<bool>,
+    pub version: Option<u16>,
+}
+
+/// The stations on one channel of a switch
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChannelEntry{
+    pub channel: u32,
+    pub stations: Vec<u64>,
+}
+
+/// Where the control socket, pid file and daemon log live if nothing else is asked for
+/// Every user gets their own, their runtime dir or else a directory of theirs in the temp dir,
+/// so one user's switch can never be driven through, or squat on, another user's files
+pub fn runtime_dir() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR"){
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => std::env::temp_dir().join(format!("qswitch-{}", unsafe {libc::getuid()})),
+    }
+}
+/// Where the control socket lives if nothing else is asked for
+pub fn default_control_path() -> PathBuf {
+    runtime_dir().join("qswitch.sock")
+}
+
+/// Makes sure the directory of a file we create exists and nobody else controls it
+/// A missing directory is made private to us, an existing one has to belong to us or to root
+pub(crate) fn prepare_private_dir(path: &Path) -> io::Result<()> {
+    let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) else {return Ok(())};
+    if !dir.exists(){
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    }
+    let owner = dir.metadata()?.uid();
+    if owner != 0 && owner != unsafe {libc::getuid()}{
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("{} belongs to another user", dir.display())));
+    }
+    Ok(())
+}
+
+/// Sends a single request to the switch listening on `path` and waits for its answer
+pub fn request(path: &Path, request: &ControlRequest) -> io::Result<ControlResponse> {
+    let mut stream = UnixStream::connect(path)?;
+    let mut line = serde_json::to_string(request).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+    let mut answer = String::new();
+    BufReader::new(stream).read_line(&mut answer)?;
+    serde_json::from_str(&answer).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Control socket functionality
+impl Switch{
+    /// Starts answering ControlRequests on a unix socket at `path`
+    /// The socket file is removed again once the switch stops
+    pub fn serve_control(&self, path: &Path) -> io::Result<()> {
+        let _guard = self.runtime.enter();
+        prepare_private_dir(path)?;
+        // A socket file left behind by a switch that died would stop us binding
+        if path.exists() && UnixStream::connect(path).is_err(){
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        qlog!(LogLevel::Info, "Switch {} listening for control on {}", self.address(), path.display());
+        self.runtime.spawn(Self::control_listener(self.clone(), listener, path.to_path_buf()));
+        Ok(())
+    }
+
+    async fn control_listener(switch: Switch, listener: UnixListener, path: PathBuf){
+        loop{
+            tokio::select!{
+                _ = switch.server.stopped() => break,
+                stream = listener.accept() => {
+                    match stream{
+                        Ok((stream, _)) => {tokio::spawn(Self::control_connection(switch.clone(), stream));},
+                        Err(e) => qlog!(LogLevel::Warn, "Switch {} failed to accept a control connection: {}", switch.address(), e),
+                    }
+                },
+            }
+        }
+        let _ = std::fs::remove_file(path);
+    }
+    /// Answers every request line on a control connection until it closes
+    async fn control_connection(switch: Switch, stream: AsyncUnixStream){
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = tokio::io::BufReader::new(reader).lines();
+        while let Ok(Some(line)) = lines.next_line().await{
+            let request = serde_json::from_str::<ControlRequest>(&line);
+            let response = match &request{
+                Ok(request) => switch.control(request.clone()).await,
+                Err(e) => ControlResponse::Error(format!("Bad request: {}", e)),
+            };
+            let mut answer = serde_json::to_string(&response).unwrap();
+            answer.push('\n');
+            let _ = writer.write_all(answer.as_bytes()).await;
+            // We answer before stopping since stopping ends the tasks serving this connection
+            match request{
+                Ok(ControlRequest::Leave) => {
+                    LocalServer::leave(switch.server.clone()).await;
+                    return;
+                },
+                Ok(ControlRequest::Shutdown) => {
+                    switch.server.shutdown();
+                    return;
+                },
+                _ => {},
+            }
+        }
+    }
+    async fn control(&self, request: ControlRequest) -> ControlResponse {
+        match request{
+            ControlRequest::Status => ControlResponse::Status{ status: self.server.status().await, station: self.station_id },
+            ControlRequest::Peers => {
+                let mut peers = vec![];
+                for address in self.server.known_servers().await{
+                    let info = self.server.peer_info(address).await;
+                    peers.push(PeerEntry{
+                        address,
+                        handshake: info.is_some(),
+                        discoverable: info.map(|i| i.discoverable),
+                        version: info.map(|i| i.version) });
+                }
+                ControlResponse::Peers(peers)
+            },
+            ControlRequest::Stations => {
+                let mut channels: Vec<ChannelEntry> = self.server.station_table().await.into_iter().map(|(channel, stations)| ChannelEntry{ channel, stations }).collect();
+                channels.sort_by_key(|c| c.channel);
+                ControlResponse::Stations(channels)
+            },
+            ControlRequest::Exchanges => ControlResponse::Exchanges(self.server.exchange_counts().await),
+            ControlRequest::Send { station, payload } => {
+                match self.send_async(station, payload.into_bytes()).await{
+                    Ok(_) => ControlResponse::Sent,
+                    Err(e) => ControlResponse::Error(e),
+                }
+            },
+            ControlRequest::Join { address } => {
+                // Joining waits on the remote server so the answer does not
+                self.runtime.spawn(Self::join_detached(self.clone(), address));
+                ControlResponse::Joining
+            },
+            ControlRequest::Leave => ControlResponse::Leaving,
+            ControlRequest::Shutdown => ControlResponse::ShuttingDown,
+        }
+    }
+    async fn join_detached(switch: Switch, address: SocketAddr){
+        switch.join_async(address).await;
+    }
+}
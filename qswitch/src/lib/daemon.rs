@@ -0,0 +1,90 @@
+use std::{fs::{self, OpenOptions}, io, os::unix::io::AsRawFd, path::{Path, PathBuf}};
+use qserver::{LocalServer, LogLevel, qlog};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{Switch, control::prepare_private_dir};
+
+/// Removes the pid file it wrote when dropped
+pub struct PidFile{
+    path: PathBuf,
+}
+
+/// Moves the process into the background
+/// The parent exits, the child starts a new session, writes its pid to `pid_file` and sends
+/// its output to `log_file`. This has to be called before any runtime is started since
+/// only the calling thread survives a fork
+pub fn daemonize(pid_file: &Path, log_file: &Path) -> io::Result<PidFile> {
+    // We refuse to start over a daemon that is still running
+    if let Some(pid) = fs::read_to_string(pid_file).ok().and_then(|p| p.trim().parse::<libc::pid_t>().ok()){
+        if unsafe {libc::kill(pid, 0)} == 0{
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("qswitch is already running as pid {}", pid)));
+        }
+    }
+    prepare_private_dir(pid_file)?;
+    prepare_private_dir(log_file)?;
+    // The log is opened first so a bad path is reported on the terminal
+    let log = OpenOptions::new().create(true).append(true).open(log_file)?;
+    let null = OpenOptions::new().read(true).open("/dev/null")?;
+
+    match unsafe {libc::fork()}{
+        -1 => return Err(io::Error::last_os_error()),
+        0 => {},
+        _ => std::process::exit(0),
+    }
+    if unsafe {libc::setsid()} == -1{
+        return Err(io::Error::last_os_error());
+    }
+    unsafe{
+        libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO);
+        libc::dup2(log.as_raw_fd(), libc::STDOUT_FILENO);
+        libc::dup2(log.as_raw_fd(), libc::STDERR_FILENO);
+    }
+    fs::write(pid_file, format!("{}\n", std::process::id()))?;
+    Ok(PidFile{ path: pid_file.to_path_buf() })
+}
+
+impl Drop for PidFile{
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Signal functionality
+impl Switch{
+    /// SIGINT and SIGTERM make the switch leave and drain, a second one stops it straight away
+    /// SIGHUP reloads the config file if one is being watched
+    pub fn handle_signals(&self) -> io::Result<()> {
+        let _guard = self.runtime.enter();
+        let interrupt = signal(SignalKind::interrupt())?;
+        let terminate = signal(SignalKind::terminate())?;
+        let hangup = signal(SignalKind::hangup())?;
+        self.runtime.spawn(Self::signal_handler(self.clone(), interrupt, terminate, hangup));
+        Ok(())
+    }
+
+    async fn signal_handler(switch: Switch, mut interrupt: tokio::signal::unix::Signal, mut terminate: tokio::signal::unix::Signal, mut hangup: tokio::signal::unix::Signal){
+        let mut leaving = false;
+        loop{
+            tokio::select!{
+                _ = switch.server.stopped() => break,
+                _ = hangup.recv() => {
+                    qlog!(LogLevel::Info, "Switch {} got SIGHUP, reloading config", switch.address());
+                    switch.reload_config();
+                },
+                _ = interrupt.recv() => leaving = Self::stop_signal(&switch, leaving),
+                _ = terminate.recv() => leaving = Self::stop_signal(&switch, leaving),
+            }
+        }
+    }
+    fn stop_signal(switch: &Switch, leaving: bool) -> bool {
+        if leaving{
+            qlog!(LogLevel::Warn, "Switch {} stopping without waiting for the drain", switch.address());
+            switch.server.shutdown();
+            return true;
+        }
+        qlog!(LogLevel::Info, "Switch {} leaving the cluster", switch.address());
+        // Leaving waits on our peers so we keep listening for a second signal meanwhile
+        switch.runtime.spawn(LocalServer::leave(switch.server.clone()));
+        true
+    }
+}
@@ -0,0 +1,136 @@
+use std::{net::SocketAddr, sync::Arc};
+use qserver::{LocalServer, LogLevel, ServerStatus, Station, StationOperable, qlog};
+use tokio::runtime::Runtime;
+
+mod control;
+mod config;
+mod daemon;
+mod cluster;
+
+pub use control::{ControlRequest, ControlResponse, PeerEntry, ChannelEntry, request, default_control_path, runtime_dir};
+pub use config::{Config, Limits, ConfigError};
+pub use daemon::{daemonize, PidFile};
+pub use cluster::{Cluster, Topology, Member};
+
+/// The channel of the station every switch exposes for test messages
+pub const MESSAGE_CHANNEL: u32 = 1;
+
+/// The raw bytes of a test message
+pub struct Message(pub Vec<u8>);
+
+/// A message for the messenger station to send, with where to report the result
+type Outgoing = (u64, Vec<u8>, flume::Sender<Result<(), String>>);
+
+/// A cluster node
+/// Wraps a LocalServer and the runtime it runs on behind a blocking api
+#[derive(Clone)]
+pub struct Switch{
+    server: Arc<LocalServer>,
+    runtime: Arc<Runtime>,
+    /// The id of our message station
+    station_id: u64,
+    outbox: flume::Sender<Outgoing>,
+    /// Asks the config watcher to reload straight away
+    reload: (flume::Sender<()>, flume::Receiver<()>),
+}
+
+impl Switch{
+    /// Starts a server on `bind`, or on an ephemeral port of the local ip if None
+    pub fn start(bind: Option<SocketAddr>, discoverable: bool) -> Switch {
+        Self::start_on(bind, discoverable, None)
+    }
+    /// Starts like `start` but on `runtime` if given, so many switches can share one
+    pub fn start_on(bind: Option<SocketAddr>, discoverable: bool, runtime: Option<Arc<Runtime>>) -> Switch {
+        let server = LocalServer::new(bind, discoverable, runtime, None);
+        let runtime = server.get_runtime();
+        let station: Station<Message> = Station::new(server.clone(), MESSAGE_CHANNEL, None);
+        let station_id = station.id();
+        let outbox = flume::unbounded();
+        runtime.spawn(Self::messenger(server.clone(), station, outbox.1));
+        Switch{ server, runtime, station_id, outbox: outbox.0, reload: flume::unbounded() }
+    }
+    /// Joins the cluster `addr` is part of
+    pub fn join(&self, addr: SocketAddr){
+        self.runtime.block_on(self.join_async(addr));
+    }
+    pub fn status(&self) -> ServerStatus {
+        self.runtime.block_on(self.server.status())
+    }
+    /// Sends `payload` from our message station to the message station `station`
+    pub fn send(&self, station: u64, payload: Vec<u8>) -> Result<(), String> {
+        self.runtime.block_on(self.send_async(station, payload))
+    }
+    /// Tells our peers we are leaving and stops the server
+    pub fn leave(&self){
+        self.runtime.block_on(LocalServer::leave(self.server.clone()));
+    }
+    /// Stops the server, peers will drop us once their keep alives run out
+    pub fn stop(&self){
+        self.server.shutdown();
+    }
+    /// Blocks until the switch has been stopped
+    pub fn wait(&self){
+        self.runtime.block_on(self.server.stopped());
+    }
+    pub fn address(&self) -> SocketAddr {
+        self.server.local_address()
+    }
+    pub fn station_id(&self) -> u64 {
+        self.station_id
+    }
+    pub fn server(&self) -> Arc<LocalServer> {
+        self.server.clone()
+    }
+    pub fn runtime(&self) -> Arc<Runtime> {
+        self.runtime.clone()
+    }
+
+    pub(crate) async fn join_async(&self, addr: SocketAddr){
+        qlog!(LogLevel::Info, "Switch {} joining {}", self.address(), addr);
+        LocalServer::join_server(self.server.clone(), addr).await;
+    }
+    pub(crate) async fn send_async(&self, station: u64, payload: Vec<u8>) -> Result<(), String> {
+        let (tx, rx) = flume::bounded(1);
+        if self.outbox.send((station, payload, tx)).is_err(){
+            return Err("The switch has stopped".to_string());
+        }
+        rx.recv_async().await.unwrap_or(Err("The switch has stopped".to_string()))
+    }
+    /// Owns the message station, sending what the outbox hands it and printing what arrives
+    async fn messenger(server: Arc<LocalServer>, mut station: Station<Message>, outbox: flume::Receiver<Outgoing>){
+        let mut known = 0;
+        loop{
+            // Our station was made before we joined anyone so it has to announce itself to new servers
+            let servers = server.known_servers().await.len();
+            if servers != known{
+                known = servers;
+                station.announce().await;
+            }
+            tokio::select!{
+                _ = server.stopped() => break,
+                outgoing = outbox.recv_async() => {
+                    let Ok((tgt, payload, result)) = outgoing else {break};
+                    let sent = station.send(tgt, true, &Message(payload)).await;
+                    let _ = result.send(sent.map(|_| ()).map_err(|e| e.to_string()));
+                },
+                // Listening is cancel safe, so losing the race to another branch drops nothing
+                message = station.listen() => {
+                    if let Some((source, from, Message(data))) = message{
+                        qlog!(LogLevel::Info, "Station {} got a message from station {} at {}: {}", station.id(), from, source, String::from_utf8_lossy(&data));
+                    }
+                },
+                _ = tokio::time::sleep(tokio::time::Duration::from_millis(500)) => {},
+            }
+        }
+    }
+}
+
+impl StationOperable for Message{
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.clone()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        Message(bytes.to_vec())
+    }
+}
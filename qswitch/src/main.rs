@@ -1,26 +1,229 @@
-use clap::Parser;
-use qserver::ClusterTerminal;
-use std::net::{SocketAddr, ToSocketAddrs};
-use local_ip_address::local_ip;
+use clap::{Parser, Subcommand};
+use qswitch::{Switch, Config, daemonize, ControlRequest, ControlResponse, request, default_control_path, runtime_dir, Cluster, Topology};
+use std::{io::BufRead, net::SocketAddr, path::PathBuf, process::exit, time::Duration};
 
 #[derive(Parser, Debug)]
 struct Arg {
-    //Target cluster address
-    #[arg(short, default_value_t = String::new())]
-    target: String,
-    #[arg(short, long)]
-    private: bool,
+    /// The control socket of the node, overrides the config file
+    #[arg(short, long, global = true)]
+    control: Option<PathBuf>,
+    /// The config file of the node
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Command,
 }
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Starts a node
+    Run {
+        //Target cluster address, joined along with the bootstrap servers of the config
+        #[arg(short)]
+        target: Option<String>,
+        /// Overrides the discoverable setting of the config
+        #[arg(short, long)]
+        private: bool,
+        /// Runs the node in the background
+        #[arg(long)]
+        daemon: bool,
+        /// Where a daemon writes its pid
+        #[arg(long, default_value_os_t = runtime_dir().join("qswitch.pid"))]
+        pid_file: PathBuf,
+        /// Where a daemon writes its output
+        #[arg(long, default_value_os_t = runtime_dir().join("qswitch.log"))]
+        log_file: PathBuf,
+    },
+    /// Shows the state of a running node
+    Status,
+    /// Lists the servers a running node keeps alive
+    Peers,
+    /// Lists the stations of a running node by channel
+    Stations,
+    /// Shows how many exchanges a running node has run
+    Exchanges,
+    /// Sends a test message from a running node's message station
+    Send {
+        station: u64,
+        payload: String,
+    },
+    /// Asks a running node to join the cluster of another server
+    Join {
+        address: SocketAddr,
+    },
+    /// Asks a running node to leave the cluster
+    Leave,
+    /// Stops a running node without telling its peers
+    Shutdown,
+    /// Runs a cluster on loopback in this process and shows its membership table
+    /// Reads `kill <n>`, `leave <n>`, `add [private]` and `quit` from stdin
+    Cluster {
+        #[arg(short, long, default_value_t = 4)]
+        nodes: usize,
+        /// How many of the nodes are not discoverable
+        #[arg(short, long, default_value_t = 0)]
+        private: usize,
+        /// chain or star
+        #[arg(short, long, default_value = "chain")]
+        topology: Topology,
+    },
+}
+
 fn main() {
     let arg = Arg::parse();
-    let ip = local_ip().unwrap();
-    let port = 0;
-    let addr = SocketAddr::new(ip, port);
-    let t1 = ClusterTerminal::new(addr, !arg.private);
-    if let Ok(addr) = arg.target.to_socket_addrs(){
-        if let Some(addr) = addr.last(){
-            t1.join_cluster(addr);
+    let config = match &arg.config {
+        Some(path) => match Config::load(path) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), e);
+                exit(1);
+            },
+        },
+        None => Config::default(),
+    };
+    let control = arg.control.or(config.control.clone()).unwrap_or(default_control_path());
+    let query = match arg.command {
+        Command::Run { target, private, daemon, pid_file, log_file } => {
+            // The watcher compares reloads against the file, not our overrides
+            let watched = arg.config.map(|path| (path, config.clone()));
+            let mut config = config;
+            if let Some(target) = target {
+                config.bootstrap.push(target);
+            }
+            if private {
+                config.discoverable = false;
+            }
+            let daemon = if daemon { Some((pid_file, log_file)) } else { None };
+            if let Err(e) = run(config, watched, control, daemon) {
+                eprintln!("{}", e);
+                exit(1);
+            }
+            return;
+        },
+        Command::Cluster { nodes, private, topology } => {
+            cluster(nodes, private, topology);
+            return;
+        },
+        Command::Status => ControlRequest::Status,
+        Command::Peers => ControlRequest::Peers,
+        Command::Stations => ControlRequest::Stations,
+        Command::Exchanges => ControlRequest::Exchanges,
+        Command::Join { address } => ControlRequest::Join { address },
+        Command::Send { station, payload } => ControlRequest::Send { station, payload },
+        Command::Leave => ControlRequest::Leave,
+        Command::Shutdown => ControlRequest::Shutdown,
+    };
+    match request(&control, &query) {
+        Ok(response) => print_response(response),
+        Err(e) => {
+            eprintln!("Could not reach the node on {}: {}", control.display(), e);
+            exit(1);
+        },
+    }
+}
+
+/// Runs a node until it is stopped
+/// Errors are returned rather than exited on so the pid file is removed on the way out
+fn run(config: Config, watched: Option<(PathBuf, Config)>, control: PathBuf, daemon: Option<(PathBuf, PathBuf)>) -> Result<(), String> {
+    // We have to fork before the switch starts its runtime
+    let _pid_file = match daemon {
+        Some((pid_file, log_file)) => Some(daemonize(&pid_file, &log_file).map_err(|e| format!("Could not start the daemon: {}", e))?),
+        None => None,
+    };
+    let switch = Switch::from_config(&config);
+    switch.handle_signals().map_err(|e| format!("Could not install the signal handlers: {}", e))?;
+    switch.serve_control(&control).map_err(|e| format!("Could not open the control socket {}: {}", control.display(), e))?;
+    if let Some((path, loaded)) = watched {
+        switch.watch_config(&path, loaded);
+    }
+    switch.wait();
+    let _ = std::fs::remove_file(&control);
+    Ok(())
+}
+
+fn cluster(nodes: usize, private: usize, topology: Topology) {
+    // The table is unreadable with every node logging under it
+    qserver::set_log_level(qserver::LogLevel::Warn);
+    let mut cluster = Cluster::start(nodes, private, topology);
+    let (tx, rx) = flume::unbounded();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    let mut last = String::new();
+    loop {
+        print!("\x1b[2J\x1b[H{}", cluster.render());
+        println!("kill <n> | leave <n> | add [private] | quit");
+        if !last.is_empty() {
+            println!("{}", last);
         }
+        let line = match rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(line) => line,
+            Err(flume::RecvTimeoutError::Timeout) => continue,
+            Err(flume::RecvTimeoutError::Disconnected) => break,
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let index = words.get(1).and_then(|w| w.parse::<usize>().ok());
+        last = match (words.first().copied(), index) {
+            (Some("kill"), Some(n)) if cluster.kill(n) => format!("Killed node {}", n),
+            (Some("leave"), Some(n)) if cluster.leave(n) => format!("Node {} left", n),
+            (Some("kill" | "leave"), _) => "No such live node".to_string(),
+            (Some("add"), _) => {
+                let discoverable = words.get(1) != Some(&"private");
+                format!("Added node {}", cluster.add(discoverable))
+            },
+            (Some("quit" | "exit"), _) => break,
+            (None, _) => String::new(),
+            _ => format!("Unknown command {}", line.trim()),
+        };
+    }
+    cluster.stop();
+}
+
+fn print_response(response: ControlResponse) {
+    match response {
+        ControlResponse::Status { status, station } => {
+            println!("address:      {}", status.address);
+            println!("discoverable: {}", status.discoverable);
+            println!("station:      {}", station);
+            println!("peers:        {}", status.peers);
+            println!("keep alives:  {}", status.keep_alives);
+            println!("stations:     {}", status.stations);
+            println!("exchanges:    {}", status.exchanges);
+        },
+        ControlResponse::Peers(peers) => {
+            for peer in peers {
+                match peer.version {
+                    Some(version) => println!("{} version {} discoverable {}", peer.address, version, peer.discoverable.unwrap_or(false)),
+                    None => println!("{} no handshake", peer.address),
+                }
+            }
+        },
+        ControlResponse::Stations(channels) => {
+            for channel in channels {
+                let name = qserver::channel_name(channel.channel).map(|n| n.to_string()).unwrap_or(channel.channel.to_string());
+                println!("{}: {}", name, channel.stations.iter().map(|s| s.to_string()).collect::<Vec<_>>().join(" "));
+            }
+        },
+        ControlResponse::Exchanges(counts) => {
+            println!("live:        {}", counts.live);
+            println!("sent:        {}", counts.sent);
+            println!("confirmed:   {}", counts.confirmed);
+            println!("unconfirmed: {}", counts.unconfirmed);
+            println!("received:    {}", counts.received);
+            println!("dropped:     {}", counts.dropped);
+        },
+        ControlResponse::Sent => println!("Sent"),
+        ControlResponse::Joining => println!("Joining"),
+        ControlResponse::Leaving => println!("Leaving"),
+        ControlResponse::ShuttingDown => println!("Shutting down"),
+        ControlResponse::Error(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        },
     }
-    loop {}
 }
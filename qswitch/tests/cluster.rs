@@ -0,0 +1,28 @@
+use std::time::{Duration, Instant};
+
+use qswitch::{Cluster, Topology};
+
+const CONVERGENCE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[test]
+fn topologies_parse(){
+    assert_eq!("chain".parse::<Topology>(), Ok(Topology::Chain));
+    assert_eq!(" Star ".parse::<Topology>(), Ok(Topology::Star));
+    assert!("ring".parse::<Topology>().is_err());
+}
+
+#[test]
+fn a_chain_converges(){
+    let mut cluster = Cluster::start(3, 1, Topology::Chain);
+    let deadline = Instant::now() + CONVERGENCE_TIMEOUT;
+    while !cluster.converged(){
+        assert!(Instant::now() < deadline, "The cluster did not converge\n{}", cluster.render());
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    let members = cluster.membership();
+    assert_eq!(members.len(), 3);
+    assert!(!members[2].discoverable);
+    // The private node still has to know every discoverable node
+    assert!(members[2].peers.contains(&0) && members[2].peers.contains(&1));
+    cluster.stop();
+}
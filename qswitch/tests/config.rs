@@ -0,0 +1,67 @@
+use std::path::PathBuf;
+
+use qserver::LogLevel;
+use qswitch::{Config, ConfigError};
+
+/// Writes `text` to a config file of its own
+fn config_file(name: &str, text: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("qswitch-{}-{}.toml", name, std::process::id()));
+    std::fs::write(&path, text).unwrap();
+    path
+}
+
+fn load(name: &str, text: &str) -> Result<Config, ConfigError> {
+    let path = config_file(name, text);
+    let config = Config::load(&path);
+    let _ = std::fs::remove_file(&path);
+    config
+}
+
+#[test]
+fn an_empty_file_is_the_default(){
+    assert_eq!(load("empty", "").unwrap(), Config::default());
+}
+
+#[test]
+fn missing_settings_take_their_default(){
+    let config = load("partial", r#"
+bootstrap = ["127.0.0.1:4000"]
+discoverable = false
+log_level = "warn"
+
+[tuning]
+keep_alive_interval = 250
+
+[limits]
+deny = ["10.0.0.0/8"]
+"#).unwrap();
+    let default = Config::default();
+    assert_eq!(config.bootstrap, vec!["127.0.0.1:4000".to_string()]);
+    assert!(!config.discoverable);
+    assert_eq!(config.log_level, LogLevel::Warn);
+    assert_eq!(config.tuning.keep_alive_interval, 250);
+    assert_eq!(config.tuning.send_timeout, default.tuning.send_timeout);
+    assert_eq!(config.limits.deny, vec!["10.0.0.0/8".to_string()]);
+    assert_eq!(config.limits.max_peers, default.limits.max_peers);
+    assert_eq!(config.bind, None);
+}
+
+#[test]
+fn an_invalid_range_is_rejected(){
+    let config = load("cidr", "[limits]\nallow = [\"10.0.0.0/40\"]\n");
+    assert!(matches!(config, Err(ConfigError::Invalid(_))), "Expected an invalid config, got {:?}", config);
+}
+
+#[test]
+fn zero_tuning_is_rejected(){
+    let config = load("tuning", "[tuning]\nsend_timeout = 0\n");
+    assert!(matches!(config, Err(ConfigError::Invalid(_))), "Expected an invalid config, got {:?}", config);
+}
+
+#[test]
+fn unreadable_and_unparsable_files_are_told_apart(){
+    let missing = Config::load(&std::env::temp_dir().join("qswitch-there-is-no-such-config.toml"));
+    assert!(matches!(missing, Err(ConfigError::Io(_))));
+    let garbage = load("garbage", "bootstrap = [");
+    assert!(matches!(garbage, Err(ConfigError::Parse(_))));
+}
@@ -0,0 +1,60 @@
+use std::{io::{BufRead, BufReader, Write}, net::SocketAddr, os::unix::net::UnixStream};
+
+use qswitch::{ControlRequest, ControlResponse, Switch, request};
+
+fn line(request: &ControlRequest) -> String {
+    serde_json::to_string(request).unwrap()
+}
+
+#[test]
+fn requests_are_single_json_lines(){
+    assert_eq!(line(&ControlRequest::Peers), r#""peers""#);
+    assert_eq!(line(&ControlRequest::Shutdown), r#""shutdown""#);
+    let address: SocketAddr = "10.0.0.2:4000".parse().unwrap();
+    assert_eq!(line(&ControlRequest::Join{ address }), r#"{"join":{"address":"10.0.0.2:4000"}}"#);
+    let send = ControlRequest::Send{ station: 7, payload: "hello".to_string() };
+    assert_eq!(line(&send), r#"{"send":{"station":7,"payload":"hello"}}"#);
+    // Every request reads back as itself
+    for request in [ControlRequest::Status, ControlRequest::Stations, ControlRequest::Exchanges, ControlRequest::Leave, ControlRequest::Join{ address }, send]{
+        let back: ControlRequest = serde_json::from_str(&line(&request)).unwrap();
+        assert_eq!(line(&back), line(&request));
+    }
+}
+
+#[test]
+fn responses_round_trip(){
+    for response in [ControlResponse::Sent, ControlResponse::Joining, ControlResponse::ShuttingDown, ControlResponse::Error("no".to_string())]{
+        let text = serde_json::to_string(&response).unwrap();
+        assert!(!text.contains('\n'));
+        let back: ControlResponse = serde_json::from_str(&text).unwrap();
+        assert_eq!(serde_json::to_string(&back).unwrap(), text);
+    }
+}
+
+#[test]
+fn a_running_switch_answers_good_and_bad_lines(){
+    let loopback: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let switch = Switch::start(Some(loopback), true);
+    let path = std::env::temp_dir().join(format!("qswitch-control-{}", std::process::id())).join("qswitch.sock");
+    switch.serve_control(&path).unwrap();
+
+    match request(&path, &ControlRequest::Status).unwrap(){
+        ControlResponse::Status{ status, station } => {
+            assert_eq!(status.address, switch.address());
+            assert_eq!(station, switch.station_id());
+        },
+        other => panic!("Expected a status, got {:?}", other),
+    }
+    // A line that is not a request is answered with an error and the connection stays usable
+    let mut stream = UnixStream::connect(&path).unwrap();
+    stream.write_all(b"{\"join\":\"nowhere\"}\n\"peers\"\n").unwrap();
+    let mut answers = BufReader::new(stream).lines();
+    let bad: ControlResponse = serde_json::from_str(&answers.next().unwrap().unwrap()).unwrap();
+    assert!(matches!(bad, ControlResponse::Error(_)), "Expected an error, got {:?}", bad);
+    let good: ControlResponse = serde_json::from_str(&answers.next().unwrap().unwrap()).unwrap();
+    assert!(matches!(good, ControlResponse::Peers(_)), "Expected the peers, got {:?}", good);
+
+    assert!(matches!(request(&path, &ControlRequest::Shutdown).unwrap(), ControlResponse::ShuttingDown));
+    switch.wait();
+    let _ = std::fs::remove_dir_all(path.parent().unwrap());
+}